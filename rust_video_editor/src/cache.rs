@@ -0,0 +1,194 @@
+//! A cache of analysis results (keyframe indexes, loudness timelines) kept in a
+//! directory and keyed by the content hash of the analysed file, so editing the file
+//! invalidates them and re-running with other settings skips the analysis. Only
+//! analyses that read the whole file are worth it, since the hash reads it too.
+//!
+//! Each result lives in `<dir>/<content hash>/<kind>-v<version>-<params hash>.json`,
+//! where the version is that of the analyser and the parameters are whatever the
+//! result depends on besides the file. Results that only read the file's header, like
+//! probes, are cheaper to compute than the hash: those are keyed by the file's path,
+//! size and modification time instead, in `<dir>/stamp-<hash of those>/`.
+
+use crate::error::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+// Where the cache lives; None when it is off. Unset means the default directory.
+fn dir_setting() -> &'static Mutex<Option<Option<PathBuf>>> {
+    static DIR: OnceLock<Mutex<Option<Option<PathBuf>>>> = OnceLock::new();
+    DIR.get_or_init(Default::default)
+}
+
+/// Use `dir` as the cache directory, or turn the cache off with None.
+pub fn set_dir(dir: Option<PathBuf>) {
+    *dir_setting().lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(dir);
+}
+
+/// `$XDG_CACHE_HOME/rust_video_editor`, falling back to `~/.cache/rust_video_editor`.
+pub fn default_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(base.join("rust_video_editor"))
+}
+
+/// The cache directory in use, or None when caching is off.
+pub fn dir() -> Option<PathBuf> {
+    let setting = dir_setting().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    setting.unwrap_or_else(default_dir)
+}
+
+// A content hash computed in this process, with the size and modification time the
+// file had, so a file is only read once unless it changes.
+struct KnownHash {
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
+fn hashes() -> &'static Mutex<HashMap<String, KnownHash>> {
+    static HASHES: OnceLock<Mutex<HashMap<String, KnownHash>>> = OnceLock::new();
+    HASHES.get_or_init(Default::default)
+}
+
+/// BLAKE3 hash of the contents of `path`, in hex.
+pub fn content_hash(path: &str) -> Result<String> {
+    let metadata = fs::metadata(path).map_err(|e| Error::io(path, e))?;
+    let modified = metadata.modified().map_err(|e| Error::io(path, e))?;
+    if let Some(known) = hashes().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(path)
+        && (known.size, known.modified) == (metadata.len(), modified)
+    {
+        return Ok(known.hash.clone());
+    }
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(file).map_err(|e| Error::io(path, e))?;
+    let hash = hasher.finalize().to_hex().to_string();
+    let known = KnownHash {
+        size: metadata.len(),
+        modified,
+        hash: hash.clone(),
+    };
+    hashes()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(path.to_string(), known);
+    Ok(hash)
+}
+
+// A hash of the canonical path, size and modification time of `path`, which changes
+// whenever the file is written or replaced without reading it.
+fn stamp(path: &str) -> Result<String> {
+    let canonical = fs::canonicalize(path).map_err(|e| Error::io(path, e))?;
+    let metadata = fs::metadata(&canonical).map_err(|e| Error::io(path, e))?;
+    let modified = metadata.modified().map_err(|e| Error::io(path, e))?;
+    let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let stamp = format!("{}\n{}\n{}", canonical.display(), metadata.len(), since_epoch.as_nanos());
+    Ok(format!("stamp-{}", blake3::hash(stamp.as_bytes()).to_hex()))
+}
+
+// The file a result is kept in, under the directory named by `key`, or None when
+// caching is off or the input is not a file that can be read (a URL, say).
+fn entry_path(
+    key: impl FnOnce() -> Result<String>,
+    kind: &str,
+    version: u32,
+    params: &impl Serialize,
+) -> Option<PathBuf> {
+    let dir = dir()?;
+    let key = key().ok()?;
+    let params = serde_json::to_string(params).expect("Cache parameters serialize");
+    let params_hash = blake3::hash(params.as_bytes()).to_hex();
+    let name = format!("{}-v{}-{}.json", kind, version, &params_hash[..16]);
+    Some(dir.join(key).join(name))
+}
+
+/// The `kind` result for `input` and `params` from the cache, or `compute`d and
+/// stored. A missing or unreadable entry is computed again, and one that cannot be
+/// written is only not cached, since the cache must never make an analysis fail.
+pub fn cached<T, P, F>(input: &str, kind: &str, version: u32, params: &P, compute: F) -> Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    P: Serialize,
+    F: FnOnce() -> Result<T>,
+{
+    read_or_compute(entry_path(|| content_hash(input), kind, version, params), compute)
+}
+
+/// Like `cached`, but keyed by the path, size and modification time of `input`
+/// rather than its contents, for results that read little of it.
+pub fn cached_by_stamp<T, P, F>(input: &str, kind: &str, version: u32, params: &P, compute: F) -> Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    P: Serialize,
+    F: FnOnce() -> Result<T>,
+{
+    read_or_compute(entry_path(|| stamp(input), kind, version, params), compute)
+}
+
+// The result stored at `path`, or `compute`d and stored there; computed every time
+// without a path.
+fn read_or_compute<T, F>(path: Option<PathBuf>, compute: F) -> Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Result<T>,
+{
+    let Some(path) = path else {
+        return compute();
+    };
+    if let Some(value) = fs::read_to_string(&path).ok().and_then(|text| serde_json::from_str(&text).ok()) {
+        return Ok(value);
+    }
+    let value = compute()?;
+    let text = serde_json::to_string(&value).expect("Cached results serialize");
+    path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| write_atomically(&path, text.as_bytes()))
+        .ok();
+    Ok(value)
+}
+
+/// Write `bytes` to `path` through a temporary file beside it, so a concurrent run
+/// never reads half of it. Nothing is left behind when that fails.
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    let written = fs::write(&temporary, bytes).and_then(|_| fs::rename(&temporary, path));
+    if written.is_err() {
+        fs::remove_file(&temporary).ok();
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn the_stamp_follows_the_file_but_not_its_spelling() {
+        let path = std::env::temp_dir().join(format!("cache-stamp-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "one").unwrap();
+        let first = stamp(&path).unwrap();
+        assert!(first.starts_with("stamp-"), "{}", first);
+        let dotted = Path::new(&path).parent().unwrap().join(".").join(Path::new(&path).file_name().unwrap());
+        assert_eq!(stamp(dotted.to_str().unwrap()).unwrap(), first);
+        // Another size
+        fs::write(&path, "three").unwrap();
+        let resized = stamp(&path).unwrap();
+        assert_ne!(resized, first);
+        // The same size, written later
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert_ne!(stamp(&path).unwrap(), resized);
+        fs::remove_file(&path).unwrap();
+        assert!(stamp(&path).is_err());
+    }
+}
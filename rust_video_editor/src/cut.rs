@@ -0,0 +1,361 @@
+//! Cutting ranges out of a file by stream copy.
+
+use crate::error::{Error, Result};
+use crate::probe::{probe_duration, video_frame_rate};
+use crate::segment;
+use crate::timestamp::{TimeRange, TimeSpec, Timestamp};
+use ffmpeg_next::Rational;
+use serde_json::Value;
+use std::path::Path;
+
+/// Copy `start..end` of `input` into `output`. The cut begins on the first video
+/// keyframe at or after `start`; use `smart_render::smart_cut` for frame accuracy.
+/// See `segment::write_segments` for `audio_fade` and the length returned.
+pub fn cut_video(
+    input: &str,
+    start: Timestamp,
+    end: Timestamp,
+    output: &str,
+    audio_fade: Option<Timestamp>,
+) -> Result<Timestamp> {
+    segment::write_segments(input, &[(start, end)], output, audio_fade)
+}
+
+/// Copy every range of `input` into one `output`, in source order. Returns the
+/// length written.
+pub fn cut_ranges(
+    input: &str,
+    ranges: &[(Timestamp, Timestamp)],
+    output: &str,
+    audio_fade: Option<Timestamp>,
+) -> Result<Timestamp> {
+    segment::write_segments(input, ranges, output, audio_fade)
+}
+
+/// The ranges kept when `ranges` are deleted from `input`.
+pub fn delete_ranges(input: &str, ranges: &[(Timestamp, Timestamp)]) -> Result<Vec<(Timestamp, Timestamp)>> {
+    let kept = segment::complement_ranges(ranges, probe_duration(input)?);
+    if kept.is_empty() {
+        return Err(Error::invalid(format!("The delete ranges cover the whole of {}", input)));
+    }
+    Ok(kept)
+}
+
+/// Resolve every range against `input`, looking up the frame rate only if a range needs
+/// it. Ranges must start inside the input; ends past its end are cut back to it.
+pub fn resolve_ranges(input: &str, ranges: &[TimeRange]) -> Result<Vec<(Timestamp, Timestamp)>> {
+    let needs_rate = ranges
+        .iter()
+        .any(|range| matches!(range.start, TimeSpec::Frame(_)) || matches!(range.end, TimeSpec::Frame(_)));
+    let frame_rate = if needs_rate { video_frame_rate(input)? } else { None };
+    let duration = probe_duration(input)?;
+    ranges
+        .iter()
+        .map(|range| resolve_range(input, range, frame_rate, duration))
+        .collect()
+}
+
+// Resolve one range of `input`, which lasts `duration`; see `resolve_ranges`.
+fn resolve_range(
+    input: &str,
+    range: &TimeRange,
+    frame_rate: Option<Rational>,
+    duration: Timestamp,
+) -> Result<(Timestamp, Timestamp)> {
+    let start = range.start.resolve(frame_rate).map_err(Error::Invalid)?;
+    let end = range.end.resolve(frame_rate).map_err(Error::Invalid)?;
+    if end <= start {
+        return Err(Error::invalid(format!("End ({}) must be after start ({})", end, start)));
+    }
+    if start < Timestamp::default() {
+        return Err(Error::invalid(format!("Range {} - {} starts before the start of {}", start, end, input)));
+    }
+    if start >= duration {
+        return Err(Error::invalid(format!(
+            "Range {} - {} starts after the end of {} ({})",
+            start, end, input, duration
+        )));
+    }
+    Ok((start, end.min(duration)))
+}
+
+/// A list of ranges to keep, as read from a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CutList {
+    /// The file the list was made for, when it says (`Analyze` JSON does)
+    pub input: Option<String>,
+    pub ranges: Vec<TimeRange>,
+}
+
+/// Read the ranges of a ranges file; see `read_cut_list`.
+pub fn read_ranges_file(path: &str) -> Result<Vec<TimeRange>> {
+    read_cut_list(path).map(|list| list.ranges)
+}
+
+/// Read a cut list. The format follows the extension, or the content for JSON:
+///
+/// - JSON: an `Analyze` report (its `keep` ranges) or an array of ranges, each an
+///   object with `start` and `end`, a `[start, end]` pair or a `"START-END"` string.
+///   Numbers are seconds.
+/// - CSV with a header naming `start` and `end` columns; when there is a `kind`
+///   column only its `keep` rows count.
+/// - Text: one `START-END` or `START END` per line, `#` starts a comment. Anything
+///   after the end is ignored, so Audacity label tracks can be read as they are.
+pub fn read_cut_list(path: &str) -> Result<CutList> {
+    let text = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+    let is_json = match extension.as_deref() {
+        Some("json") => true,
+        Some("csv") => false,
+        _ => text.trim_start().starts_with(['{', '[']),
+    };
+    if is_json {
+        return read_json(&text).map_err(|e| Error::invalid(format!("{}: {}", path, e)));
+    }
+    let list = if extension.as_deref() == Some("csv") { read_csv(&text) } else { read_text(&text) };
+    list.map_err(|(number, e)| Error::invalid(format!("{}:{}: {}", path, number, e)))
+}
+
+// Errors come with their line number.
+fn read_text(text: &str) -> Result<CutList, (usize, String)> {
+    let mut ranges = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        // Audacity writes spectral selections on lines of their own starting with '\'
+        if line.is_empty() || line.starts_with('\\') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let range: Result<TimeRange, String> = match (fields.next(), fields.next()) {
+            (Some(start), Some(end)) => start
+                .parse()
+                .and_then(|start| end.parse().map(|end| TimeRange { start, end })),
+            _ => line.parse(),
+        };
+        ranges.push(range.map_err(|e| (number + 1, e))?);
+    }
+    Ok(CutList { input: None, ranges })
+}
+
+fn read_csv(text: &str) -> Result<CutList, (usize, String)> {
+    let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let (number, header) = lines.next().ok_or((1, "empty file".to_string()))?;
+    let header: Vec<String> = header.split(',').map(|name| name.trim().to_ascii_lowercase()).collect();
+    let column = |name: &str| header.iter().position(|field| field == name);
+    let (Some(start_column), Some(end_column)) = (column("start"), column("end")) else {
+        return Err((number + 1, "the header must name a start and an end column".to_string()));
+    };
+    let kind_column = column("kind");
+    let mut ranges = Vec::new();
+    for (number, line) in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if kind_column.is_some_and(|kind| fields.get(kind) != Some(&"keep")) {
+            continue;
+        }
+        let field = |index: usize| fields.get(index).copied().unwrap_or("");
+        let range = field(start_column)
+            .parse()
+            .and_then(|start| field(end_column).parse().map(|end| TimeRange { start, end }));
+        ranges.push(range.map_err(|e| (number + 1, e))?);
+    }
+    Ok(CutList { input: None, ranges })
+}
+
+fn read_json(text: &str) -> Result<CutList, String> {
+    let value: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let (input, ranges) = match &value {
+        Value::Object(report) => (
+            report.get("input").and_then(Value::as_str).map(str::to_string),
+            report.get("keep").ok_or("expected a `keep` list of ranges")?,
+        ),
+        _ => (None, &value),
+    };
+    let ranges = ranges
+        .as_array()
+        .ok_or("expected a list of ranges")?
+        .iter()
+        .enumerate()
+        .map(|(index, range)| json_range(range).map_err(|e| format!("range {}: {}", index + 1, e)))
+        .collect::<Result<_, _>>()?;
+    Ok(CutList { input, ranges })
+}
+
+fn json_range(range: &Value) -> Result<TimeRange, String> {
+    let (start, end) = match range {
+        Value::String(range) => return range.parse(),
+        Value::Array(pair) if pair.len() == 2 => (&pair[0], &pair[1]),
+        Value::Object(range) => (
+            range.get("start").ok_or("missing start")?,
+            range.get("end").ok_or("missing end")?,
+        ),
+        _ => return Err(format!("expected {{\"start\", \"end\"}}, [start, end] or \"START-END\", got {}", range)),
+    };
+    Ok(TimeRange {
+        start: json_time(start)?,
+        end: json_time(end)?,
+    })
+}
+
+// A time as any string a `TimeSpec` accepts, or a number of seconds.
+fn json_time(time: &Value) -> Result<TimeSpec, String> {
+    match time {
+        Value::String(time) => time.parse(),
+        Value::Number(seconds) => match seconds.as_f64() {
+            Some(seconds) if seconds >= 0.0 => {
+                Ok(TimeSpec::Time(Timestamp::from_micros((seconds * 1e6).round() as i64)))
+            }
+            _ => Err(format!("'{}' is not a non-negative number of seconds", seconds)),
+        },
+        _ => Err(format!("expected a time, got {}", time)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(text: &str) -> TimeSpec {
+        text.parse().unwrap()
+    }
+
+    fn range(start: &str, end: &str) -> TimeRange {
+        TimeRange {
+            start: time(start),
+            end: time(end),
+        }
+    }
+
+    #[test]
+    fn reads_text_lists() {
+        let cases = [
+            ("00:12-00:19\n", vec![range("00:12", "00:19")]),
+            ("1s 2s\n3s\t4s\n", vec![range("1s", "2s"), range("3s", "4s")]),
+            ("# kept\n\n1s-2s # first\n  \n", vec![range("1s", "2s")]),
+            ("frame:10-frame:20", vec![range("frame:10", "frame:20")]),
+            // Audacity labels: start, end and a label, with spectral selection lines
+            ("1.5\t2.25\tintro\n\\\t100\t2000\n3\t4\t\n", vec![range("1.5", "2.25"), range("3", "4")]),
+            ("", vec![]),
+        ];
+        for (text, ranges) in cases {
+            assert_eq!(read_text(text), Ok(CutList { input: None, ranges }), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn text_errors_give_the_line() {
+        let cases = [("1s-2s\nnonsense\n", 2), ("\n\n1s\n", 3), ("1s x\n", 1), ("1s-\n", 1), ("1s 2s\n3s-4s-5s", 2)];
+        for (text, line) in cases {
+            assert_eq!(read_text(text).map_err(|(number, _)| number), Err(line), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn reads_csv_lists() {
+        let cases = [
+            ("start,end\n1s,2s\n00:03,00:04\n", vec![range("1s", "2s"), range("00:03", "00:04")]),
+            ("End, Start\n2,1\n", vec![range("1", "2")]),
+            (
+                "kind,start,end\nkeep,1,2\ncut,2,3\nkeep,3,4\n",
+                vec![range("1", "2"), range("3", "4")],
+            ),
+            ("\nstart,end\n\n1,2\n", vec![range("1", "2")]),
+            ("start,end\n", vec![]),
+        ];
+        for (text, ranges) in cases {
+            assert_eq!(read_csv(text), Ok(CutList { input: None, ranges }), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn csv_errors_give_the_line() {
+        let cases = [
+            ("", 1),
+            ("from,to\n1,2\n", 1),
+            ("\nstart\n", 2),
+            ("start,end\n1,2\n3\n", 3),
+            ("start,end\nx,2\n", 2),
+        ];
+        for (text, line) in cases {
+            assert_eq!(read_csv(text).map_err(|(number, _)| number), Err(line), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn reads_json_lists() {
+        let cases = [
+            (r#"[{"start": 1.5, "end": "00:02"}]"#, None, vec![range("1.5", "00:02")]),
+            (r#"[[1, 2], "3s-4s"]"#, None, vec![range("1", "2"), range("3s", "4s")]),
+            (r#"[{"start": "frame:1", "end": "frame:2", "label": "x"}]"#, None, vec![range("frame:1", "frame:2")]),
+            (
+                r#"{"input": "in.mp4", "keep": [{"start": "00:00:01.000", "end": "00:00:02.000"}]}"#,
+                Some("in.mp4".to_string()),
+                vec![range("1s", "2s")],
+            ),
+            (r#"{"keep": []}"#, None, vec![]),
+        ];
+        for (text, input, ranges) in cases {
+            assert_eq!(read_json(text), Ok(CutList { input, ranges }), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        for text in [
+            "",
+            "[1, 2",
+            r#"{"input": "in.mp4"}"#,
+            r#"{"keep": {}}"#,
+            "[1]",
+            "[[1, 2, 3]]",
+            r#"[{"start": 1}]"#,
+            r#"[{"end": 1}]"#,
+            r#"[{"start": -1, "end": 1}]"#,
+            r#"[{"start": true, "end": 1}]"#,
+            r#"["1s"]"#,
+            r#"[["x", "2"]]"#,
+        ] {
+            assert!(read_json(text).is_err(), "{} parsed", text);
+        }
+    }
+
+    #[test]
+    fn json_errors_name_the_range() {
+        let error = read_json(r#"[[1, 2], [3]]"#).unwrap_err();
+        assert!(error.starts_with("range 2:"), "{}", error);
+    }
+
+    #[test]
+    fn json_times_are_strings_or_seconds() {
+        assert_eq!(json_time(&Value::from(1.5)), Ok(TimeSpec::Time(Timestamp::ms(1_500))));
+        assert_eq!(json_time(&Value::from(0)), Ok(TimeSpec::Time(Timestamp::ms(0))));
+        assert_eq!(json_time(&Value::from("frame:5")), Ok(TimeSpec::Frame(5)));
+        assert!(json_time(&Value::from(-0.5)).is_err());
+        assert!(json_time(&Value::Null).is_err());
+        assert_eq!(json_range(&Value::from("1s-2s")), Ok(range("1s", "2s")));
+    }
+
+    #[test]
+    fn resolves_ranges_within_the_input() {
+        let duration = Timestamp::ms(10_000);
+        let resolve = |range: TimeRange, rate| resolve_range("in.mp4", &range, rate, duration);
+        assert_eq!(resolve(range("1s", "2s"), None).unwrap(), (Timestamp::ms(1_000), Timestamp::ms(2_000)));
+        assert_eq!(resolve(range("9s", "20s"), None).unwrap(), (Timestamp::ms(9_000), duration));
+        let rate = Some(Rational::new(25, 1));
+        assert_eq!(resolve(range("frame:25", "frame:50"), rate).unwrap(), (Timestamp::ms(1_000), Timestamp::ms(2_000)));
+        for (start, end, rate) in [
+            ("2s", "1s", None),
+            ("2s", "2s", None),
+            ("frame:50", "frame:25", rate),
+            ("-1s", "1s", None),
+            ("10s", "11s", None),
+            ("frame:1", "frame:2", None),
+        ] {
+            assert!(
+                matches!(resolve(range(start, end), rate), Err(Error::Invalid(_))),
+                "{}-{} resolved",
+                start,
+                end
+            );
+        }
+    }
+}
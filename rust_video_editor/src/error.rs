@@ -0,0 +1,206 @@
+//! Errors returned by the editing API.
+
+use crate::timestamp::Timestamp;
+use ffmpeg_next as ffmpeg;
+use std::fmt;
+use std::io;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What went wrong, with the file (and stream, where there is one) it happened in.
+/// The underlying FFmpeg or I/O error is kept as the `source()`.
+#[derive(Debug)]
+pub enum Error {
+    /// Bad arguments, e.g. an inverted range or a frame time without a frame rate
+    Invalid(String),
+    /// FFmpeg itself could not be initialised
+    Init(ffmpeg::Error),
+    /// An input or output file could not be opened
+    Open { path: String, source: ffmpeg::Error },
+    /// A stream or container the operation needs is not one it can handle
+    Unsupported {
+        path: String,
+        stream: Option<usize>,
+        reason: String,
+    },
+    /// A stream copy cut found no video keyframe to start from
+    NoKeyframe {
+        path: String,
+        start: Timestamp,
+        end: Timestamp,
+    },
+    /// Nothing would have been written to the output
+    EmptyOutput { path: String, reason: String },
+    /// Decoding, encoding or filtering failed
+    Codec {
+        path: String,
+        stream: Option<usize>,
+        source: ffmpeg::Error,
+    },
+    /// Writing the output container failed
+    Mux { path: String, source: ffmpeg::Error },
+    /// Reading or writing a plain file (project, ranges file) failed
+    Io { path: String, source: io::Error },
+    /// A project file could not be parsed or was written by a newer version
+    Project { path: String, reason: String },
+    /// A written file did not decode cleanly or did not match its cut list
+    Verify { path: String, problems: usize },
+}
+
+impl Error {
+    pub fn invalid(reason: impl Into<String>) -> Error {
+        Error::Invalid(reason.into())
+    }
+
+    pub fn open(path: &str, source: ffmpeg::Error) -> Error {
+        Error::Open {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn unsupported(path: &str, stream: Option<usize>, reason: impl Into<String>) -> Error {
+        Error::Unsupported {
+            path: path.to_string(),
+            stream,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn empty_output(path: &str, reason: impl Into<String>) -> Error {
+        Error::EmptyOutput {
+            path: path.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn codec(path: &str, stream: Option<usize>, source: ffmpeg::Error) -> Error {
+        Error::Codec {
+            path: path.to_string(),
+            stream,
+            source,
+        }
+    }
+
+    pub fn mux(path: &str, source: ffmpeg::Error) -> Error {
+        Error::Mux {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn io(path: &str, source: io::Error) -> Error {
+        Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn project(path: &str, reason: impl Into<String>) -> Error {
+        Error::Project {
+            path: path.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn verify(path: &str, problems: usize) -> Error {
+        Error::Verify {
+            path: path.to_string(),
+            problems,
+        }
+    }
+
+    /// Process exit code for this kind of error, so scripts can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Invalid(_) => 2,
+            Error::Open { .. } => 3,
+            Error::Unsupported { .. } => 4,
+            Error::NoKeyframe { .. } => 5,
+            Error::EmptyOutput { .. } => 6,
+            Error::Init(_) | Error::Codec { .. } => 7,
+            Error::Mux { .. } => 8,
+            Error::Io { .. } => 9,
+            Error::Project { .. } => 10,
+            Error::Verify { .. } => 11,
+        }
+    }
+}
+
+// Ensure FFmpeg is initialised before touching any of its APIs.
+pub(crate) fn init() -> Result<()> {
+    ffmpeg::init().map_err(Error::Init)
+}
+
+fn stream_suffix(stream: &Option<usize>) -> String {
+    stream.map(|index| format!(" (stream {})", index)).unwrap_or_default()
+}
+
+// The source error is not repeated here: callers print the `source()` chain.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Invalid(reason) => write!(f, "{}", reason),
+            Error::Init(_) => write!(f, "Failed to initialise FFmpeg"),
+            Error::Open { path, .. } => write!(f, "Failed to open {}", path),
+            Error::Unsupported { path, stream, reason } => {
+                write!(f, "Unsupported input {}{}: {}", path, stream_suffix(stream), reason)
+            }
+            Error::NoKeyframe { path, start, end } => {
+                write!(f, "No video keyframe in {} between {} and {}", path, start, end)
+            }
+            Error::EmptyOutput { path, reason } => write!(f, "Nothing written to {}: {}", path, reason),
+            Error::Codec { path, stream, .. } => write!(f, "Codec error in {}{}", path, stream_suffix(stream)),
+            Error::Mux { path, .. } => write!(f, "Failed to write {}", path),
+            Error::Io { path, .. } => write!(f, "Failed to access {}", path),
+            Error::Project { path, reason } => write!(f, "Invalid project file {}: {}", path, reason),
+            Error::Verify { path, problems } => write!(f, "{} failed verification ({} problems)", path, problems),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Init(source)
+            | Error::Open { source, .. }
+            | Error::Codec { source, .. }
+            | Error::Mux { source, .. } => Some(source),
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_kind_of_error_has_its_exit_code() {
+        let ffmpeg = || ffmpeg::Error::Bug;
+        let cases = [
+            (Error::invalid("inverted range"), 2),
+            (Error::open("in.mp4", ffmpeg()), 3),
+            (Error::unsupported("in.mp4", Some(1), "no decoder"), 4),
+            (
+                Error::NoKeyframe {
+                    path: "in.mp4".to_string(),
+                    start: Timestamp::from_secs(1),
+                    end: Timestamp::from_secs(2),
+                },
+                5,
+            ),
+            (Error::empty_output("out.mp4", "no ranges"), 6),
+            (Error::Init(ffmpeg()), 7),
+            (Error::codec("in.mp4", None, ffmpeg()), 7),
+            (Error::mux("out.mp4", ffmpeg()), 8),
+            (Error::io("project.json", io::Error::other("disk full")), 9),
+            (Error::project("project.json", "version 2"), 10),
+            (Error::verify("out.mp4", 3), 11),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+        }
+    }
+}
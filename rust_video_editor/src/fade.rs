@@ -0,0 +1,436 @@
+//! Re-encoding the audio of a stream-copy join with short fades at the edges of every
+//! range, so jump cuts do not click. The video is still copied.
+
+use crate::error::{Error, Result};
+use crate::interleave::Interleaver;
+use crate::loudness;
+use crate::rebase::Mapping;
+use crate::render;
+use crate::timestamp::Timestamp;
+use ffmpeg::format::sample::Type as SampleType;
+use ffmpeg::format::Sample;
+use ffmpeg::{codec, decoder, encoder, filter, format, frame, media, ChannelLayout, Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use std::f64::consts::PI;
+
+// Gain `x` of the way through a fade in: half a cosine from 0 to 1, which has no
+// corner at either end for the ear to pick up.
+fn ramp(x: f64) -> f64 {
+    0.5 - 0.5 * (PI * x.clamp(0.0, 1.0)).cos()
+}
+
+// Plane `index` of `frame`, a planar f64 frame. Reached through `extended_data`,
+// since `Audio::plane_mut` panics past the eighth plane.
+fn plane_mut(frame: &mut frame::Audio, index: usize) -> &mut [f64] {
+    assert!(index < frame.planes(), "plane {} of {}", index, frame.planes());
+    let samples = frame.samples();
+    // SAFETY: `frame` was allocated as planar f64, with `planes()` planes of
+    // `samples()` samples each in `extended_data`
+    unsafe { std::slice::from_raw_parts_mut(*(*frame.as_mut_ptr()).extended_data.add(index) as *mut f64, samples) }
+}
+
+/// Fades applied to one audio stream. Samples go in as they are decoded and come out
+/// faded in at the start of each range; the last `length` are held back until it is
+/// known whether the range ends there, so they can be faded out.
+#[derive(Debug, Clone)]
+pub struct Fader {
+    length: usize,
+    // Samples of the current range taken so far
+    position: usize,
+    // Per channel, the samples not yet released
+    held: Vec<Vec<f64>>,
+}
+
+impl Fader {
+    /// Fade over `length` samples at either end of each range; 0 passes the samples
+    /// through as they are.
+    pub fn new(length: usize) -> Fader {
+        Fader {
+            length,
+            position: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Take the next `samples` of the current range, one Vec per channel, and return
+    /// those that can be written.
+    pub fn push(&mut self, mut samples: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+        let count = samples.first().map_or(0, Vec::len);
+        for i in 0..count.min(self.length.saturating_sub(self.position)) {
+            let gain = ramp((self.position + i) as f64 / self.length as f64);
+            for channel in &mut samples {
+                channel[i] *= gain;
+            }
+        }
+        self.position += count;
+        if self.held.len() != samples.len() {
+            self.held.resize(samples.len(), Vec::new());
+        }
+        let held = self.held.first().map_or(0, Vec::len);
+        let release = (held + count).saturating_sub(self.length);
+        self.held
+            .iter_mut()
+            .zip(samples)
+            .map(|(held, samples)| {
+                held.extend(samples);
+                held.drain(..release).collect()
+            })
+            .collect()
+    }
+
+    /// End the current range: fade out what is held and return it.
+    pub fn end_range(&mut self) -> Vec<Vec<f64>> {
+        self.position = 0;
+        let mut held = std::mem::take(&mut self.held);
+        let count = held.first().map_or(0, Vec::len);
+        for channel in &mut held {
+            for (i, sample) in channel.iter_mut().enumerate() {
+                *sample *= ramp((count - i - 1) as f64 / self.length as f64);
+            }
+        }
+        held
+    }
+}
+
+/// One audio stream of a join, decoded, faded and encoded again with the codec it
+/// had (or the output format's default when there is no encoder for that).
+pub(crate) struct FadedAudio {
+    input: String,
+    stream: usize,
+    time_base: Rational,
+    decoder: decoder::Audio,
+    encoder: encoder::Audio,
+    framer: filter::Graph,
+    layout: ChannelLayout,
+    rate: u32,
+    out_index: usize,
+    fader: Fader,
+    // Where the range being written starts in the source; None before the first
+    range: Option<Timestamp>,
+    // Whether the next decoded samples are the first of their range
+    starting: bool,
+    // Output position of the next sample, in samples
+    next_pts: i64,
+}
+
+impl FadedAudio {
+    /// Add the output stream for `stream` of `input`, fading over `fade` at each edge.
+    pub(crate) fn open(
+        input: &str,
+        stream: &format::stream::Stream,
+        fade: Timestamp,
+        output_file: &mut format::context::Output,
+        output: &str,
+    ) -> Result<FadedAudio> {
+        let decoder = codec::context::Context::from_parameters(stream.parameters())
+            .and_then(|ctx| ctx.decoder().audio())
+            .map_err(|e| Error::codec(input, Some(stream.index()), e))?;
+        let codec = encoder::find(stream.parameters().id())
+            .or_else(|| encoder::find(output_file.format().codec(output, media::Type::Audio)))
+            .ok_or_else(|| Error::unsupported(output, None, "no audio encoder available for the output format"))?;
+        let audio_codec = codec.audio().map_err(|e| Error::codec(output, None, e))?;
+        let global_header = output_file.format().flags().contains(format::Flags::GLOBAL_HEADER);
+        let mut out_stream = output_file.add_stream(codec).map_err(|e| Error::mux(output, e))?;
+        let mut encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .audio()
+            .map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+
+        let layout = render::audio_layout(&decoder);
+        let channel_layout = audio_codec
+            .channel_layouts()
+            .map(|layouts| layouts.best(decoder.channels() as i32))
+            .unwrap_or(layout);
+        let sample_format = audio_codec
+            .formats()
+            .and_then(|mut formats| formats.next())
+            .ok_or_else(|| Error::unsupported(output, None, "the audio encoder reports no sample formats"))?;
+        let rate = decoder.rate();
+        let time_base = Rational::new(1, rate as i32);
+
+        encoder.set_rate(rate as i32);
+        encoder.set_channel_layout(channel_layout);
+        encoder.set_format(sample_format);
+        encoder.set_time_base(time_base);
+        if decoder.bit_rate() > 0 {
+            encoder.set_bit_rate(decoder.bit_rate());
+        }
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let encoder = encoder.open_as(codec).map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+        out_stream.set_parameters(&encoder);
+        out_stream.set_time_base(time_base);
+
+        let frame_size = if audio_codec
+            .capabilities()
+            .contains(codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
+        {
+            None
+        } else {
+            Some(encoder.frame_size())
+        };
+        let framer = render::audio_graph(
+            (time_base, rate, Sample::F64(SampleType::Planar), layout),
+            (encoder.format(), encoder.channel_layout(), encoder.rate()),
+            "anull",
+            frame_size,
+        )
+        .map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+        let fade_samples = fade.to_pts(time_base).max(0) as usize;
+        Ok(FadedAudio {
+            input: input.to_string(),
+            stream: stream.index(),
+            time_base: stream.time_base(),
+            out_index: out_stream.index(),
+            decoder,
+            encoder,
+            framer,
+            layout,
+            rate,
+            fader: Fader::new(fade_samples),
+            range: None,
+            starting: true,
+            next_pts: 0,
+        })
+    }
+
+    pub(crate) fn out_index(&self) -> usize {
+        self.out_index
+    }
+
+    /// Decode `packet`, which `mapping` places in the output, and write its samples.
+    pub(crate) fn write(
+        &mut self,
+        mapping: &Mapping,
+        packet: &Packet,
+        output_file: &mut format::context::Output,
+        interleaver: &mut Interleaver,
+        output: &str,
+    ) -> Result<()> {
+        if self.range != Some(mapping.begin) {
+            if self.range.is_some() {
+                let tail = self.fader.end_range();
+                self.send(tail, output_file, interleaver, output)?;
+                // Start the decoder afresh, so the new range is not blended with the end of the last
+                self.decoder.flush();
+            }
+            self.range = Some(mapping.begin);
+            self.starting = true;
+        }
+        self.decoder
+            .send_packet(packet)
+            .map_err(|e| Error::codec(&self.input, Some(self.stream), e))?;
+        let mut decoded = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let Some(mut samples) = loudness::channel_samples(&decoded) else { continue };
+            if self.starting
+                && let Some(ts) = decoded.timestamp()
+            {
+                self.starting = false;
+                // Put the range where the video puts it: after silence if the audio ran
+                // short of the last range's video, or cut in by what ran over
+                let position = mapping.offset + (Timestamp::from_pts(ts, self.time_base) - mapping.begin);
+                let target = position.to_pts(Rational::new(1, self.rate as i32));
+                if target > self.next_pts {
+                    let silence = vec![vec![0.0; (target - self.next_pts) as usize]; samples.len()];
+                    self.send(silence, output_file, interleaver, output)?;
+                } else {
+                    let overlap = (self.next_pts - target) as usize;
+                    for channel in &mut samples {
+                        channel.drain(..overlap.min(channel.len()));
+                    }
+                }
+            }
+            let ready = self.fader.push(samples);
+            self.send(ready, output_file, interleaver, output)?;
+        }
+        Ok(())
+    }
+
+    /// Fade out the last range and write everything still buffered.
+    pub(crate) fn finish(
+        &mut self,
+        output_file: &mut format::context::Output,
+        interleaver: &mut Interleaver,
+        output: &str,
+    ) -> Result<()> {
+        let tail = self.fader.end_range();
+        self.send(tail, output_file, interleaver, output)?;
+        self.framer
+            .get("in")
+            .unwrap()
+            .source()
+            .flush()
+            .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+        self.encode_framed(output_file, interleaver, output)?;
+        self.encoder
+            .send_eof()
+            .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+        self.write_encoded(output_file, interleaver, output)
+    }
+
+    // Append `samples` (one Vec per channel) to the output.
+    fn send(
+        &mut self,
+        samples: Vec<Vec<f64>>,
+        output_file: &mut format::context::Output,
+        interleaver: &mut Interleaver,
+        output: &str,
+    ) -> Result<()> {
+        let count = samples.first().map_or(0, Vec::len);
+        if count == 0 {
+            return Ok(());
+        }
+        let mut frame = frame::Audio::new(Sample::F64(SampleType::Planar), count, self.layout);
+        for (index, channel) in samples.iter().enumerate().take(frame.planes()) {
+            plane_mut(&mut frame, index).copy_from_slice(channel);
+        }
+        frame.set_rate(self.rate);
+        frame.set_pts(Some(self.next_pts));
+        self.next_pts += count as i64;
+        self.framer
+            .get("in")
+            .unwrap()
+            .source()
+            .add(&frame)
+            .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+        self.encode_framed(output_file, interleaver, output)
+    }
+
+    fn encode_framed(
+        &mut self,
+        output_file: &mut format::context::Output,
+        interleaver: &mut Interleaver,
+        output: &str,
+    ) -> Result<()> {
+        let mut framed = frame::Audio::empty();
+        while self.framer.get("out").unwrap().sink().frame(&mut framed).is_ok() {
+            self.encoder
+                .send_frame(&framed)
+                .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+            self.write_encoded(output_file, interleaver, output)?;
+        }
+        Ok(())
+    }
+
+    fn write_encoded(
+        &mut self,
+        output_file: &mut format::context::Output,
+        interleaver: &mut Interleaver,
+        output: &str,
+    ) -> Result<()> {
+        let out_time_base = output_file.stream(self.out_index).expect("Output stream not found").time_base();
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(self.out_index);
+            packet.rescale_ts(Rational::new(1, self.rate as i32), out_time_base);
+            interleaver
+                .push(std::mem::replace(&mut packet, Packet::empty()), out_time_base, output_file)
+                .map_err(|e| Error::mux(output, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The gain the fader gives each sample of a range `count` samples long, pushed
+    // `frame` samples at a time over two channels.
+    fn gains(length: usize, count: usize, frame: usize) -> Vec<f64> {
+        let mut fader = Fader::new(length);
+        let mut out = vec![Vec::new(); 2];
+        let mut sent = 0;
+        while sent < count {
+            let size = frame.min(count - sent);
+            for (out, samples) in out.iter_mut().zip(fader.push(vec![vec![1.0; size]; 2])) {
+                out.extend(samples);
+            }
+            sent += size;
+        }
+        for (out, samples) in out.iter_mut().zip(fader.end_range()) {
+            out.extend(samples);
+        }
+        assert_eq!(out[0], out[1], "channels faded differently");
+        out.swap_remove(0)
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn fades_in_and_out_over_the_length() {
+        let half = 0.5 - 0.5 * (PI / 4.0).cos();
+        let expected = [0.0, half, 0.5, 1.0 - half, 1.0, 1.0 - half, 0.5, half, 0.0];
+        assert_close(&gains(4, 9, 9), &expected);
+        assert_close(&gains(4, 12, 12)[4..8], &[1.0; 4]);
+    }
+
+    #[test]
+    fn gain_is_zero_at_both_ends_and_never_above_one() {
+        for (length, count) in [(1, 1), (1, 5), (4, 9), (48, 1000), (441, 2000)] {
+            let gains = gains(length, count, count);
+            assert_eq!(gains.first(), Some(&0.0), "length {} count {}", length, count);
+            assert_eq!(gains.last(), Some(&0.0), "length {} count {}", length, count);
+            assert!(gains.iter().all(|gain| (0.0..=1.0).contains(gain)));
+        }
+    }
+
+    #[test]
+    fn short_range_overlaps_the_fades() {
+        // Shorter than both fades together: each sample gets the product of the two
+        let overlapping = gains(4, 5, 5);
+        let ramp_at = |i: usize| ramp(i as f64 / 4.0);
+        let expected: Vec<f64> = (0..5).map(|i| ramp_at(i) * ramp_at(4 - i)).collect();
+        assert_close(&overlapping, &expected);
+        assert_close(&overlapping, &overlapping.iter().rev().copied().collect::<Vec<_>>());
+        // Shorter than one fade
+        let short = gains(8, 3, 3);
+        assert_eq!(short.len(), 3);
+        assert_eq!((short[0], short[2]), (0.0, 0.0));
+        assert!(short[1] > 0.0 && short[1] < 1.0);
+    }
+
+    #[test]
+    fn frames_smaller_than_the_fade_give_the_same_gains() {
+        for (length, count) in [(4, 9), (4, 5), (16, 40), (8, 3)] {
+            let whole = gains(length, count, count);
+            for frame in [1, 2, 3, length - 1] {
+                assert_close(&gains(length, count, frame), &whole);
+            }
+        }
+    }
+
+    #[test]
+    fn a_zero_length_fade_leaves_the_samples_alone() {
+        for (count, frame) in [(1, 1), (9, 9), (10, 3)] {
+            assert_eq!(gains(0, count, frame), vec![1.0; count]);
+        }
+    }
+
+    #[test]
+    fn every_sample_comes_out_once() {
+        let mut fader = Fader::new(10);
+        let (mut taken, mut given) = (0, 0);
+        let mut frames = [4, 1, 9, 0, 30].into_iter().cycle();
+        for range in [3, 25, 10, 0, 7, 100] {
+            let mut sent = 0;
+            while sent < range {
+                let count = frames.next().unwrap().min(range - sent);
+                let ready = fader.push(vec![vec![0.25; count]; 3]);
+                assert!(ready.iter().all(|channel| channel.len() == ready[0].len()));
+                given += ready[0].len();
+                sent += count;
+            }
+            taken += sent;
+            given += fader.end_range().first().map_or(0, Vec::len);
+        }
+        assert_eq!(given, taken);
+    }
+}
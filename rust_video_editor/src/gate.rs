@@ -0,0 +1,224 @@
+//! A noise gate over a loudness timeline.
+
+use crate::loudness::Level;
+use crate::timestamp::Timestamp;
+
+/// Settings of a noise gate. The gate opens when the smoothed level rises above
+/// `open_db` and closes once it has stayed below `close_db` for `hold`. Attack and
+/// release are the time constants of the level smoothing while it rises and falls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gate {
+    pub open_db: f64,
+    pub close_db: f64,
+    pub attack: Timestamp,
+    pub hold: Timestamp,
+    pub release: Timestamp,
+}
+
+impl Gate {
+    /// The intervals during which the gate is open. `levels` must be in time order;
+    /// time not covered by any level, and levels without speech, count as digital silence.
+    pub fn open_intervals(&self, levels: &[Level]) -> Vec<(Timestamp, Timestamp)> {
+        let open_power = db_to_power(self.open_db);
+        let close_power = db_to_power(self.close_db.min(self.open_db));
+        let mut intervals = Vec::new();
+        let mut envelope = 0.0;
+        // Start of the current open interval
+        let mut opened: Option<Timestamp> = None;
+        // When the envelope last fell below the close threshold while open
+        let mut below_since: Option<Timestamp> = None;
+        let mut position: Option<Timestamp> = None;
+
+        // The levels with silent levels filling the gaps between them
+        let filled = levels.iter().scan(None, |last_end: &mut Option<Timestamp>, level| {
+            let gap = last_end
+                .filter(|&end| level.start > end)
+                .map(|end| Level {
+                    start: end,
+                    end: level.start,
+                    power: 0.0,
+                    speech: false,
+                });
+            *last_end = Some(level.end);
+            Some(gap.into_iter().chain(std::iter::once(*level)))
+        });
+        for level in filled.flatten() {
+            let power = if level.speech { level.power } else { 0.0 };
+            envelope = self.follow(envelope, power, level.end - level.start);
+            match opened {
+                None if envelope > open_power => {
+                    opened = Some(level.start);
+                    below_since = None;
+                }
+                None => {}
+                Some(start) if envelope < close_power => {
+                    let since = *below_since.get_or_insert(level.start);
+                    if level.end >= since + self.hold {
+                        intervals.push((start, since + self.hold));
+                        opened = None;
+                        below_since = None;
+                    }
+                }
+                Some(_) => below_since = None,
+            }
+            position = Some(level.end);
+        }
+        if let (Some(start), Some(end)) = (opened, position) {
+            let end = below_since.map_or(end, |since| end.min(since + self.hold));
+            intervals.push((start, end));
+        }
+        intervals
+    }
+
+    // Move the envelope toward `power` over `duration`, at the attack rate while
+    // rising and the release rate while falling.
+    fn follow(&self, envelope: f64, power: f64, duration: Timestamp) -> f64 {
+        let constant = if power > envelope { self.attack } else { self.release };
+        if constant.as_micros() <= 0 {
+            return power;
+        }
+        let decay = (-(duration.as_micros() as f64) / constant.as_micros() as f64).exp();
+        power + (envelope - power) * decay
+    }
+}
+
+fn db_to_power(db: f64) -> f64 {
+    10f64.powf(db / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Consecutive 10 ms levels, one per entry of `dbs`, starting at zero.
+    fn timeline(dbs: &[f64]) -> Vec<Level> {
+        dbs.iter()
+            .enumerate()
+            .map(|(index, &db)| Level {
+                start: Timestamp::ms(index as i64 * 10),
+                end: Timestamp::ms(index as i64 * 10 + 10),
+                power: db_to_power(db),
+                speech: true,
+            })
+            .collect()
+    }
+
+    // `before` quiet levels, `loud` loud ones, then `after` quiet ones.
+    fn burst(before: usize, loud: usize, after: usize) -> Vec<Level> {
+        let mut dbs = vec![-60.0; before];
+        dbs.extend(vec![-20.0; loud]);
+        dbs.extend(vec![-60.0; after]);
+        timeline(&dbs)
+    }
+
+    fn gate(open_db: f64, close_db: f64) -> Gate {
+        Gate {
+            open_db,
+            close_db,
+            attack: Timestamp::default(),
+            hold: Timestamp::default(),
+            release: Timestamp::default(),
+        }
+    }
+
+    #[test]
+    fn opens_over_the_loud_levels() {
+        assert_eq!(
+            gate(-40.0, -40.0).open_intervals(&burst(10, 20, 10)),
+            vec![(Timestamp::ms(100), Timestamp::ms(300))]
+        );
+        assert_eq!(gate(-40.0, -40.0).open_intervals(&burst(10, 0, 10)), vec![]);
+        assert_eq!(gate(-40.0, -40.0).open_intervals(&[]), vec![]);
+    }
+
+    #[test]
+    fn stays_open_to_the_end() {
+        assert_eq!(gate(-40.0, -40.0).open_intervals(&burst(0, 5, 0)), vec![(Timestamp::ms(0), Timestamp::ms(50))]);
+        let hold = Gate { hold: Timestamp::ms(100), ..gate(-40.0, -40.0) };
+        assert_eq!(hold.open_intervals(&burst(0, 5, 3)), vec![(Timestamp::ms(0), Timestamp::ms(80))]);
+        assert_eq!(hold.open_intervals(&burst(0, 5, 20)), vec![(Timestamp::ms(0), Timestamp::ms(150))]);
+    }
+
+    #[test]
+    fn closes_below_the_close_threshold_only() {
+        // Dips to -45 dB stay above a -50 dB close threshold
+        let dbs = [-60.0, -30.0, -45.0, -45.0, -30.0, -55.0, -60.0];
+        assert_eq!(gate(-40.0, -50.0).open_intervals(&timeline(&dbs)), vec![(Timestamp::ms(10), Timestamp::ms(50))]);
+        assert_eq!(
+            gate(-40.0, -40.0).open_intervals(&timeline(&dbs)),
+            vec![(Timestamp::ms(10), Timestamp::ms(20)), (Timestamp::ms(40), Timestamp::ms(50))]
+        );
+        // A close threshold above the open one acts as the open one
+        let levels = timeline(&dbs);
+        assert_eq!(gate(-40.0, -30.0).open_intervals(&levels), gate(-40.0, -40.0).open_intervals(&levels));
+    }
+
+    #[test]
+    fn hold_bridges_short_silences() {
+        let hold = Gate { hold: Timestamp::ms(30), ..gate(-40.0, -40.0) };
+        let mut levels = burst(5, 5, 2);
+        levels.extend(burst(0, 5, 10).into_iter().map(|level| Level {
+            start: level.start + Timestamp::ms(120),
+            end: level.end + Timestamp::ms(120),
+            ..level
+        }));
+        assert_eq!(hold.open_intervals(&levels), vec![(Timestamp::ms(50), Timestamp::ms(200))]);
+        let short = Gate { hold: Timestamp::ms(10), ..hold };
+        assert_eq!(
+            short.open_intervals(&levels),
+            vec![(Timestamp::ms(50), Timestamp::ms(110)), (Timestamp::ms(120), Timestamp::ms(180))]
+        );
+    }
+
+    #[test]
+    fn gaps_and_non_speech_count_as_silence() {
+        let mut levels = burst(0, 5, 0);
+        levels.extend(burst(0, 5, 0).into_iter().map(|level| Level {
+            start: level.start + Timestamp::ms(200),
+            end: level.end + Timestamp::ms(200),
+            ..level
+        }));
+        assert_eq!(
+            gate(-40.0, -40.0).open_intervals(&levels),
+            vec![(Timestamp::ms(0), Timestamp::ms(50)), (Timestamp::ms(200), Timestamp::ms(250))]
+        );
+        let mut levels = burst(2, 6, 2);
+        for level in &mut levels[4..6] {
+            level.speech = false;
+        }
+        assert_eq!(
+            gate(-40.0, -40.0).open_intervals(&levels),
+            vec![(Timestamp::ms(20), Timestamp::ms(40)), (Timestamp::ms(60), Timestamp::ms(80))]
+        );
+    }
+
+    #[test]
+    fn attack_delays_opening_and_release_delays_closing() {
+        // -37 dB is 3 dB over the threshold: the envelope needs more than one 20 ms
+        // time constant's worth of it to get there
+        let mut dbs = vec![-60.0; 5];
+        dbs.extend(vec![-37.0; 10]);
+        dbs.extend(vec![-90.0; 10]);
+        let levels = timeline(&dbs);
+        assert_eq!(gate(-40.0, -40.0).open_intervals(&levels), vec![(Timestamp::ms(50), Timestamp::ms(150))]);
+        let attack = Gate { attack: Timestamp::ms(20), ..gate(-40.0, -40.0) };
+        assert_eq!(attack.open_intervals(&levels), vec![(Timestamp::ms(60), Timestamp::ms(150))]);
+        let release = Gate { release: Timestamp::ms(20), ..gate(-40.0, -40.0) };
+        let intervals = release.open_intervals(&levels);
+        assert_eq!(intervals.len(), 1);
+        assert_eq!(intervals[0].0, Timestamp::ms(50));
+        assert!(intervals[0].1 > Timestamp::ms(150), "{:?}", intervals);
+    }
+
+    #[test]
+    fn follow_approaches_the_power_exponentially() {
+        let smooth = Gate { attack: Timestamp::ms(10), release: Timestamp::ms(40), ..gate(-40.0, -40.0) };
+        let rising = smooth.follow(0.0, 1.0, Timestamp::ms(10));
+        assert!((rising - (1.0 - (-1.0f64).exp())).abs() < 1e-12);
+        let falling = smooth.follow(1.0, 0.0, Timestamp::ms(40));
+        assert!((falling - (-1.0f64).exp()).abs() < 1e-12);
+        assert_eq!(smooth.follow(0.5, 0.5, Timestamp::ms(10)), 0.5);
+        assert_eq!(gate(-40.0, -40.0).follow(0.25, 1.0, Timestamp::ms(1)), 1.0);
+        assert_eq!(gate(-40.0, -40.0).follow(1.0, 0.25, Timestamp::ms(1)), 0.25);
+    }
+}
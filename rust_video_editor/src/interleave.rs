@@ -0,0 +1,230 @@
+use crate::timestamp::Timestamp;
+use ffmpeg::{format, Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Packets held before the oldest one is written regardless of the other streams.
+pub(crate) const DEFAULT_LOOKAHEAD: usize = 512;
+
+struct Queued<T> {
+    dts: Timestamp,
+    // Arrival order, so packets with equal dts keep their order
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Queued<T> {}
+
+impl<T> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Queued<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.dts, self.seq).cmp(&(other.dts, other.seq))
+    }
+}
+
+// The ordering behind `Interleaver`, apart from the packets so it can be tested
+// without FFmpeg.
+struct Queue<T> {
+    heap: BinaryHeap<Reverse<Queued<T>>>,
+    // Latest dts queued per stream that has not finished
+    latest: HashMap<usize, Timestamp>,
+    finished: HashSet<usize>,
+    lookahead: usize,
+    seq: u64,
+}
+
+impl<T> Queue<T> {
+    fn new(lookahead: usize) -> Self {
+        Queue {
+            heap: BinaryHeap::new(),
+            latest: HashMap::new(),
+            finished: HashSet::new(),
+            lookahead: lookahead.max(1),
+            seq: 0,
+        }
+    }
+
+    // An item without a dts goes with the stream's previous one
+    fn push(&mut self, stream: usize, dts: Option<Timestamp>, item: T) {
+        let dts = dts.or_else(|| self.latest.get(&stream).copied()).unwrap_or_default();
+        if !self.finished.contains(&stream) {
+            self.latest.insert(stream, dts);
+        }
+        self.heap.push(Reverse(Queued {
+            dts,
+            seq: self.seq,
+            item,
+        }));
+        self.seq += 1;
+    }
+
+    fn finish_stream(&mut self, stream: usize) {
+        self.finished.insert(stream);
+        self.latest.remove(&stream);
+    }
+
+    // The next item that can no longer be overtaken, or any next item when `all`
+    fn pop(&mut self, all: bool) -> Option<T> {
+        let Reverse(head) = self.heap.peek()?;
+        let safe = self.latest.values().all(|&latest| head.dts <= latest);
+        if !(all || safe || self.heap.len() > self.lookahead) {
+            return None;
+        }
+        self.heap.pop().map(|Reverse(queued)| queued.item)
+    }
+}
+
+/// Writes packets from several output streams in dts order across streams, the way
+/// the muxer wants them. A packet is written once every stream that has started and
+/// not finished has queued something at or after its dts, or when more than
+/// `lookahead` packets are held, so memory stays bounded even when a stream goes quiet.
+/// A stream that never sends a packet is never waited for.
+pub(crate) struct Interleaver {
+    queue: Queue<Packet>,
+}
+
+impl Interleaver {
+    /// An interleaver holding at most `lookahead` packets (at least one).
+    pub fn new(lookahead: usize) -> Self {
+        Interleaver {
+            queue: Queue::new(lookahead),
+        }
+    }
+
+    /// Queue `packet`, whose timestamps are already in the output stream's `time_base`,
+    /// then write out everything that can no longer be overtaken.
+    pub fn push(
+        &mut self,
+        packet: Packet,
+        time_base: Rational,
+        output: &mut format::context::Output,
+    ) -> Result<(), ffmpeg::Error> {
+        let dts = packet.dts().or(packet.pts()).map(|ts| Timestamp::from_pts(ts, time_base));
+        self.queue.push(packet.stream(), dts, packet);
+        self.drain(output, false)
+    }
+
+    /// Stop waiting for output stream `stream`, which will send no more packets, and
+    /// write out what was only held for it.
+    pub fn finish_stream(&mut self, stream: usize, output: &mut format::context::Output) -> Result<(), ffmpeg::Error> {
+        self.queue.finish_stream(stream);
+        self.drain(output, false)
+    }
+
+    /// Write everything still queued.
+    pub fn flush(&mut self, output: &mut format::context::Output) -> Result<(), ffmpeg::Error> {
+        self.drain(output, true)
+    }
+
+    fn drain(&mut self, output: &mut format::context::Output, all: bool) -> Result<(), ffmpeg::Error> {
+        while let Some(packet) = self.queue.pop(all) {
+            packet.write(output)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dts(ms: i64) -> Option<Timestamp> {
+        Some(Timestamp::ms(ms))
+    }
+
+    fn drain<T>(queue: &mut Queue<T>, all: bool) -> Vec<T> {
+        std::iter::from_fn(|| queue.pop(all)).collect()
+    }
+
+    #[test]
+    fn packets_wait_until_every_started_stream_has_caught_up() {
+        let mut queue = Queue::new(DEFAULT_LOOKAHEAD);
+        queue.push(0, dts(0), "v0");
+        // The only stream so far cannot be overtaken by itself
+        assert_eq!(drain(&mut queue, false), ["v0"]);
+        queue.push(0, dts(40), "v40");
+        queue.push(1, dts(20), "a20");
+        assert_eq!(drain(&mut queue, false), ["a20"]);
+        queue.push(1, dts(41), "a41");
+        queue.push(1, dts(62), "a62");
+        assert_eq!(drain(&mut queue, false), ["v40"]);
+        queue.push(0, dts(80), "v80");
+        assert_eq!(drain(&mut queue, false), ["a41", "a62"]);
+    }
+
+    #[test]
+    fn equal_dts_keep_their_arrival_order() {
+        let mut queue = Queue::new(DEFAULT_LOOKAHEAD);
+        for (stream, name) in [(0, "v"), (1, "a"), (2, "s"), (1, "a2")] {
+            queue.push(stream, dts(100), name);
+        }
+        assert_eq!(drain(&mut queue, true), ["v", "a", "s", "a2"]);
+    }
+
+    #[test]
+    fn a_packet_without_dts_goes_with_its_streams_previous_one() {
+        let mut queue = Queue::new(DEFAULT_LOOKAHEAD);
+        queue.push(0, dts(50), "v50");
+        queue.push(1, dts(60), "a60");
+        queue.push(0, None, "v?");
+        queue.push(1, None, "a?");
+        assert_eq!(drain(&mut queue, true), ["v50", "v?", "a60", "a?"]);
+    }
+
+    #[test]
+    fn a_quiet_stream_holds_no_more_than_the_lookahead() {
+        let mut queue = Queue::new(4);
+        queue.push(1, dts(0), 0);
+        let mut written = drain(&mut queue, false);
+        for t in 1..=10 {
+            queue.push(0, dts(t * 10), t);
+            written.extend(drain(&mut queue, false));
+        }
+        // Stream 1 stopped at 0, so only the lookahead lets stream 0 through
+        assert_eq!(written, [0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(drain(&mut queue, true), [7, 8, 9, 10]);
+        assert_eq!(queue.pop(true), None);
+    }
+
+    #[test]
+    fn a_finished_stream_is_no_longer_waited_for() {
+        let mut queue = Queue::new(DEFAULT_LOOKAHEAD);
+        queue.push(0, dts(0), "v0");
+        queue.push(1, dts(0), "a0");
+        queue.push(0, dts(40), "v40");
+        queue.push(0, dts(80), "v80");
+        assert_eq!(drain(&mut queue, false), ["v0", "a0"]);
+        queue.finish_stream(1);
+        assert_eq!(drain(&mut queue, false), ["v40", "v80"]);
+        // A straggler from the finished stream does not make the others wait again
+        queue.push(1, dts(90), "a90");
+        queue.push(0, dts(120), "v120");
+        assert_eq!(drain(&mut queue, false), ["a90", "v120"]);
+    }
+
+    #[test]
+    fn flushing_writes_everything_in_dts_order() {
+        let mut queue = Queue::new(DEFAULT_LOOKAHEAD);
+        queue.push(0, dts(0), "v0");
+        assert_eq!(drain(&mut queue, false), ["v0"]);
+        queue.push(1, dts(10), "a10");
+        queue.push(1, dts(30), "a30");
+        queue.push(0, dts(20), "v20");
+        assert_eq!(drain(&mut queue, false), ["a10", "v20"]);
+        queue.push(2, dts(5), "s5");
+        assert_eq!(drain(&mut queue, true), ["s5", "a30"]);
+        assert!(queue.heap.is_empty());
+    }
+}
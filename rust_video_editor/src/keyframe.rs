@@ -0,0 +1,343 @@
+//! Keyframe lookup through an index of every video keyframe, built in one pass over
+//! the packets and cached in memory and, when enabled, in a file next to the source.
+
+use crate::cache;
+use crate::error::{self, Error, Result};
+use crate::timestamp::Timestamp;
+use ffmpeg::{format, media, Rational};
+use ffmpeg_next as ffmpeg;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+
+/// Bump when the layout of the cache files changes, so old ones are rebuilt.
+const INDEX_VERSION: u32 = 1;
+
+/// One video keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Presentation time in the stream's time base
+    pub pts: i64,
+    /// Byte offset of its packet in the file, when the demuxer reports one
+    pub position: Option<i64>,
+}
+
+/// The keyframes of one video stream, in pts order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamKeyframes {
+    pub stream: usize,
+    /// Numerator and denominator of the stream's time base
+    pub time_base: (i32, i32),
+    pub keyframes: Vec<Keyframe>,
+    /// Largest pts of any packet of the stream
+    pub last_pts: Option<i64>,
+    /// Largest pts - dts seen, i.e. how far decoding runs ahead of presentation
+    pub delay: i64,
+}
+
+impl StreamKeyframes {
+    pub fn time_base(&self) -> Rational {
+        Rational::new(self.time_base.0, self.time_base.1)
+    }
+
+    /// The last keyframe at or before `pts`.
+    pub fn at_or_before(&self, pts: i64) -> Option<Keyframe> {
+        let after = self.keyframes.partition_point(|keyframe| keyframe.pts <= pts);
+        after.checked_sub(1).map(|i| self.keyframes[i])
+    }
+
+    /// The first keyframe at or after `pts`.
+    pub fn at_or_after(&self, pts: i64) -> Option<Keyframe> {
+        let i = self.keyframes.partition_point(|keyframe| keyframe.pts < pts);
+        self.keyframes.get(i).copied()
+    }
+
+    /// Time of the last keyframe at or before `time`.
+    pub fn time_at_or_before(&self, time: Timestamp) -> Option<Timestamp> {
+        let time_base = self.time_base();
+        self.at_or_before(time.to_pts(time_base))
+            .map(|keyframe| Timestamp::from_pts(keyframe.pts, time_base))
+    }
+
+    /// Time of the first keyframe at or after `time`.
+    pub fn time_at_or_after(&self, time: Timestamp) -> Option<Timestamp> {
+        let time_base = self.time_base();
+        self.at_or_after(time.to_pts(time_base))
+            .map(|keyframe| Timestamp::from_pts(keyframe.pts, time_base))
+    }
+
+    /// Where the GOP starting at the keyframe `pts` ends: the next keyframe, or just
+    /// past the last packet of the stream.
+    pub fn gop_end(&self, pts: i64) -> i64 {
+        self.at_or_after(pts.saturating_add(1))
+            .map(|keyframe| keyframe.pts)
+            .unwrap_or_else(|| self.last_pts.map_or(pts, |last| last.max(pts)) + 1)
+    }
+}
+
+/// Size and modification time of a file, to tell whether an index still describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    modified_ns: u128,
+}
+
+impl FileStamp {
+    fn of(path: &str) -> Result<FileStamp> {
+        let metadata = fs::metadata(path).map_err(|e| Error::io(path, e))?;
+        let modified = metadata.modified().map_err(|e| Error::io(path, e))?;
+        Ok(FileStamp {
+            size: metadata.len(),
+            modified_ns: modified.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos()),
+        })
+    }
+}
+
+/// Every keyframe of every video stream of one file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyframeIndex {
+    version: u32,
+    // None for inputs FFmpeg opens but the filesystem cannot stat (URLs, pipes),
+    // whose indexes are never cached
+    source: Option<FileStamp>,
+    /// The stream FFmpeg picks as the best video stream, if there is video
+    pub best_video: Option<usize>,
+    pub streams: Vec<StreamKeyframes>,
+}
+
+impl KeyframeIndex {
+    /// Index `input` by reading all its packets once, without decoding.
+    pub fn build(input: &str) -> Result<KeyframeIndex> {
+        error::init()?;
+        let source = FileStamp::of(input).ok();
+        let mut input_file = format::input(&input).map_err(|e| Error::open(input, e))?;
+        let best_video = input_file.streams().best(media::Type::Video).map(|stream| stream.index());
+        let mut streams: Vec<StreamKeyframes> = input_file
+            .streams()
+            .filter(|stream| stream.parameters().medium() == media::Type::Video)
+            .map(|stream| StreamKeyframes {
+                stream: stream.index(),
+                time_base: (stream.time_base().numerator(), stream.time_base().denominator()),
+                keyframes: Vec::new(),
+                last_pts: None,
+                delay: 0,
+            })
+            .collect();
+        for (stream, packet) in input_file.packets() {
+            let Some(entry) = streams.iter_mut().find(|entry| entry.stream == stream.index()) else { continue };
+            let Some(pts) = packet.pts() else { continue };
+            if packet.is_key() {
+                let position = packet.position();
+                entry.keyframes.push(Keyframe {
+                    pts,
+                    position: (position >= 0).then_some(position as i64),
+                });
+            }
+            if let Some(dts) = packet.dts() {
+                entry.delay = entry.delay.max(pts - dts);
+            }
+            entry.last_pts = entry.last_pts.max(Some(pts));
+        }
+        for entry in &mut streams {
+            entry.keyframes.sort_unstable_by_key(|keyframe| keyframe.pts);
+            entry.keyframes.dedup_by_key(|keyframe| keyframe.pts);
+        }
+        Ok(KeyframeIndex {
+            version: INDEX_VERSION,
+            source,
+            best_video,
+            streams,
+        })
+    }
+
+    /// The keyframes of the best video stream.
+    pub fn video(&self) -> Option<&StreamKeyframes> {
+        self.best_video.and_then(|index| self.stream(index))
+    }
+
+    pub fn stream(&self, index: usize) -> Option<&StreamKeyframes> {
+        self.streams.iter().find(|entry| entry.stream == index)
+    }
+
+    // Whether this index was built from `path` as it is now.
+    fn describes(&self, path: &str) -> bool {
+        self.version == INDEX_VERSION
+            && self.source.is_some()
+            && FileStamp::of(path).ok() == self.source
+    }
+}
+
+static DISK_CACHE: AtomicBool = AtomicBool::new(false);
+
+/// Also keep indexes in `<source>.keyframes.json` files, so later runs can skip
+/// the indexing pass. Off by default.
+pub fn set_disk_cache(enabled: bool) {
+    DISK_CACHE.store(enabled, Ordering::Relaxed);
+}
+
+fn memory() -> &'static Mutex<HashMap<String, Arc<KeyframeIndex>>> {
+    static MEMORY: OnceLock<Mutex<HashMap<String, Arc<KeyframeIndex>>>> = OnceLock::new();
+    MEMORY.get_or_init(Default::default)
+}
+
+fn cache_path(input: &str) -> String {
+    format!("{}.keyframes.json", input)
+}
+
+/// The index of `input` if one is at hand (in memory, or on disk when the disk cache
+/// is enabled) and still matches the file. Never scans the file.
+pub fn cached(input: &str) -> Option<Arc<KeyframeIndex>> {
+    let mut memory = memory().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(index) = memory.get(input).filter(|index| index.describes(input)) {
+        return Some(Arc::clone(index));
+    }
+    if !DISK_CACHE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let text = fs::read_to_string(cache_path(input)).ok()?;
+    let index: KeyframeIndex = serde_json::from_str(&text).ok()?;
+    if !index.describes(input) {
+        return None;
+    }
+    let index = Arc::new(index);
+    memory.insert(input.to_string(), Arc::clone(&index));
+    Some(index)
+}
+
+/// The index of `input`, from the caches or built (and cached) now. Inputs that are
+/// not files are indexed every time.
+pub fn index(input: &str) -> Result<Arc<KeyframeIndex>> {
+    if let Some(index) = cached(input) {
+        return Ok(index);
+    }
+    let Ok(stamp) = FileStamp::of(input) else {
+        return KeyframeIndex::build(input).map(Arc::new);
+    };
+    let mut index = cache::cached(input, "keyframes", INDEX_VERSION, &(), || KeyframeIndex::build(input))?;
+    // The analysis cache goes by content, so the entry may come from a copy of the file
+    index.source = Some(stamp);
+    let index = Arc::new(index);
+    if DISK_CACHE.load(Ordering::Relaxed) {
+        // Like the analysis cache, this one must never make a cut fail: an index that
+        // cannot be written (read-only media, say) only costs the next run a scan
+        let text = serde_json::to_string(&*index).expect("Keyframe index serializes");
+        cache::write_atomically(Path::new(&cache_path(input)), text.as_bytes()).ok();
+    }
+    let mut memory = memory().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    memory.insert(input.to_string(), Arc::clone(&index));
+    Ok(index)
+}
+
+/// First keyframe of the best video stream at or after `start`, or None when there
+/// is no video or no keyframe after `start`.
+pub fn find_next_keyframe(input: &str, start: Timestamp) -> Result<Option<Timestamp>> {
+    Ok(index(input)?.video().and_then(|video| video.time_at_or_after(start)))
+}
+
+/// Seek `input_file`, opened from `path`, so that reading starts at the last video
+/// keyframe at or before `start`. The keyframe comes from the index when one is
+/// cached, or when the disk cache is on from one built now for later runs to reuse;
+/// otherwise the demuxer looks for it.
+pub fn seek(input_file: &mut format::context::Input, path: &str, start: Timestamp) -> Result<()> {
+    let index = if DISK_CACHE.load(Ordering::Relaxed) { Some(index(path)?) } else { cached(path) };
+    let target = index
+        .and_then(|index| index.video()?.time_at_or_before(start))
+        .unwrap_or(start);
+    let seek_ts = target.as_micros();
+    input_file
+        .seek(seek_ts, ..seek_ts)
+        .map_err(|e| Error::codec(path, None, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keyframes at 0, 2, 4 and 6 s in a 1/1000 time base, the last packet at 7.5 s
+    fn layout() -> StreamKeyframes {
+        StreamKeyframes {
+            stream: 1,
+            time_base: (1, 1000),
+            keyframes: [0, 2000, 4000, 6000].map(|pts| Keyframe { pts, position: None }).to_vec(),
+            last_pts: Some(7500),
+            delay: 0,
+        }
+    }
+
+    #[test]
+    fn lookups_find_the_keyframe_on_either_side() {
+        let layout = layout();
+        let pts = |keyframe: Option<Keyframe>| keyframe.map(|keyframe| keyframe.pts);
+        let cases = [(0, Some(0), Some(0)), (1999, Some(0), Some(2000)), (2000, Some(2000), Some(2000))];
+        for (at, before, after) in cases {
+            assert_eq!(pts(layout.at_or_before(at)), before, "{}", at);
+            assert_eq!(pts(layout.at_or_after(at)), after, "{}", at);
+        }
+        assert_eq!(pts(layout.at_or_before(-1)), None);
+        assert_eq!(pts(layout.at_or_after(6001)), None);
+        assert_eq!(pts(layout.at_or_before(i64::MAX)), Some(6000));
+    }
+
+    #[test]
+    fn a_gop_ends_at_the_next_keyframe_or_just_past_the_last_packet() {
+        let layout = layout();
+        assert_eq!(layout.gop_end(0), 2000);
+        assert_eq!(layout.gop_end(4000), 6000);
+        assert_eq!(layout.gop_end(6000), 7501);
+        let empty = StreamKeyframes {
+            keyframes: Vec::new(),
+            last_pts: None,
+            ..layout
+        };
+        assert_eq!(empty.gop_end(100), 101);
+    }
+
+    #[test]
+    fn time_lookups_convert_through_the_time_base() {
+        let layout = layout();
+        assert_eq!(layout.time_at_or_after(Timestamp::from_millis(2500)), Some(Timestamp::from_millis(4000)));
+        assert_eq!(layout.time_at_or_before(Timestamp::from_millis(2500)), Some(Timestamp::from_millis(2000)));
+        assert_eq!(layout.time_at_or_after(Timestamp::from_millis(6500)), None);
+        let ntsc = StreamKeyframes {
+            time_base: (1001, 30000),
+            keyframes: vec![Keyframe { pts: 300, position: None }],
+            ..layout
+        };
+        // 300 frames at 29.97 fps
+        assert_eq!(ntsc.time_at_or_after(Timestamp::from_millis(5000)), Some(Timestamp::from_millis(10_010)));
+    }
+
+    #[test]
+    fn the_video_is_the_best_video_stream() {
+        let index = KeyframeIndex {
+            version: INDEX_VERSION,
+            source: None,
+            best_video: Some(1),
+            streams: vec![StreamKeyframes { stream: 0, ..layout() }, layout()],
+        };
+        assert_eq!(index.video().map(|video| video.stream), Some(1));
+        assert_eq!(index.stream(0).map(|video| video.stream), Some(0));
+        assert!(index.stream(2).is_none());
+        let audio_only = KeyframeIndex {
+            best_video: None,
+            streams: Vec::new(),
+            ..index
+        };
+        assert!(audio_only.video().is_none());
+    }
+
+    #[test]
+    fn an_index_without_a_file_stamp_describes_nothing() {
+        let index = KeyframeIndex {
+            version: INDEX_VERSION,
+            source: None,
+            best_video: None,
+            streams: Vec::new(),
+        };
+        assert!(!index.describes("Cargo.toml"));
+    }
+}
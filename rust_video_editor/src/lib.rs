@@ -0,0 +1,31 @@
+//! Cutting, joining and silence removal for video files, built on FFmpeg.
+//!
+//! Times are [`timestamp::Timestamp`]s (microseconds). The main entry points are
+//! [`cut::cut_video`] and [`segment::write_segments`] for stream-copy cuts and joins,
+//! [`smart_render::smart_cut`] for frame-accurate cuts, [`silence`] for detecting and
+//! removing silence, [`keyframe::find_next_keyframe`], [`probe`] for stream facts,
+//! [`project::Project`] with [`render::render`] for multi-clip edits and
+//! [`verify::verify`] for checking what was written.
+
+pub mod cache;
+pub mod cut;
+pub mod error;
+pub mod fade;
+pub mod gate;
+mod interleave;
+pub mod keyframe;
+pub mod loudness;
+pub mod probe;
+pub mod project;
+mod rebase;
+pub mod report;
+pub mod render;
+pub mod segment;
+pub mod silence;
+pub mod smart_render;
+pub mod timeline;
+pub mod timestamp;
+pub mod vad;
+pub mod verify;
+
+pub use error::{Error, Result};
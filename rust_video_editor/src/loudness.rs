@@ -0,0 +1,727 @@
+//! Audio level measurement.
+
+use crate::cache;
+use crate::error::{self, Error, Result};
+use crate::timestamp::Timestamp;
+use crate::vad;
+use ffmpeg::format::{self, Sample};
+use ffmpeg::{codec, decoder, frame, media, Rational};
+use ffmpeg_next as ffmpeg;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Level of one stretch of an audio stream.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// Mean square of the samples, full scale = 1.0
+    pub power: f64,
+    /// Whether voice activity detection heard speech; always true without it
+    pub speech: bool,
+}
+
+/// The loudness timeline of one audio stream: consecutive, non-overlapping levels in
+/// time order, one per hop.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamLevels {
+    pub stream: usize,
+    pub levels: Vec<Level>,
+}
+
+/// How audio is cut into windows for measuring. Each window is `window_ms` long and
+/// windows start every `hop_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Windowing {
+    pub window_ms: u64,
+    pub hop_ms: u64,
+}
+
+pub const DEFAULT_WINDOW_MS: u64 = 20;
+pub const DEFAULT_HOP_MS: u64 = 10;
+
+impl Default for Windowing {
+    fn default() -> Self {
+        Windowing {
+            window_ms: DEFAULT_WINDOW_MS,
+            hop_ms: DEFAULT_HOP_MS,
+        }
+    }
+}
+
+impl Windowing {
+    // Window and hop in samples at `rate`, at least one sample each.
+    fn samples(&self, rate: u32) -> (usize, usize) {
+        let samples = |ms: u64| ((rate as u64 * ms / 1000) as usize).max(1);
+        let window = samples(self.window_ms);
+        (window, samples(self.hop_ms).min(window))
+    }
+}
+
+// Slides a window over one stream's samples. Each window's level is reported for
+// the hop-long slot at its centre, so the levels tile the timeline; the first slot of
+// a run reaches back to the run's first sample.
+struct Windower {
+    rate: u32,
+    window: usize,
+    hop: usize,
+    // Time of the run's first sample; None before any audio or after a gap
+    anchor: Option<Timestamp>,
+    // Samples between the anchor and `powers[0]`
+    offset: usize,
+    // Per-sample power (mean square over the channels), from `offset` on
+    powers: VecDeque<f64>,
+    // The channels mixed down, from `offset` on; only kept for voice detection
+    mono: VecDeque<f64>,
+    detector: Option<vad::Detector>,
+    // Whether the next level is the first of its run
+    first: bool,
+    levels: Vec<Level>,
+}
+
+impl Windower {
+    fn new(rate: u32, windowing: &Windowing, detect_voice: bool) -> Self {
+        let (window, hop) = windowing.samples(rate);
+        Windower {
+            rate,
+            window,
+            hop,
+            anchor: None,
+            offset: 0,
+            powers: VecDeque::new(),
+            mono: VecDeque::new(),
+            detector: detect_voice.then(|| vad::Detector::new(rate, window)),
+            first: true,
+            levels: Vec::new(),
+        }
+    }
+
+    // Time of sample `index` of the buffer.
+    fn time(&self, anchor: Timestamp, index: usize) -> Timestamp {
+        anchor + Timestamp::from_samples(self.offset + index, self.rate)
+    }
+
+    // Add the samples of a frame, one vector per channel, whose first sample plays at `start`.
+    fn push(&mut self, start: Timestamp, channels: &[Vec<f64>]) {
+        let mut overlap = 0;
+        if let Some(anchor) = self.anchor {
+            let expected = self.time(anchor, self.powers.len());
+            let tolerance = Timestamp::from_samples(self.window / 2, self.rate);
+            if start - expected > tolerance {
+                // A gap in the stream starts a new run
+                self.finish();
+            } else if expected - start > tolerance {
+                // Samples that play before the end of what was measured would put levels
+                // out of order, so only the part after it is taken
+                overlap = (expected - start).to_pts(Rational::new(1, self.rate as i32)) as usize;
+            }
+        }
+        let trimmed: Vec<Vec<f64>>;
+        let channels = if overlap > 0 {
+            trimmed = channels.iter().map(|channel| channel[overlap.min(channel.len())..].to_vec()).collect();
+            &trimmed[..]
+        } else {
+            channels
+        };
+        if self.anchor.is_none() {
+            self.anchor = Some(start);
+            self.offset = 0;
+            self.first = true;
+        }
+        self.powers.extend(powers(channels));
+        if self.detector.is_some() {
+            self.mono.extend(mix_down(channels));
+        }
+        while self.powers.len() >= self.window {
+            self.emit(self.window);
+            self.powers.drain(..self.hop);
+            self.mono.drain(..self.hop.min(self.mono.len()));
+            self.offset += self.hop;
+        }
+    }
+
+    fn emit(&mut self, count: usize) {
+        let Some(anchor) = self.anchor else { return };
+        let power = self.powers.iter().take(count).sum::<f64>() / count as f64;
+        let start = if self.first {
+            self.time(anchor, 0)
+        } else {
+            self.time(anchor, (self.window - self.hop) / 2)
+        };
+        let end = if count < self.window {
+            self.time(anchor, count)
+        } else {
+            self.time(anchor, (self.window + self.hop) / 2)
+        };
+        let speech = self.detector.as_ref().is_none_or(|detector| {
+            let samples: Vec<f64> = self.mono.iter().take(count).copied().collect();
+            detector.features(&samples).is_speech()
+        });
+        if end > start {
+            self.levels.push(Level {
+                start,
+                end,
+                power,
+                speech,
+            });
+        }
+        self.first = false;
+    }
+
+    // Report whatever the last full window left uncovered and end the run.
+    fn finish(&mut self) {
+        if let Some(anchor) = self.anchor {
+            let covered = self.levels.last().map_or(Timestamp::default(), |level| level.end);
+            if !self.powers.is_empty() && self.time(anchor, self.powers.len()) > covered {
+                self.emit(self.powers.len().min(self.window));
+            }
+        }
+        self.anchor = None;
+        self.powers.clear();
+        self.mono.clear();
+    }
+}
+
+// Mixes streams, each already mixed down to one channel, into one at `rate` by time,
+// resampling those at other rates. A stretch comes out once every stream has sent what
+// plays there, or once more than `lookahead` samples are held so a stream that goes
+// quiet cannot hold everything up; what comes later for a stretch already out is
+// dropped. Where no stream plays the mix has a gap.
+struct Mixer {
+    rate: u32,
+    streams: usize,
+    lookahead: usize,
+    // Slot (sample at `rate`, counted from time zero) of `held[0]`
+    base: i64,
+    // Per slot from `base` on: the samples added up, and whether any stream played there
+    held: VecDeque<(f64, bool)>,
+    // Per stream: the slot after the last one it sent
+    reached: HashMap<usize, i64>,
+    // Slots before this are out
+    released: i64,
+}
+
+// How long the mixer holds samples for a quiet stream.
+const MIX_LOOKAHEAD_SECS: usize = 10;
+
+impl Mixer {
+    fn new(rate: u32, streams: usize) -> Self {
+        Mixer {
+            rate,
+            streams,
+            lookahead: rate as usize * MIX_LOOKAHEAD_SECS,
+            base: 0,
+            held: VecDeque::new(),
+            reached: HashMap::new(),
+            released: i64::MIN,
+        }
+    }
+
+    fn slot_time(&self, slot: i64) -> Timestamp {
+        Timestamp::from_pts(slot, Rational::new(1, self.rate as i32))
+    }
+
+    // Add the samples `stream` plays from `start` on at `rate`, interpolating linearly
+    // between them when that is not the mix's rate.
+    fn push(&mut self, stream: usize, start: Timestamp, rate: u32, samples: &[f64]) {
+        let Some(&last) = samples.last() else { return };
+        let time_base = Rational::new(1, self.rate as i32);
+        let first = start.to_pts(time_base);
+        let end = (start + Timestamp::from_samples(samples.len(), rate)).to_pts(time_base);
+        let from = first.max(self.released);
+        if self.held.is_empty() {
+            self.base = from;
+        }
+        if from < self.base {
+            for _ in from..self.base {
+                self.held.push_front((0.0, false));
+            }
+            self.base = from;
+        }
+        let needed = (end - self.base).max(0) as usize;
+        if needed > self.held.len() {
+            self.held.resize(needed, (0.0, false));
+        }
+        let step = rate as f64 / self.rate as f64;
+        for slot in from..end {
+            let position = (slot - first) as f64 * step;
+            let index = position as usize;
+            let sample = samples.get(index).copied().unwrap_or(last);
+            let next = samples.get(index + 1).copied().unwrap_or(sample);
+            let held = &mut self.held[(slot - self.base) as usize];
+            held.0 += sample + (next - sample) * (position - index as f64);
+            held.1 = true;
+        }
+        let reached = self.reached.entry(stream).or_insert(end);
+        *reached = (*reached).max(end);
+    }
+
+    // The stretches that can come out, or all held when `all`: each a start time and
+    // its samples, split where no stream played.
+    fn pop(&mut self, all: bool) -> Vec<(Timestamp, Vec<f64>)> {
+        let held_end = self.base + self.held.len() as i64;
+        let mut ready = match self.reached.values().min() {
+            _ if all => held_end,
+            Some(&reached) if self.reached.len() >= self.streams => reached,
+            _ => self.base,
+        };
+        if self.held.len() > self.lookahead {
+            ready = ready.max(held_end - self.lookahead as i64);
+        }
+        let ready = ready.clamp(self.base, held_end);
+        let mut runs: Vec<(Timestamp, Vec<f64>)> = Vec::new();
+        let mut playing = false;
+        for slot in self.base..ready {
+            let (sample, played) = self.held.pop_front().expect("Held slot vanished");
+            match runs.last_mut() {
+                Some((_, run)) if played && playing => run.push(sample),
+                _ if played => runs.push((self.slot_time(slot), vec![sample])),
+                _ => {}
+            }
+            playing = played;
+        }
+        self.base = ready;
+        self.released = self.released.max(ready);
+        runs
+    }
+}
+
+// One audio stream being decoded during `measure`.
+struct StreamDecoder {
+    decoder: decoder::Audio,
+    time_base: Rational,
+    // End of the previous frame, for frames without a timestamp
+    next_start: Timestamp,
+}
+
+impl StreamDecoder {
+    // Hand each frame the decoder has ready to `take`: when it starts, its sample rate
+    // and its channels.
+    fn receive_frames(&mut self, mut take: impl FnMut(Timestamp, u32, &[Vec<f64>])) {
+        let mut frame = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            let start = frame
+                .timestamp()
+                .map(|ts| Timestamp::from_pts(ts, self.time_base))
+                .unwrap_or(self.next_start);
+            self.next_start = start + Timestamp::from_samples(frame.samples(), frame.rate());
+            if let Some(channels) = channel_samples(&frame) {
+                take(start, frame.rate(), &channels);
+            }
+        }
+    }
+}
+
+// The levels a windower measured, with voice detection narrowed to the windows whose
+// surroundings rise and fall like speech.
+fn finish_levels(mut windower: Windower, windowing: &Windowing) -> Vec<Level> {
+    windower.finish();
+    let mut levels = windower.levels;
+    if windower.detector.is_some() {
+        // One window cannot tell a vowel from a held note, but the rise and fall of the
+        // level around it can
+        let span = (vad::MODULATION_SPAN_MS / windowing.hop_ms).max(1) as usize;
+        let powers: Vec<f64> = levels.iter().map(|level| level.power).collect();
+        for (level, modulated) in levels.iter_mut().zip(vad::modulated(&powers, span)) {
+            level.speech &= modulated;
+        }
+    }
+    levels
+}
+
+// Bump whenever a change here changes the levels measured, so cached timelines are
+// not reused.
+const MEASURE_VERSION: u32 = 2;
+
+/// Decode the audio streams of `input` listed in `streams` (every audio stream when
+/// None) together in one demux pass and measure each one over sliding windows, with
+/// voice activity detection on each window when `detect_voice` is set. With `mix` the
+/// streams are mixed into one, sample by sample, and only that is measured: the one
+/// timeline returned has the first stream's index. Results come from the analysis
+/// cache when it has them.
+pub fn measure(
+    input: &str,
+    streams: Option<&[usize]>,
+    windowing: &Windowing,
+    detect_voice: bool,
+    mix: bool,
+) -> Result<Vec<StreamLevels>> {
+    if windowing.window_ms == 0 || windowing.hop_ms == 0 {
+        return Err(Error::invalid("The analysis window and hop must be at least 1 ms"));
+    }
+    let params = (streams, windowing, detect_voice, mix);
+    cache::cached(input, "loudness", MEASURE_VERSION, &params, || {
+        measure_uncached(input, streams, windowing, detect_voice, mix)
+    })
+}
+
+fn measure_uncached(
+    input: &str,
+    streams: Option<&[usize]>,
+    windowing: &Windowing,
+    detect_voice: bool,
+    mix: bool,
+) -> Result<Vec<StreamLevels>> {
+    error::init()?;
+    let mut input_file = format::input(&input).map_err(|e| Error::open(input, e))?;
+    let mut decoders = HashMap::new();
+    for stream in input_file.streams() {
+        let index = stream.index();
+        let is_audio = stream.parameters().medium() == media::Type::Audio;
+        let wanted = match streams {
+            Some(wanted) => wanted.contains(&index),
+            None => is_audio,
+        };
+        if !wanted {
+            continue;
+        }
+        if !is_audio {
+            return Err(Error::unsupported(input, Some(index), "not an audio stream"));
+        }
+        let decoder = codec::context::Context::from_parameters(stream.parameters())
+            .and_then(|ctx| ctx.decoder().audio())
+            .map_err(|e| Error::codec(input, Some(index), e))?;
+        decoders.insert(
+            index,
+            StreamDecoder {
+                decoder,
+                time_base: stream.time_base(),
+                next_start: Timestamp::default(),
+            },
+        );
+    }
+    if let Some(missing) = streams.and_then(|wanted| wanted.iter().find(|index| !decoders.contains_key(index))) {
+        return Err(Error::invalid(format!("{} has no stream {}", input, missing)));
+    }
+    if decoders.is_empty() {
+        return Err(Error::unsupported(input, None, "no audio stream to analyse"));
+    }
+
+    // Mixed at the highest rate of the streams, so none loses its top end
+    let mut mixing = mix.then(|| {
+        let rate = decoders.values().map(|stream| stream.decoder.rate()).max().unwrap_or(0).max(1);
+        (Mixer::new(rate, decoders.len()), Windower::new(rate, windowing, detect_voice))
+    });
+    let mut windowers: HashMap<usize, Windower> = HashMap::new();
+    let mut take = |index: usize, stream: &mut StreamDecoder, mixing: &mut Option<(Mixer, Windower)>| {
+        stream.receive_frames(|start, rate, channels| match mixing.as_mut() {
+            Some((mixer, _)) => mixer.push(index, start, rate, &mix_down(channels)),
+            None => windowers
+                .entry(index)
+                .or_insert_with(|| Windower::new(rate, windowing, detect_voice))
+                .push(start, channels),
+        });
+    };
+    for (stream, packet) in input_file.packets() {
+        let index = stream.index();
+        let Some(decoder) = decoders.get_mut(&index) else { continue };
+        decoder
+            .decoder
+            .send_packet(&packet)
+            .map_err(|e| Error::codec(input, Some(index), e))?;
+        take(index, decoder, &mut mixing);
+        if let Some((mixer, windower)) = mixing.as_mut() {
+            for (start, samples) in mixer.pop(false) {
+                windower.push(start, &[samples]);
+            }
+        }
+    }
+    let mut indices: Vec<usize> = decoders.keys().copied().collect();
+    indices.sort();
+    for &index in &indices {
+        let decoder = decoders.get_mut(&index).expect("Decoder vanished");
+        decoder.decoder.send_eof().map_err(|e| Error::codec(input, Some(index), e))?;
+        take(index, decoder, &mut mixing);
+    }
+    if let Some((mut mixer, mut windower)) = mixing {
+        for (start, samples) in mixer.pop(true) {
+            windower.push(start, &[samples]);
+        }
+        return Ok(vec![StreamLevels {
+            stream: indices[0],
+            levels: finish_levels(windower, windowing),
+        }]);
+    }
+    Ok(indices
+        .into_iter()
+        .map(|index| StreamLevels {
+            stream: index,
+            levels: windowers.remove(&index).map_or(Vec::new(), |windower| finish_levels(windower, windowing)),
+        })
+        .collect())
+}
+
+/// The samples of each channel of `frame`, scaled to full scale = 1.0. Every packed
+/// and planar sample format is read; None for a frame without a sample format or
+/// without samples, like the empty frames some decoders return at the end.
+pub fn channel_samples(frame: &frame::Audio) -> Option<Vec<Vec<f64>>> {
+    let format = frame.format();
+    let bytes = format.bytes();
+    let (channels, samples) = (frame.channels() as usize, frame.samples());
+    if format == Sample::None || bytes == 0 || channels == 0 || samples == 0 {
+        return None;
+    }
+    // One plane per channel, padded past the last sample, or one with the channels
+    // interleaved. `planes()` is 0 for a frame whose data FFmpeg never set.
+    let (planes, length) = if format.is_planar() {
+        (channels, samples * bytes)
+    } else {
+        (1, samples * channels * bytes)
+    };
+    if frame.planes() < planes {
+        return None;
+    }
+    let data: Vec<&[u8]> = (0..planes).map(|plane| plane_bytes(frame, plane, length)).collect();
+    Some(read_channels(format, bytes, &data, channels))
+}
+
+// The `bytes`-wide samples of each of `channels` channels in `planes`: one plane per
+// channel, or one with the channels interleaved.
+fn read_channels(format: Sample, bytes: usize, planes: &[&[u8]], channels: usize) -> Vec<Vec<f64>> {
+    if planes.len() == channels {
+        return planes
+            .iter()
+            .map(|plane| plane.chunks_exact(bytes).map(|b| sample_value(format, b)).collect())
+            .collect();
+    }
+    let mut read = vec![Vec::with_capacity(planes[0].len() / bytes / channels); channels];
+    for (index, b) in planes[0].chunks_exact(bytes).enumerate() {
+        read[index % channels].push(sample_value(format, b));
+    }
+    read
+}
+
+// The first `length` bytes of plane `index` of `frame`, which the caller has checked
+// is below `frame.planes()`. Read through `extended_data`, since `Audio::data` panics
+// past the eighth plane and sizes the others by a linesize that FFmpeg only sets for
+// the first plane of audio.
+fn plane_bytes(frame: &frame::Audio, index: usize, length: usize) -> &[u8] {
+    // SAFETY: an audio frame with samples and a nonzero linesize has `planes()` planes
+    // in `extended_data`, each holding at least the frame's samples of its channels
+    unsafe { std::slice::from_raw_parts(*(*frame.as_ptr()).extended_data.add(index), length) }
+}
+
+/// Power of each sample instant of `frame`: the mean square over its channels.
+pub fn sample_powers(frame: &frame::Audio) -> Option<Vec<f64>> {
+    channel_samples(frame).map(|channels| powers(&channels))
+}
+
+fn powers(channels: &[Vec<f64>]) -> Vec<f64> {
+    let count = channels.len().max(1) as f64;
+    let mut powers = vec![0.0; channels.first().map_or(0, Vec::len)];
+    for channel in channels {
+        for (power, sample) in powers.iter_mut().zip(channel) {
+            *power += sample * sample / count;
+        }
+    }
+    powers
+}
+
+// The average of the channels at each sample instant.
+fn mix_down(channels: &[Vec<f64>]) -> Vec<f64> {
+    let count = channels.len().max(1) as f64;
+    let mut mono = vec![0.0; channels.first().map_or(0, Vec::len)];
+    for channel in channels {
+        for (mixed, sample) in mono.iter_mut().zip(channel) {
+            *mixed += sample / count;
+        }
+    }
+    mono
+}
+
+// One sample in native byte order, scaled so full scale is 1.0.
+fn sample_value(format: Sample, b: &[u8]) -> f64 {
+    match format {
+        Sample::U8(_) => (b[0] as f64 - 128.0) / 128.0,
+        Sample::I16(_) => i16::from_ne_bytes([b[0], b[1]]) as f64 / 32_768.0,
+        Sample::I32(_) => i32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as f64 / 2_147_483_648.0,
+        Sample::I64(_) => i64::from_ne_bytes(b.try_into().expect("8-byte sample")) as f64 / 9_223_372_036_854_775_808.0,
+        Sample::F32(_) => f32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        Sample::F64(_) => f64::from_ne_bytes(b.try_into().expect("8-byte sample")),
+        Sample::None => 0.0,
+    }
+}
+
+/// RMS level of `frame` in dBFS (negative infinity for digital silence), or None when
+/// the frame has no samples or no sample format.
+pub fn rms_db(frame: &frame::Audio) -> Option<f64> {
+    let powers = sample_powers(frame)?;
+    if powers.is_empty() {
+        return None;
+    }
+    Some(mean_square_db(powers.iter().sum::<f64>() / powers.len() as f64))
+}
+
+/// Convert a mean square (full scale = 1.0) to dBFS.
+pub fn mean_square_db(mean_square: f64) -> f64 {
+    10.0 * mean_square.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Levels of 100-sample frames at 1 kHz starting at each of `starts`, in milliseconds.
+    fn levels(starts: &[i64]) -> Vec<Level> {
+        let mut windower = Windower::new(1000, &Windowing::default(), false);
+        for &start in starts {
+            windower.push(Timestamp::ms(start), &[vec![0.5; 100]]);
+        }
+        windower.finish();
+        windower.levels
+    }
+
+    fn assert_tiled(levels: &[Level]) {
+        for pair in levels.windows(2) {
+            assert!(pair[0].end <= pair[1].start, "{:?} overlaps {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn consecutive_frames_tile_the_timeline() {
+        let levels = levels(&[0, 100]);
+        assert_tiled(&levels);
+        assert_eq!(levels.first().unwrap().start, Timestamp::ms(0));
+        assert_eq!(levels.last().unwrap().end, Timestamp::ms(200));
+        assert!(levels.iter().all(|level| (level.power - 0.25).abs() < 1e-12));
+    }
+
+    #[test]
+    fn overlapping_frame_only_adds_what_follows() {
+        let levels = levels(&[0, 100, 150]);
+        assert_tiled(&levels);
+        assert_eq!(levels.last().unwrap().end, Timestamp::ms(250));
+    }
+
+    #[test]
+    fn frame_entirely_before_the_end_adds_nothing() {
+        assert_eq!(levels(&[0, 100, 0]), levels(&[0, 100]));
+    }
+
+    #[test]
+    fn gap_starts_a_new_run() {
+        let levels = levels(&[0, 500]);
+        assert_tiled(&levels);
+        assert!(levels.iter().any(|level| level.start == Timestamp::ms(500)));
+        assert_eq!(levels.last().unwrap().end, Timestamp::ms(600));
+        assert!(levels.iter().all(|level| level.end <= Timestamp::ms(100) || level.start >= Timestamp::ms(500)));
+    }
+
+    #[test]
+    fn samples_are_scaled_to_full_scale_in_every_format() {
+        use format::sample::Type::{Packed, Planar};
+        for layout in [Packed, Planar] {
+            let cases: Vec<(Sample, Vec<u8>, f64)> = vec![
+                // Unsigned 8-bit is biased by 128
+                (Sample::U8(layout), vec![0], -1.0),
+                (Sample::U8(layout), vec![128], 0.0),
+                (Sample::U8(layout), vec![192], 0.5),
+                (Sample::U8(layout), vec![255], 127.0 / 128.0),
+                (Sample::I16(layout), i16::MIN.to_ne_bytes().to_vec(), -1.0),
+                (Sample::I16(layout), 16_384i16.to_ne_bytes().to_vec(), 0.5),
+                (Sample::I16(layout), i16::MAX.to_ne_bytes().to_vec(), 32_767.0 / 32_768.0),
+                (Sample::I32(layout), i32::MIN.to_ne_bytes().to_vec(), -1.0),
+                (Sample::I32(layout), (1i32 << 30).to_ne_bytes().to_vec(), 0.5),
+                (Sample::I32(layout), (-1i32 << 29).to_ne_bytes().to_vec(), -0.25),
+                (Sample::I64(layout), i64::MIN.to_ne_bytes().to_vec(), -1.0),
+                (Sample::I64(layout), (1i64 << 62).to_ne_bytes().to_vec(), 0.5),
+                (Sample::F32(layout), 0.25f32.to_ne_bytes().to_vec(), 0.25),
+                (Sample::F64(layout), (-0.75f64).to_ne_bytes().to_vec(), -0.75),
+            ];
+            for (format, bytes, expected) in cases {
+                assert_eq!(sample_value(format, &bytes), expected, "{:?}", format);
+            }
+        }
+    }
+
+    fn i16_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_ne_bytes()).collect()
+    }
+
+    #[test]
+    fn packed_channels_are_deinterleaved() {
+        let format = Sample::I16(format::sample::Type::Packed);
+        let data = i16_bytes(&[16_384, -16_384, 8_192, 0, 0, 8_192]);
+        let channels = read_channels(format, 2, &[&data], 2);
+        assert_eq!(channels, [vec![0.5, 0.25, 0.0], vec![-0.5, 0.0, 0.25]]);
+        let channels = read_channels(format, 2, &[&data], 3);
+        assert_eq!(channels, [vec![0.5, 0.0], vec![-0.5, 0.0], vec![0.25, 0.25]]);
+    }
+
+    #[test]
+    fn planar_channels_are_read_plane_by_plane() {
+        let format = Sample::I16(format::sample::Type::Planar);
+        let (left, right) = (i16_bytes(&[16_384, 8_192]), i16_bytes(&[-16_384, 0]));
+        let channels = read_channels(format, 2, &[&left, &right], 2);
+        assert_eq!(channels, [vec![0.5, 0.25], vec![-0.5, 0.0]]);
+        // Mono is the same either way
+        let packed = read_channels(Sample::I16(format::sample::Type::Packed), 2, &[&left], 1);
+        assert_eq!(read_channels(format, 2, &[&left], 1), packed);
+    }
+
+    // Stretches the mixer lets out: start in microseconds and samples
+    fn runs(mixer: &mut Mixer, all: bool) -> Vec<(i64, Vec<f64>)> {
+        mixer.pop(all).into_iter().map(|(start, samples)| (start.as_micros(), samples)).collect()
+    }
+
+    #[test]
+    fn mixed_streams_add_up_by_time() {
+        let mut mixer = Mixer::new(1000, 2);
+        mixer.push(0, Timestamp::ms(0), 1000, &[0.125, 0.25, 0.5, 0.5]);
+        // Nothing is out until the other stream has caught up
+        assert_eq!(runs(&mut mixer, false), []);
+        mixer.push(1, Timestamp::ms(2), 1000, &[0.25, 0.25, 0.25]);
+        assert_eq!(runs(&mut mixer, false), [(0, vec![0.125, 0.25, 0.75, 0.75])]);
+        mixer.push(0, Timestamp::ms(4), 1000, &[0.5, 0.5]);
+        assert_eq!(runs(&mut mixer, false), [(4_000, vec![0.75])]);
+        assert_eq!(runs(&mut mixer, true), [(5_000, vec![0.5])]);
+    }
+
+    #[test]
+    fn opposite_streams_cancel_in_the_mix() {
+        let mut mixer = Mixer::new(1000, 2);
+        let tone: Vec<f64> = (0..8).map(|i| if i % 2 == 0 { 0.5 } else { -0.5 }).collect();
+        let inverted: Vec<f64> = tone.iter().map(|sample| -sample).collect();
+        mixer.push(0, Timestamp::ms(0), 1000, &tone);
+        mixer.push(1, Timestamp::ms(0), 1000, &inverted);
+        // Silence, where adding the powers would have made it louder than either
+        assert_eq!(runs(&mut mixer, true), [(0, vec![0.0; 8])]);
+    }
+
+    #[test]
+    fn a_stream_at_another_rate_is_interpolated() {
+        let mut mixer = Mixer::new(2000, 2);
+        mixer.push(0, Timestamp::ms(0), 2000, &[0.5; 4]);
+        mixer.push(1, Timestamp::ms(0), 1000, &[0.0, 0.5]);
+        assert_eq!(runs(&mut mixer, false), [(0, vec![0.5, 0.75, 1.0, 1.0])]);
+    }
+
+    #[test]
+    fn the_mix_has_gaps_where_no_stream_plays() {
+        let mut mixer = Mixer::new(1000, 2);
+        mixer.push(0, Timestamp::ms(0), 1000, &[0.25; 2]);
+        mixer.push(1, Timestamp::ms(1), 1000, &[0.25; 2]);
+        mixer.push(0, Timestamp::ms(6), 1000, &[0.5; 2]);
+        mixer.push(1, Timestamp::ms(7), 1000, &[0.5]);
+        let expected = [(0, vec![0.25, 0.5, 0.25]), (6_000, vec![0.5, 1.0])];
+        assert_eq!(runs(&mut mixer, false), expected);
+    }
+
+    #[test]
+    fn a_quiet_stream_holds_the_mix_up_no_further_than_the_lookahead() {
+        let mut mixer = Mixer::new(1000, 2);
+        mixer.push(0, Timestamp::ms(0), 1000, &vec![0.25; 12_000]);
+        let out = runs(&mut mixer, false);
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].0, out[0].1.len()), (0, 2_000));
+        // The late stream only adds to what is still held
+        mixer.push(1, Timestamp::ms(0), 1000, &vec![0.5; 3_000]);
+        let out = runs(&mut mixer, true);
+        assert_eq!(out.len(), 1);
+        let (start, samples) = &out[0];
+        assert_eq!((*start, samples.len()), (2_000_000, 10_000));
+        assert!(samples[..1_000].iter().all(|&sample| sample == 0.75));
+        assert!(samples[1_000..].iter().all(|&sample| sample == 0.25));
+    }
+}
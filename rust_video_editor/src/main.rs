@@ -1,477 +1,641 @@
-use clap::{Parser, Subcommand};
-use ffmpeg_next as ffmpeg;
-use std::collections::HashMap;
-use ffmpeg::{format};
-use std::fs;
+use clap::{Args, Parser, Subcommand};
+use rust_video_editor::loudness::{Windowing, DEFAULT_HOP_MS, DEFAULT_WINDOW_MS};
+use rust_video_editor::project::Project;
+use rust_video_editor::report::{self, ReportFormat};
+use rust_video_editor::silence::{
+    self, Analysis, Detection, SilenceSettings, StreamPolicy, Threshold, DEFAULT_AUTO_MARGIN_DB, DEFAULT_MERGE_GAP_MS,
+    DEFAULT_MIN_KEEP_MS,
+};
+use rust_video_editor::timestamp::{TimeRange, TimeSpec, Timestamp};
+use rust_video_editor::verify::{self, Expected, DEFAULT_TOLERANCE_MS};
+use rust_video_editor::{cache, cut, keyframe, probe, segment, smart_render, Error, Result};
+use std::path::PathBuf;
+use std::process;
 
+/// Cut videos, remove their silences and join the parts without re-encoding.
 #[derive(Parser)]
 #[command(name = "Rust Video Editor")]
 struct Cli {
+    /// Project file (JSON, or TOML for a `.toml` path) that commands without an output update
+    #[arg(long, global = true, default_value = "project.json")]
+    project: String,
+    /// Keep keyframe indexes in `<source>.keyframes.json` files for later runs; a
+    /// cut builds the index of a source that has none yet, so later cuts seek by it
+    #[arg(long, global = true)]
+    keyframe_cache: bool,
+    /// Directory for cached analysis results, instead of the user cache directory
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Neither read nor write cached analysis results
+    #[arg(long, global = true, conflicts_with = "cache_dir")]
+    no_cache: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Add a file to the project as a source, with a full-length clip of it
     Load {
+        /// Media file to add
         filename: String,
     },
+    /// Print the format, streams and keyframes of a file
+    Probe {
+        /// Media file to describe
+        input: String,
+        /// Print the report as JSON for other tools
+        #[arg(long)]
+        json: bool,
+    },
+    /// Render the project's timeline to a file
     Export {
-        output: String,
+        /// Output path; defaults to the output saved in the project by the last export
+        output: Option<String>,
+        /// Always decode and re-encode instead of stream copying
+        #[arg(long)]
+        reencode: bool,
+        /// Re-encode the audio of a stream copy, fading it out and in over this many
+        /// milliseconds at every join so the cuts do not click (e.g. 10)
+        #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+        audio_fade: Option<u64>,
+        /// Cut list (see --ranges-file of Cut) that replaces silence removal on its source
+        #[arg(long)]
+        ranges_file: Option<String>,
+        /// Source the cut list applies to, when the list does not say and the project
+        /// has more than one
+        #[arg(long, requires = "ranges_file")]
+        source: Option<String>,
+        #[command(flatten)]
+        verify: VerifyArgs,
     },
+    /// Keep ranges of a file, writing them joined or trimming the project's clips
     Cut {
+        /// Media file to cut
         input: String,
-        start: u32,
-        end: u32,
-        output: String,
+        /// Start of the range to keep, e.g. `00:01:02.345`, `62.345s`, `1500ms` or `frame:1490`
+        #[arg(requires = "end")]
+        start: Option<TimeSpec>,
+        /// End of the range to keep, in the same forms as START
+        end: Option<TimeSpec>,
+        /// Output path; without one the ranges become the in/out points of the project's clips from INPUT
+        output: Option<String>,
+        /// More ranges to keep as START-END, e.g. `--range 00:12-00:19`
+        #[arg(long = "range", value_name = "START-END")]
+        ranges: Vec<TimeRange>,
+        /// Cut list: JSON (e.g. from Analyze), CSV with start/end columns, Audacity
+        /// labels or text with one START-END range per line
+        #[arg(long)]
+        ranges_file: Option<String>,
+        /// Output path, for when only --range/--ranges-file give the ranges
+        #[arg(short = 'o', long = "output", conflicts_with = "output")]
+        output_file: Option<String>,
+        /// Re-encode the partial GOPs at the cut points for frame-accurate edges
+        #[arg(long)]
+        smart: bool,
+        /// Re-encode the audio, fading it out and in over this many milliseconds at
+        /// every cut so it does not click (e.g. 10); the video is still copied
+        #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "smart")]
+        audio_fade: Option<u64>,
+        /// Treat the ranges as the parts to remove and keep everything else
+        #[arg(long)]
+        delete: bool,
+        #[command(flatten)]
+        verify: VerifyArgs,
     },
+    /// Keep only the parts of a file that are not silent
     RemoveSilence {
+        /// Media file to remove silence from
+        input: String,
+        #[command(flatten)]
+        silence: SilenceArgs,
+        /// Output path; without one the settings are saved to the project and applied on Export
+        output: Option<String>,
+        /// Output path, for when --auto takes the place of the threshold
+        #[arg(short = 'o', long = "output", conflicts_with = "output")]
+        output_file: Option<String>,
+        /// Re-encode the audio, fading it out and in over this many milliseconds at
+        /// every cut so it does not click (e.g. 10); the video is still copied
+        #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+        audio_fade: Option<u64>,
+        #[command(flatten)]
+        verify: VerifyArgs,
+    },
+    /// Detect silence without cutting and write what was found for review
+    Analyze {
+        /// Media file to analyse
         input: String,
-        threshold: f64,
-        output: String,
+        #[command(flatten)]
+        silence: SilenceArgs,
+        /// Files to write: .json, .csv, .txt (Audacity labels), .ffconcat (ffmpeg -f concat
+        /// -safe 0 -i, as it names the input by absolute path) or .filter (ffmpeg
+        /// -filter_complex_script); repeat for several
+        #[arg(short = 'o', long = "output", value_name = "FILE", required = true)]
+        outputs: Vec<String>,
+        /// Write every output in this format instead of going by its extension
+        #[arg(long)]
+        format: Option<ReportFormat>,
+    },
+    /// Decode a whole file and check its timestamps, and its duration against a cut list
+    Verify {
+        /// Media file to check
+        input: String,
+        /// Ranges the file was cut to, as START-END
+        #[arg(long = "range", value_name = "START-END")]
+        ranges: Vec<TimeRange>,
+        /// Cut list the file was made from (see --ranges-file of Cut)
+        #[arg(long)]
+        ranges_file: Option<String>,
+        /// Source the ranges refer to, when the cut list does not say; needed for
+        /// frame times, ranges that run past its end and telling where stream copied
+        /// ranges start
+        #[arg(long)]
+        source: Option<String>,
+        /// The file was re-encoded (a smart cut or a re-encoded export), so it holds
+        /// the ranges exactly rather than from their first video keyframes
+        #[arg(long)]
+        reencoded: bool,
+        /// Milliseconds the duration and the audio/video start may be off by
+        #[arg(long, value_name = "MS", default_value_t = DEFAULT_TOLERANCE_MS)]
+        tolerance: u64,
+        /// Print the report as JSON for other tools
+        #[arg(long)]
+        json: bool,
     },
 }
 
-fn cut_noisy_segments(input: &str, threshold:f64, output: &str) -> Result<(), String> {
-    // Vector?
-    let mut keep_intervals = find_noisy_intervals(input, threshold)?;
-    
-    if keep_intervals.is_empty() {
-        println!("No non noisy segments found.");
-        return Ok(());
-    } else {
-            // Remove zero-length and duplicate intervals
-        keep_intervals.retain(|(s, e)| e > s);
-        keep_intervals.dedup();
-        // Filter intervals based on minimum duration
-        let min_duration = 1; // seconds
-        keep_intervals.retain(|(s, e)| e - s >= min_duration);
+/// Running Verify on what a command writes.
+#[derive(Args)]
+struct VerifyArgs {
+    /// Verify the output once written and fail if it does not pass
+    #[arg(long)]
+    verify: bool,
+    /// Milliseconds the duration and the audio/video start may be off by
+    #[arg(long, value_name = "MS", requires = "verify", default_value_t = DEFAULT_TOLERANCE_MS)]
+    tolerance: u64,
+}
 
-        // Merge overlapping or adjacent intervals
-        keep_intervals.sort_by_key(|&(s, _)| s);
-        let mut merged_intervals: Vec<(u32, u32)> = Vec::new();
-        for (s, e) in keep_intervals {
-            if let Some((_, last_e)) = merged_intervals.last_mut() {
-                if s <= *last_e + 1 {
-                    // Merge intervals if overlapping or adjacent
-                    *last_e = (*last_e).max(e);
-                    continue;
-                }
-            }
-            merged_intervals.push((s, e));
+impl VerifyArgs {
+    // Verify `output` against the ranges it was cut from, if asked to.
+    fn check(&self, output: &str, expected: impl FnOnce() -> Result<Expected>) -> Result<()> {
+        if !self.verify {
+            return Ok(());
         }
-        // Print merged intervals for debugging
-        // println!("Merged intervals: {:?}", merged_intervals);
-        // Filter intervals based on minimum duration (e.g., 2 seconds)
-        let min_duration = 2;
-        // println!("Filtered intervals (>{}s): {:?}", min_duration, merged_intervals);
-        let mut segment_paths = Vec::new();
-        let mut index = 0;
-        let mut total_segment_duration = 0.0;
-        for (start, end) in merged_intervals {
-            let base = output.trim_end_matches(".mp4");
-            // Find the next keyframe after start
-            let keyframe_start = find_next_keyframe(input, start).unwrap_or(start);
-            // Only cut if the keyframe is strictly less than end
-            if keyframe_start >= end {
-                continue; // skip if no keyframe in interval
-            }
-            // Align cut to the exact keyframe timestamp
-            let segment_output = format!("{}_{}.mp4", base, index);
-            match cut_video(input, keyframe_start, end, &segment_output) {
-                Ok(()) => {
-                    // Filter out zero-length or <=2-frame video segments
-                    let mut video_packet_count = 0;
-                    let mut seg_duration = 0.0;
-                    if let Ok(mut seg_file) = format::input(&segment_output) {
-                        seg_duration = seg_file.duration() as f64 / ffmpeg::ffi::AV_TIME_BASE as f64;
-                        for (stream, _) in seg_file.packets() {
-                            if stream.parameters().medium() == ffmpeg::media::Type::Video {
-                                video_packet_count += 1;
-                                if video_packet_count > 2 {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    // println!("Segment {}: {}-{}s, duration: {:.2}s, video frames: {}", index, start, end, seg_duration, video_packet_count);
-                    total_segment_duration += seg_duration;
-                    if video_packet_count <= 2 {
-                        // println!("Deleting segment {}: zero-length or <=2-frame video", segment_output);
-                        let _ = std::fs::remove_file(&segment_output);
-                        continue;
-                    }
-                    segment_paths.push(segment_output.clone());
-                    index += 1;
-                    // println!("Segments created: {}", index);
-                }
-                Err(_e) => {
-                    // println!("Skipping segment {}: {}", index, e);
-                    let _ = std::fs::remove_file(&segment_output);
-                }
-            }
-        }
-        // println!("Total segment duration before join: {:.2}s", total_segment_duration);
+        println!("Verifying {}", output);
+        verify_output(output, Some(&expected()?), self.tolerance, false)
+    }
 
-        if index == 0 {
-            // println!("No segments to join. Output file will not be created.");
-            return Err("No noisy segments found to join.".to_string());
+    // --verify only makes sense for commands that write a file.
+    fn require_output(&self, output: Option<&str>) -> Result<()> {
+        if self.verify && output.is_none() {
+            return Err(Error::invalid("--verify needs an output to verify"));
         }
+        Ok(())
+    }
+}
 
-        //Recompile the output into a single video file
-        let mut output_file = format::output(output).map_err(|e| e.to_string())?;
-        let mut out_stream_indices: HashMap<usize, usize> = HashMap::new();
-
-        if index > 0 {
-            let first_segment_input = &segment_paths[0];
-            let first_segment_file = format::input(first_segment_input).map_err(|e| e.to_string())?;
+/// How RemoveSilence and Analyze detect silence.
+#[derive(Args)]
+struct SilenceArgs {
+    /// RMS level in dBFS (e.g. -40) that audio must exceed to be kept, or `auto[+DB]`
+    #[arg(allow_negative_numbers = true, required_unless_present = "auto")]
+    threshold: Option<Threshold>,
+    /// Derive the threshold from each recording's noise floor instead
+    #[arg(long, conflicts_with = "threshold")]
+    auto: bool,
+    /// How far above the noise floor the --auto threshold sits, in dB
+    #[arg(long, value_name = "DB", requires = "auto", default_value_t = DEFAULT_AUTO_MARGIN_DB)]
+    auto_margin: f64,
+    /// Level in dBFS the audio must fall below before the gate closes again
+    #[arg(long, value_name = "DB", allow_negative_numbers = true)]
+    close_threshold: Option<f64>,
+    /// Milliseconds the measured level takes to follow a rise
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    attack: u64,
+    /// Milliseconds the gate stays open after the level falls below the close threshold
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    hold: u64,
+    /// Milliseconds the measured level takes to follow a fall
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    release: u64,
+    /// Silences shorter than this many milliseconds are left in
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    min_silence: u64,
+    /// Sounds shorter than this many milliseconds are cut along with the silence
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_MIN_KEEP_MS)]
+    min_keep: u64,
+    /// Milliseconds of silence kept before each sound
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pad_before: u64,
+    /// Milliseconds of silence kept after each sound
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pad_after: u64,
+    /// Kept parts closer than this many milliseconds are joined
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_MERGE_GAP_MS)]
+    merge_gap: u64,
+    /// Audio streams that decide: `any` has sound, `all` have sound, one stream
+    /// index, or their `sum` (the streams mixed together) has sound
+    #[arg(long, value_name = "POLICY", default_value_t = StreamPolicy::Any)]
+    streams: StreamPolicy,
+    /// `energy` keeps any audio above the threshold, `vad` only what also sounds
+    /// like speech: steady tones, hum and noise are dropped, music may be kept
+    #[arg(long, value_name = "DETECTOR", default_value_t = Detection::Energy)]
+    detector: Detection,
+    /// Length of the windows the loudness is measured over, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_WINDOW_MS)]
+    window: u64,
+    /// Milliseconds between the starts of consecutive windows
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_HOP_MS)]
+    hop: u64,
+}
 
-            for (stream_index, stream) in first_segment_file.streams().enumerate() {
-                let codec_id = stream.parameters().id();
-                if codec_id == ffmpeg::codec::Id::None {
-                    println!("Skipping stream {}: codec is None", stream_index);
-                    continue;
-                }
-                let mut out_stream = output_file.add_stream(codec_id).map_err(|e| e.to_string())?;
-                out_stream.set_parameters(stream.parameters());
-                out_stream_indices.insert(stream_index, out_stream.index());
-            }
+impl SilenceArgs {
+    fn settings(self) -> SilenceSettings {
+        let threshold = match self.threshold {
+            Some(threshold) if !self.auto => threshold,
+            _ => Threshold::Auto {
+                margin_db: self.auto_margin,
+            },
+        };
+        SilenceSettings {
+            threshold,
+            close_threshold: self.close_threshold,
+            attack_ms: self.attack,
+            hold_ms: self.hold,
+            release_ms: self.release,
+            min_silence_ms: self.min_silence,
+            min_keep_ms: self.min_keep,
+            pad_before_ms: self.pad_before,
+            pad_after_ms: self.pad_after,
+            merge_gap_ms: self.merge_gap,
+            streams: self.streams,
+            detection: self.detector,
+            windowing: Windowing {
+                window_ms: self.window,
+                hop_ms: self.hop,
+            },
         }
+    }
+}
 
-        output_file.write_header().map_err(|e| e.to_string())?;
-
-        let mut last_pts: HashMap<usize, i64> = HashMap::new();
-        let mut last_dts: HashMap<usize, i64> = HashMap::new();
-        let mut first_packet_written: HashMap<usize, bool> = HashMap::new();
-
-        for segment_input in &segment_paths {
-            let mut segment_file = format::input(segment_input).map_err(|e| e.to_string())?;
-            // Collect all packets, sort by pts (for proper interleaving)
-            let mut packets: Vec<(usize, ffmpeg::Packet)> = Vec::new();
-            for (packet_stream, packet) in segment_file.packets() {
-                packets.push((packet_stream.index(), packet));
-            }
-            packets.sort_by_key(|(_, packet)| packet.pts().unwrap_or(0));
-
-            // Track first pts/dts per stream in this segment
-            let mut segment_first_pts: HashMap<usize, i64> = HashMap::new();
-            let mut segment_first_dts: HashMap<usize, i64> = HashMap::new();
-            for (stream_index, packet) in &packets {
-                if !segment_first_pts.contains_key(stream_index) {
-                    if let Some(pts) = packet.pts() {
-                        segment_first_pts.insert(*stream_index, pts);
-                    }
-                }
-                if !segment_first_dts.contains_key(stream_index) {
-                    if let Some(dts) = packet.dts() {
-                        segment_first_dts.insert(*stream_index, dts);
-                    }
-                }
-            }
-
-            // Calculate offset ONCE per segment per stream
-            let mut segment_pts_offset: HashMap<usize, i64> = HashMap::new();
-            let mut segment_dts_offset: HashMap<usize, i64> = HashMap::new();
-            for stream_index in segment_first_pts.keys() {
-                let out_stream_index = *out_stream_indices.get(stream_index).unwrap();
-                let last_pts_val = *last_pts.get(&out_stream_index).unwrap_or(&-1);
-                let last_dts_val = *last_dts.get(&out_stream_index).unwrap_or(&-1);
-                let seg_first_pts = *segment_first_pts.get(stream_index).unwrap_or(&0);
-                let seg_first_dts = *segment_first_dts.get(stream_index).unwrap_or(&0);
+// Print the file's streams and add it to the project as a source and, unless the
+// timeline already uses it, one full-length clip.
+fn load_clip(project_path: &str, filename: &str) -> Result<()> {
+    let duration = probe::probe_duration(filename)?;
+    println!("Duration: {}", duration);
+    println!("Streams:");
+    for stream in probe::streams(filename)? {
+        println!(
+            "  Stream {}: codec_type={:?}, codec_id={:?}",
+            stream.index, stream.medium, stream.codec
+        );
+    }
+    let mut project = Project::load(project_path)?;
+    project.add_source(filename, duration);
+    if !project.clips.iter().any(|clip| clip.source == filename) {
+        project.add_clip(filename, Timestamp::default(), duration);
+    }
+    project.print();
+    project.save(project_path)
+}
 
-                let pts_offset = last_pts_val + 1 - seg_first_pts;
-                let dts_offset = last_dts_val + 1 - seg_first_dts;
-                segment_pts_offset.insert(*stream_index, pts_offset);
-                segment_dts_offset.insert(*stream_index, dts_offset);
-                // println!("[JOIN] Stream {}: segment_pts_offset = {} dts_offset = {} (last_pts {} seg_first_pts {} last_dts {} seg_first_dts {})", out_stream_index, pts_offset, dts_offset, last_pts_val, seg_first_pts, last_dts_val, seg_first_dts);
-            }
+fn probe_file(input: &str, json: bool) -> Result<()> {
+    let report = probe::probe(input)?;
+    if json {
+        let text = serde_json::to_string_pretty(&report)
+            .map_err(|e| Error::invalid(format!("Failed to serialize probe report: {}", e)))?;
+        println!("{}", text);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
 
-            let mut seen_first_keyframe: HashMap<usize, bool> = HashMap::new();
-            for (stream_index, mut packet) in packets {
-                if let Some(&out_stream_index) = out_stream_indices.get(&stream_index) {
-                    let stream = segment_file.stream(stream_index).unwrap();
-                    // For video, skip until first keyframe
-                    if stream.parameters().medium() == ffmpeg::media::Type::Video {
-                        let seen = seen_first_keyframe.entry(stream_index).or_insert(false);
-                        if !*seen {
-                            if !packet.is_key() {
-                                continue;
-                            }
-                            *seen = true;
-                        }
-                    }
-                    // Apply offset ONCE per packet
-                    if let Some(offset) = segment_pts_offset.get(&stream_index) {
-                        if let Some(pts) = packet.pts() {
-                            let candidate = pts + offset;
-                            // println!("[JOIN] Stream {}: old PTS {} -> new PTS {} (offset {})", out_stream_index, pts, candidate, offset);
-                            packet.set_pts(Some(candidate));
-                            last_pts.insert(out_stream_index, candidate);
-                        }
-                    }
-                    if let Some(offset) = segment_dts_offset.get(&stream_index) {
-                        if let Some(dts) = packet.dts() {
-                            let candidate = dts + offset;
-                            // println!("[JOIN] Stream {}: old DTS {} -> new DTS {} (offset {})", out_stream_index, dts, candidate, offset);
-                            packet.set_dts(Some(candidate));
-                            last_dts.insert(out_stream_index, candidate);
-                        }
-                    }
-                    packet.set_stream(out_stream_index);
-                    packet.write_interleaved(&mut output_file).map_err(|e| e.to_string())?;
-                } else {
-                    println!("Skipping stream {} in segment {}: not present in output file", stream_index, segment_input);
-                }
-            }
+fn export_project(
+    project_path: &str,
+    output: Option<String>,
+    reencode: bool,
+    audio_fade: Option<u64>,
+    ranges_file: Option<String>,
+    source: Option<String>,
+    verify: &VerifyArgs,
+) -> Result<()> {
+    let mut project = Project::load(project_path)?;
+    if let Some(ranges_file) = ranges_file {
+        let list = cut::read_cut_list(&ranges_file)?;
+        let only_source = match &project.sources[..] {
+            [source] => Some(source.path.clone()),
+            _ => None,
+        };
+        let source = source.or(list.input).or(only_source).ok_or_else(|| {
+            Error::invalid(format!("{} does not say which source it cuts: pass --source", ranges_file))
+        })?;
+        let ranges = cut::resolve_ranges(&source, &list.ranges)?;
+        println!("Keeping {} ranges of {} from {}", ranges.len(), source, ranges_file);
+        project.apply_cut_list(&source, &ranges)?;
+    }
+    let export = project.export(output, reencode, audio_fade)?;
+    for analysed in &export.analyses {
+        print_analysis(&analysed.source, &analysed.analysis);
+    }
+    let how = if export.stream_copied { "Stream copied" } else { "Re-encoded" };
+    println!("{} {} clips -> {} ({})", how, export.clips.len(), export.output, export.written);
+    for clip in &export.clips {
+        println!("  {} [{} - {}]", clip.source, clip.start, clip.end);
+    }
+    project.save(project_path)?;
+    verify.check(&export.output, || {
+        let ranges: Vec<_> = export.clips.iter().map(|clip| (clip.start, clip.end)).collect();
+        if export.stream_copied {
+            Expected::stream_copied(&export.clips[0].source, &ranges)
+        } else {
+            Ok(Expected::reencoded(&ranges))
         }
-        output_file.write_trailer().map_err(|e| e.to_string())?;
+    })
+}
 
-        // Remove all segment files after joining
-        for seg_path in segment_paths {
-            let _ = std::fs::remove_file(seg_path);
-        }
-        Ok(())
+// Print how `input` was analysed for silence: the derived threshold and where it is noisy.
+fn print_analysis(input: &str, analysis: &Analysis) {
+    match &analysis.auto {
+        Some(auto) => println!(
+            "Auto threshold for {}: {:.1} dBFS (noise floor {:.1} dBFS, speech {:.1} dBFS)",
+            input, auto.threshold_db, auto.noise_floor_db, auto.speech_db
+        ),
+        None => println!("Threshold for {}: {:.1} dBFS", input, analysis.threshold_db),
     }
+    let formatted: Vec<String> = analysis.noisy.iter().map(|(s, e)| format!("{}-{}", s, e)).collect();
+    println!("Noisy intervals: [{}]", formatted.join(", "));
 }
 
-fn find_noisy_intervals(input: &str, threshold: f64) -> Result<Vec<(u32, u32)>, String> {
-    ffmpeg::init().map_err(|e| e.to_string())?;
-    let mut input_file = format::input(&input).map_err(|e| e.to_string())?;
-    let mut noisy_intervals = Vec::new();
-
-    let streams: Vec<_> = input_file.streams().enumerate()
-        .filter(|(_, stream)| stream.parameters().medium() == ffmpeg::media::Type::Audio)
-        .map(|(stream_index, stream)| (stream_index, stream.parameters().clone()))
-        .collect();
-
-    let mut current_start: Option<u32> = None;
-    let mut last_end: Option<u32> = None;
-
-    for (stream_index, codec_params) in streams {
-        let mut decoder = ffmpeg::codec::context::Context::from_parameters(codec_params)
-            .and_then(|ctx| ctx.decoder().audio())
-            .map_err(|e| e.to_string())?;
-
-        for (packet_stream, packet) in input_file.packets() {
-            if packet_stream.index() == stream_index {
-                decoder.send_packet(&packet).map_err(|e| e.to_string())?;
-                let mut frame = ffmpeg::frame::Audio::empty();
-                while decoder.receive_frame(&mut frame).is_ok() {
-                    let time_base = packet_stream.time_base();
-                    let start_pts = packet.pts().unwrap_or(0);
-                    let start_sec = start_pts as f64 * time_base.numerator() as f64 / time_base.denominator() as f64;
-                    let frame_duration_sec = frame.samples() as f64 / decoder.rate() as f64;
-                    let end_sec = start_sec + frame_duration_sec;
-                    let start_u32 = start_sec as u32;
-                    let end_u32 = end_sec as u32;
-
-                    if is_noisy(&frame, threshold) {
-                        if current_start.is_none() {
-                            current_start = Some(start_u32);
-                        }
-                        last_end = Some(end_u32);
-                    } else {
-                        if let (Some(s), Some(e)) = (current_start, last_end) {
-                            noisy_intervals.push((s, e));
-                            current_start = None;
-                            last_end = None;
-                        }
-                    }
-                }
-            }
-        }
+// All ranges given to Cut: the positional START END first, then --range, then the file.
+fn collect_ranges(
+    range: Option<(TimeSpec, TimeSpec)>,
+    mut ranges: Vec<TimeRange>,
+    ranges_file: Option<String>,
+) -> Result<Vec<TimeRange>> {
+    if let Some((start, end)) = range {
+        ranges.insert(0, TimeRange { start, end });
     }
-    // Push the last interval if the file ends while still in a noisy segment
-    if let (Some(s), Some(e)) = (current_start, last_end) {
-        noisy_intervals.push((s, e));
+    if let Some(path) = ranges_file {
+        ranges.extend(cut::read_ranges_file(&path)?);
     }
-    println!("Noisy intervals: {:?}", noisy_intervals);
-    for (s, e) in &noisy_intervals {
-        // println!("Interval: {} - {} ({}s)", s, e, e - s);
+    if ranges.is_empty() {
+        return Err(Error::invalid("No range given: pass START END, --range or --ranges-file"));
     }
-    Ok(noisy_intervals)
+    Ok(ranges)
 }
 
-fn is_noisy(audio_frame: &ffmpeg::frame::Audio, threshold: f64) -> bool {
-    match audio_frame.format().name() {
-        "s16" => {
-            let data = audio_frame.data(0);
-            let samples: Vec<i16> = data
-                .chunks_exact(2)
-                .map(|b| i16::from_le_bytes([b[0], b[1]]))
-                .collect();
-            let sum_squares: f64 = samples.iter().map(|&s| (s as f64).powi(2)).sum();
-            let rms = (sum_squares / samples.len() as f64).sqrt();
-            let db = 20.0 * (rms.abs().log10());
-            // println!("Frame dB: {}", db);
-            db > threshold
-        }
-        "fltp" => {
-            let nb_channels = audio_frame.channels();
-            let mut sum_squares = 0.0;
-            let mut count = 0;
-            for ch in 0..(nb_channels as usize) {
-                let data = audio_frame.data(ch);
-                let samples: Vec<f32> = data
-                    .chunks_exact(4)
-                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
-                    .collect();
-                sum_squares += samples.iter().map(|&s| (s as f64).powi(2)).sum::<f64>();
-                count += samples.len();
-            }
-            let rms = (sum_squares / count as f64).sqrt();
-            let db = 20.0 * (rms.abs().log10());
-            // println!("Frame dB: {}", db);
-            db > threshold
-        }
-        // Add more formats as needed
-        fmt => {
-            println!("Unsupported audio format: {}", fmt);
-            false
-        }
+// The ranges Cut keeps: those given, or with `delete` everything but them.
+fn kept_ranges(input: &str, ranges: &[TimeRange], delete: bool) -> Result<Vec<(Timestamp, Timestamp)>> {
+    let ranges = cut::resolve_ranges(input, ranges)?;
+    if !delete {
+        return Ok(ranges);
+    }
+    for (start, end) in &ranges {
+        println!("Deleting {} - {} from {}", start, end, input);
     }
+    cut::delete_ranges(input, &ranges)
 }
 
-fn cut_video(input: &str, start: u32, end: u32, output: &str) -> Result<(), String> {
-    ffmpeg::init().map_err(|e| e.to_string())?;
-    let mut input_file = format::input(&input).map_err(|e| e.to_string())?;
-    let mut start_ts = 0;
-    let mut stream_mapping = HashMap::new();
-    let mut ts_bounds: HashMap<usize, (i64, i64)> = HashMap::new();
-    // Create output context
-    let mut output_file = format::output(&output).map_err(|e| e.to_string())?;
-    // Copy over streams and build index mapping
-    for (idx, stream) in input_file.streams().enumerate() {
-        let time_base = stream.time_base();
-        start_ts = (start as i64 * time_base.denominator() as i64) / time_base.numerator() as i64;
-        let end_ts = (end as i64 * time_base.denominator() as i64) / time_base.numerator() as i64; 
-        ts_bounds.insert(idx, (start_ts, end_ts));
-        let codec_params = stream.parameters();
-        let mut out_stream = output_file.add_stream(codec_params.id()).map_err(|e| e.to_string())?;
-        out_stream.set_parameters(codec_params);
-        stream_mapping.insert(idx, out_stream.index());
-    }
-    output_file.write_header().map_err(|e| e.to_string())?;
-    input_file.seek(start_ts, ..start_ts).map_err(|e| e.to_string())?;
-    let mut packets_written = 0;
-    let mut video_packets_written = 0;
-    let mut seen_keyframe = false;
-    for (stream, mut packet) in input_file.packets() {
-        let stream_index = packet.stream();
-        if stream.parameters().medium() == ffmpeg::media::Type::Video {
-            if !seen_keyframe {
-                if packet.is_key() {
-                    seen_keyframe = true;
-                } else {
-                    continue;
-                }
+fn cut_ranges(
+    project_path: &str,
+    input: &str,
+    ranges: &[(Timestamp, Timestamp)],
+    output: Option<String>,
+    smart: bool,
+    audio_fade: Option<u64>,
+    verify: &VerifyArgs,
+) -> Result<()> {
+    match output {
+        Some(output) if smart => {
+            let [(start, end)] = ranges[..] else {
+                return Err(Error::invalid("--smart cuts a single range"));
+            };
+            println!("Cutting from {} ({} to {}) -> {}", input, start, end, output);
+            smart_render::smart_cut(input, start, end, &output)?;
+            verify.check(&output, || Ok(Expected::reencoded(&[(start, end)])))
+        }
+        Some(output) => {
+            for (start, end) in ranges {
+                println!("Cutting from {} ({} to {}) -> {}", input, start, end, output);
             }
-            video_packets_written += 1;
+            let written = cut::cut_ranges(input, ranges, &output, audio_fade.map(Timestamp::from_millis))?;
+            println!("Joined {} ranges into {} ({})", ranges.len(), output, written);
+            verify.check(&output, || Expected::stream_copied(input, ranges))
         }
-        if let Some((start_ts, end_ts)) = ts_bounds.get(&stream_index) {
-            if let Some(pts) = packet.pts() {
-                if pts < *start_ts || pts > *end_ts {
-                    continue;
-                }
+        None => {
+            let mut project = Project::load(project_path)?;
+            if project.source(input).is_none() {
+                project.add_source(input, probe::probe_duration(input)?);
             }
-            let out_index = *stream_mapping.get(&stream_index).expect("Stream mapping not found");
-            let out_stream_temp = output_file.stream(out_index).expect("Output stream not found");
-            // Print stream type
-            let medium = stream.parameters().medium();
-            // println!("Writing packet for stream {} ({:?})", stream_index, medium);
-            packet.set_stream(out_index);
-            packet.rescale_ts(stream.time_base(), out_stream_temp.time_base());
-            packet.write_interleaved(&mut output_file).map_err(|e| e.to_string())?;
-            packets_written += 1;
+            for (start, end) in ranges {
+                println!("Keeping {} {} - {} in {}", input, start, end, project_path);
+            }
+            project.trim(input, ranges);
+            project.print();
+            project.save(project_path)
         }
     }
-    // println!("Packets written: {}", packets_written);
-    if video_packets_written == 0 {
-        std::fs::remove_file(output).ok();
-        return Err("No video packets written for this segment (likely no keyframe in interval)".to_string());
+}
+
+// Analyse `input` once and write the result to each of `outputs`.
+fn analyze(input: &str, settings: &SilenceSettings, outputs: &[String], format: Option<ReportFormat>) -> Result<()> {
+    let formats = outputs
+        .iter()
+        .map(|output| {
+            format.or_else(|| ReportFormat::from_path(output)).ok_or_else(|| {
+                Error::invalid(format!("Cannot tell the format of {} from its extension: pass --format", output))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let analysis = silence::analyze(input, settings)?;
+    print_analysis(input, &analysis);
+    println!("Kept {} parts of {}", analysis.keep.len(), input);
+    for (output, format) in outputs.iter().zip(formats) {
+        println!("Writing {} analysis -> {}", format, output);
+        report::write(output, format, input, settings, &analysis)?;
     }
-    output_file.write_trailer().map_err(|e| e.to_string())?;
     Ok(())
 }
 
-fn find_next_keyframe(input: &str, start_sec: u32) -> Option<u32> {
-    ffmpeg::init().ok()?;
-    let mut input_file = format::input(input).ok()?;
-    // Collect video stream indices and time bases first
-    let video_streams: Vec<_> = input_file.streams()
-        .enumerate()
-        .filter(|(_, stream)| stream.parameters().medium() == ffmpeg::media::Type::Video)
-        .map(|(idx, stream)| (idx, stream.time_base()))
-        .collect();
+// Verify `output` and print the report, as JSON for `json`; fails if it did not pass.
+fn verify_output(output: &str, expected: Option<&Expected>, tolerance_ms: u64, json: bool) -> Result<()> {
+    let report = verify::verify(output, expected, Timestamp::from_millis(tolerance_ms))?;
+    if json {
+        let text = serde_json::to_string_pretty(&report)
+            .map_err(|e| Error::invalid(format!("Failed to serialize verify report: {}", e)))?;
+        println!("{}", text);
+    } else {
+        report.print();
+    }
+    report.ensure_passed()
+}
 
-    for (stream_index, time_base) in video_streams {
-        let start_ts = (start_sec as i64 * time_base.denominator() as i64) / time_base.numerator() as i64;
-        for (packet_stream, packet) in input_file.packets() {
-            if packet_stream.index() == stream_index {
-                if let Some(pts) = packet.pts() {
-                    if pts >= start_ts && packet.is_key() {
-                        // Convert pts back to seconds
-                        let sec = (pts * time_base.numerator() as i64 / time_base.denominator() as i64) as u32;
-                        return Some(sec);
-                    }
+// Verify `input`, against the given ranges when there are any: as a stream copy of
+// them when there is a source to index, unless `reencoded`. Without a source they are
+// taken as they are.
+fn verify_file(
+    input: &str,
+    mut ranges: Vec<TimeRange>,
+    ranges_file: Option<String>,
+    source: Option<String>,
+    reencoded: bool,
+    tolerance_ms: u64,
+    json: bool,
+) -> Result<()> {
+    let mut source = source;
+    if let Some(path) = ranges_file {
+        let list = cut::read_cut_list(&path)?;
+        source = source.or(list.input);
+        ranges.extend(list.ranges);
+    }
+    let expected = if ranges.is_empty() {
+        None
+    } else {
+        match &source {
+            Some(source) => {
+                let resolved = cut::resolve_ranges(source, &ranges)?;
+                if reencoded {
+                    Some(Expected::reencoded(&resolved))
+                } else {
+                    Some(Expected::stream_copied(source, &resolved)?)
                 }
             }
+            None => {
+                let resolved = ranges
+                    .iter()
+                    .map(|range| Ok((range.start.resolve(None)?, range.end.resolve(None)?)))
+                    .collect::<std::result::Result<Vec<_>, String>>()
+                    .map_err(Error::Invalid)?;
+                // With no source to index, a stream copy is taken at the ranges' full length
+                let resolved = if reencoded { resolved } else { segment::normalize_ranges(&resolved) };
+                Some(Expected::reencoded(&resolved))
+            }
         }
+    };
+    verify_output(input, expected.as_ref(), tolerance_ms, json)
+}
+
+// Report `err` and its causes on stderr and exit with the code for its kind.
+fn fail(context: &str, err: Error) -> ! {
+    eprintln!("{}: {}", context, err);
+    let mut source = std::error::Error::source(&err);
+    while let Some(cause) = source {
+        eprintln!("  caused by: {}", cause);
+        source = cause.source();
     }
-    None
+    process::exit(err.exit_code())
 }
 
 fn main() {
     let cli = Cli::parse();
+    keyframe::set_disk_cache(cli.keyframe_cache);
+    if cli.no_cache {
+        cache::set_dir(None);
+    } else if let Some(dir) = &cli.cache_dir {
+        cache::set_dir(Some(dir.clone()));
+    }
 
     match cli.command {
         Commands::Load { filename } => {
             println!("Loading video file: {}", filename);
-
-            ffmpeg::init().unwrap();
-
-            if let Ok(context) = ffmpeg::format::input(&filename) {
-                println!("Duration: {:?}", context.duration());
-                println!("Streams:");
-
-                for (idx, stream) in context.streams().enumerate() {
-                    let params = stream.parameters();
-                    println!(
-                        "  Stream {}: codec_type={:?}, codec_id={:?}",
-                        idx,
-                        params.medium(),
-                        params.id()
-                    );
-                }
-            } else {
-                println!("Failed to load video file: {}", filename);
-            }
-            // Here you would add the logic to load the video file
+            load_clip(&cli.project, &filename).unwrap_or_else(|err| fail("Failed to load video file", err));
+        }
+        Commands::Probe { input, json } => {
+            probe_file(&input, json).unwrap_or_else(|err| fail("Failed to probe file", err));
         }
-        Commands::Export { output } => {
-            println!("Exporting video to: {}", output);
-            // Here you would add the logic to export the video
+        Commands::Export {
+            output,
+            reencode,
+            audio_fade,
+            ranges_file,
+            source,
+            verify,
+        } => {
+            export_project(&cli.project, output, reencode, audio_fade, ranges_file, source, &verify)
+                .unwrap_or_else(|err| fail("Error exporting video", err));
         }
         Commands::Cut {
             input,
             start,
             end,
             output,
+            ranges,
+            ranges_file,
+            output_file,
+            smart,
+            audio_fade,
+            delete,
+            verify,
+        } => {
+            let output = output.or(output_file);
+            verify
+                .require_output(output.as_deref())
+                .and_then(|_| collect_ranges(start.zip(end), ranges, ranges_file))
+                .and_then(|ranges| kept_ranges(&input, &ranges, delete))
+                .and_then(|ranges| cut_ranges(&cli.project, &input, &ranges, output, smart, audio_fade, &verify))
+                .unwrap_or_else(|err| fail("Error cutting video", err));
+        }
+        Commands::RemoveSilence {
+            input,
+            silence: silence_args,
+            output,
+            output_file,
+            audio_fade,
+            verify,
         } => {
-            println!(
-                "Cutting from {} ({}s to {}s) -> {}",
-                input, start, end, output
-            );
-            cut_video(&input, start, end, &output)
-                .unwrap_or_else(|err| println!("Error cutting video: {}", err));
+            let settings = silence_args.settings();
+            let threshold = settings.threshold;
+            let output = output.or(output_file);
+            verify
+                .require_output(output.as_deref())
+                .unwrap_or_else(|err| fail("Error removing silence", err));
+            match output {
+                Some(output) => {
+                    println!(
+                        "Removing silence from {} with threshold {} -> {}",
+                        input, threshold, output
+                    );
+                    silence::cut_noisy_segments(&input, &settings, &output, audio_fade.map(Timestamp::from_millis))
+                        .and_then(|(analysis, written)| {
+                            print_analysis(&input, &analysis);
+                            println!("Joined {} ranges into {} ({})", analysis.keep.len(), output, written);
+                            verify.check(&output, || Expected::stream_copied(&input, &analysis.keep))
+                        })
+                        .unwrap_or_else(|err| fail("Error removing silence", err));
+                }
+                None => {
+                    println!("Removing silence from {} with threshold {} on export", input, threshold);
+                    Project::load(&cli.project)
+                        .and_then(|mut project| {
+                            project.set_silence(&input, settings)?;
+                            project.save(&cli.project)
+                        })
+                        .unwrap_or_else(|err| fail("Error removing silence", err));
+                }
+            }
+        }
+        Commands::Analyze {
+            input,
+            silence: silence_args,
+            outputs,
+            format,
+        } => {
+            let settings = silence_args.settings();
+            println!("Analysing {} with threshold {}", input, settings.threshold);
+            analyze(&input, &settings, &outputs, format).unwrap_or_else(|err| fail("Error analysing audio", err));
         }
-        Commands::RemoveSilence { input, threshold, output } => {
-            println!(
-                "Removing silence from {} with threshold {} -> {}",
-                input, threshold, output
-            );
-            cut_noisy_segments(&input, threshold, &output)
-                .unwrap_or_else(|err| println!("Error removing silence: {}", err));
+        Commands::Verify {
+            input,
+            ranges,
+            ranges_file,
+            source,
+            reencoded,
+            tolerance,
+            json,
+        } => {
+            verify_file(&input, ranges, ranges_file, source, reencoded, tolerance, json)
+                .unwrap_or_else(|err| fail("Verification failed", err));
         }
     }
 }
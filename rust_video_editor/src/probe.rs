@@ -0,0 +1,320 @@
+//! Basic facts about a media file.
+
+use crate::cache;
+use crate::error::{self, Error, Result};
+use crate::keyframe::{self, StreamKeyframes};
+use crate::timestamp::Timestamp;
+use ffmpeg::codec::Profile;
+use ffmpeg::{codec, format, media, ChannelLayout, Rational};
+use ffmpeg_next as ffmpeg;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// One stream of a media file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub index: usize,
+    pub medium: media::Type,
+    pub codec: codec::Id,
+}
+
+// Bump whenever a change here changes what is reported, so cached probes are not
+// reused.
+const PROBE_VERSION: u32 = 1;
+
+/// Container duration of `filename`. Fails when the container does not know it.
+pub fn probe_duration(filename: &str) -> Result<Timestamp> {
+    // Only the header is read, which is cheaper than hashing the file
+    cache::cached_by_stamp(filename, "duration", PROBE_VERSION, &(), || {
+        error::init()?;
+        let context = format::input(&filename).map_err(|e| Error::open(filename, e))?;
+        if context.duration() <= 0 {
+            return Err(Error::unsupported(filename, None, "no known duration"));
+        }
+        Ok(Timestamp::from_micros(context.duration()))
+    })
+}
+
+/// The streams of `filename`, in container order.
+pub fn streams(filename: &str) -> Result<Vec<StreamInfo>> {
+    error::init()?;
+    let context = format::input(&filename).map_err(|e| Error::open(filename, e))?;
+    Ok(context
+        .streams()
+        .map(|stream| StreamInfo {
+            index: stream.index(),
+            medium: stream.parameters().medium(),
+            codec: stream.parameters().id(),
+        })
+        .collect())
+}
+
+/// Frame rate of the best video stream, used to resolve `frame:N` times.
+pub fn video_frame_rate(input: &str) -> Result<Option<Rational>> {
+    error::init()?;
+    let input_file = format::input(&input).map_err(|e| Error::open(input, e))?;
+    let rate = input_file.streams().best(ffmpeg::media::Type::Video).map(|stream| {
+        let avg = stream.avg_frame_rate();
+        if avg.numerator() > 0 { avg } else { stream.rate() }
+    });
+    Ok(rate)
+}
+
+/// Everything `Probe` reports about a file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProbeReport {
+    pub path: String,
+    /// Short name of the container format, e.g. `mov,mp4,m4a,3gp,3g2,mj2`
+    pub format: String,
+    pub format_description: String,
+    pub duration: Option<Timestamp>,
+    /// Total bit rate in bits per second
+    pub bit_rate: Option<i64>,
+    pub tags: BTreeMap<String, String>,
+    pub streams: Vec<StreamReport>,
+}
+
+/// One stream of a `ProbeReport`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamReport {
+    pub index: usize,
+    /// `video`, `audio`, `subtitle`, `data` or `attachment`
+    pub medium: String,
+    pub codec: String,
+    pub profile: Option<String>,
+    /// As `numerator/denominator`
+    pub time_base: String,
+    pub start_time: Option<Timestamp>,
+    pub duration: Option<Timestamp>,
+    pub frames: Option<i64>,
+    pub language: Option<String>,
+    /// Flags such as `default` or `forced`
+    pub disposition: Vec<String>,
+    pub tags: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyframes: Option<KeyframeStats>,
+}
+
+/// The picture of a video stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VideoReport {
+    pub width: u32,
+    pub height: u32,
+    pub pixel_format: Option<String>,
+    /// Average frame rate as `numerator/denominator`
+    pub frame_rate: Option<String>,
+}
+
+/// The sound of an audio stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioReport {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub channel_layout: Option<String>,
+    pub sample_format: String,
+}
+
+/// Spacing of the keyframes of a video stream, in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyframeStats {
+    pub count: usize,
+    pub min_interval: Option<f64>,
+    pub mean_interval: Option<f64>,
+    pub max_interval: Option<f64>,
+}
+
+/// Describe `input`: its container, every stream and the keyframe spacing of its
+/// video streams (which takes a pass over the packets unless the keyframe index is
+/// cached).
+pub fn probe(input: &str) -> Result<ProbeReport> {
+    let mut report = cache::cached_by_stamp(input, "probe", PROBE_VERSION, &(), || probe_uncached(input))?;
+    // Cached under another spelling of the same path
+    report.path = input.to_string();
+    Ok(report)
+}
+
+fn probe_uncached(input: &str) -> Result<ProbeReport> {
+    error::init()?;
+    let context = format::input(&input).map_err(|e| Error::open(input, e))?;
+    // Indexing reads every packet, for nothing without video
+    let index = match context.streams().best(media::Type::Video) {
+        Some(_) => Some(keyframe::index(input)?),
+        None => None,
+    };
+    let streams = context
+        .streams()
+        .map(|stream| {
+            let parameters = stream.parameters();
+            let medium = parameters.medium();
+            let time_base = stream.time_base();
+            let decoder = || codec::context::Context::from_parameters(stream.parameters()).ok();
+            let video = (medium == media::Type::Video)
+                .then(|| decoder()?.decoder().video().ok())
+                .flatten();
+            let audio = (medium == media::Type::Audio)
+                .then(|| decoder()?.decoder().audio().ok())
+                .flatten();
+            let profile = video
+                .as_ref()
+                .map(|video| video.profile())
+                .or_else(|| audio.as_ref().map(|audio| audio.profile()))
+                .filter(|profile| *profile != Profile::Unknown)
+                .map(|profile| format!("{:?}", profile));
+            let tags = tags(stream.metadata().iter());
+            StreamReport {
+                index: stream.index(),
+                medium: format!("{:?}", medium).to_ascii_lowercase(),
+                codec: parameters.id().name().to_string(),
+                profile,
+                time_base: rational(time_base),
+                start_time: known(stream.start_time()).map(|pts| Timestamp::from_pts(pts, time_base)),
+                duration: known(stream.duration())
+                    .filter(|&duration| duration > 0)
+                    .map(|duration| Timestamp::from_pts(duration, time_base)),
+                frames: Some(stream.frames()).filter(|&frames| frames > 0),
+                language: tags.get("language").cloned(),
+                disposition: stream
+                    .disposition()
+                    .iter_names()
+                    .map(|(name, _)| name.to_ascii_lowercase())
+                    .collect(),
+                tags,
+                video: video.map(|video| VideoReport {
+                    width: video.width(),
+                    height: video.height(),
+                    pixel_format: video.format().descriptor().map(|descriptor| descriptor.name().to_string()),
+                    frame_rate: Some(stream.avg_frame_rate())
+                        .filter(|rate| rate.numerator() > 0)
+                        .map(rational),
+                }),
+                audio: audio.map(|audio| AudioReport {
+                    sample_rate: audio.rate(),
+                    channels: audio.channels(),
+                    channel_layout: layout_name(audio.channel_layout()).map(str::to_string),
+                    sample_format: audio.format().name().to_string(),
+                }),
+                keyframes: index.as_ref().and_then(|index| index.stream(stream.index())).map(keyframe_stats),
+            }
+        })
+        .collect();
+    Ok(ProbeReport {
+        path: input.to_string(),
+        format: context.format().name().to_string(),
+        format_description: context.format().description().to_string(),
+        duration: Some(context.duration())
+            .filter(|&duration| duration > 0)
+            .map(Timestamp::from_micros),
+        bit_rate: Some(context.bit_rate()).filter(|&rate| rate > 0),
+        tags: tags(context.metadata().iter()),
+        streams,
+    })
+}
+
+impl ProbeReport {
+    /// Print the report for people, one stream after another.
+    pub fn print(&self) {
+        let duration = self.duration.map_or("unknown".to_string(), |duration| duration.to_string());
+        println!("{}: {} ({}), duration {}", self.path, self.format, self.format_description, duration);
+        if let Some(bit_rate) = self.bit_rate {
+            println!("  Bit rate: {} kb/s", bit_rate / 1000);
+        }
+        print_tags("  ", &self.tags);
+        for stream in &self.streams {
+            let profile = stream.profile.as_ref().map_or(String::new(), |profile| format!(" ({})", profile));
+            let language = stream.language.as_ref().map_or(String::new(), |language| format!(" [{}]", language));
+            println!("  Stream {}{}: {} {}{}", stream.index, language, stream.medium, stream.codec, profile);
+            if let Some(video) = &stream.video {
+                let pixel_format = video.pixel_format.as_deref().unwrap_or("unknown pixel format");
+                let frame_rate = video.frame_rate.as_deref().unwrap_or("unknown");
+                println!(
+                    "    {}x{}, {}, {} fps",
+                    video.width, video.height, pixel_format, frame_rate
+                );
+            }
+            if let Some(audio) = &stream.audio {
+                let layout = audio.channel_layout.as_deref().unwrap_or("unknown layout");
+                println!(
+                    "    {} Hz, {} channels ({}), {}",
+                    audio.sample_rate, audio.channels, layout, audio.sample_format
+                );
+            }
+            let start = stream.start_time.map_or("unknown".to_string(), |start| start.to_string());
+            let duration = stream.duration.map_or("unknown".to_string(), |duration| duration.to_string());
+            println!("    Time base {}, start {}, duration {}", stream.time_base, start, duration);
+            if let Some(frames) = stream.frames {
+                println!("    {} frames", frames);
+            }
+            if !stream.disposition.is_empty() {
+                println!("    Disposition: {}", stream.disposition.join(", "));
+            }
+            if let Some(keyframes) = &stream.keyframes {
+                match (keyframes.min_interval, keyframes.mean_interval, keyframes.max_interval) {
+                    (Some(min), Some(mean), Some(max)) => println!(
+                        "    {} keyframes, every {:.3}s on average ({:.3}s - {:.3}s)",
+                        keyframes.count, mean, min, max
+                    ),
+                    _ => println!("    {} keyframes", keyframes.count),
+                }
+            }
+            print_tags("    ", &stream.tags);
+        }
+    }
+}
+
+fn keyframe_stats(keyframes: &StreamKeyframes) -> KeyframeStats {
+    let time_base = keyframes.time_base();
+    let times: Vec<Timestamp> = keyframes
+        .keyframes
+        .iter()
+        .map(|keyframe| Timestamp::from_pts(keyframe.pts, time_base))
+        .collect();
+    let intervals: Vec<f64> = times.windows(2).map(|pair| (pair[1] - pair[0]).as_secs_f64()).collect();
+    KeyframeStats {
+        count: times.len(),
+        min_interval: intervals.iter().copied().reduce(f64::min),
+        mean_interval: (!intervals.is_empty()).then(|| intervals.iter().sum::<f64>() / intervals.len() as f64),
+        max_interval: intervals.iter().copied().reduce(f64::max),
+    }
+}
+
+fn print_tags(indent: &str, tags: &BTreeMap<String, String>) {
+    for (key, value) in tags {
+        println!("{}{}: {}", indent, key, value);
+    }
+}
+
+fn tags<'a>(entries: impl Iterator<Item = (&'a str, &'a str)>) -> BTreeMap<String, String> {
+    entries.map(|(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
+fn rational(rate: Rational) -> String {
+    format!("{}/{}", rate.numerator(), rate.denominator())
+}
+
+// FFmpeg marks unknown stream times with AV_NOPTS_VALUE.
+fn known(ts: i64) -> Option<i64> {
+    Some(ts).filter(|&ts| ts != ffmpeg::ffi::AV_NOPTS_VALUE)
+}
+
+// FFmpeg's name for the common channel layouts.
+fn layout_name(layout: ChannelLayout) -> Option<&'static str> {
+    [
+        (ChannelLayout::MONO, "mono"),
+        (ChannelLayout::STEREO, "stereo"),
+        (ChannelLayout::_2POINT1, "2.1"),
+        (ChannelLayout::SURROUND, "3.0"),
+        (ChannelLayout::QUAD, "quad"),
+        (ChannelLayout::_5POINT0, "5.0(side)"),
+        (ChannelLayout::_5POINT1, "5.1(side)"),
+        (ChannelLayout::_5POINT0_BACK, "5.0"),
+        (ChannelLayout::_5POINT1_BACK, "5.1"),
+        (ChannelLayout::_7POINT1, "7.1"),
+    ]
+    .into_iter()
+    .find(|(known, _)| *known == layout)
+    .map(|(_, name)| name)
+}
@@ -0,0 +1,467 @@
+use crate::error::{Error, Result};
+use crate::render;
+use crate::silence::{self, Analysis, SilenceSettings};
+use crate::timeline::Clip;
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Bump when the file layout changes and add the upgrade step to `Project::migrate`.
+pub const PROJECT_VERSION: u32 = 1;
+
+/// The edit decisions shared by `Load`, `Cut`, `RemoveSilence` and `Export`. It is saved
+/// as JSON, or TOML when the path ends in `.toml`, so it can be kept in git and
+/// rendered again later with the same result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Project {
+    /// Files written before versioning (the old session files) have no version: 0
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    /// The timeline, in order. Each clip's start/end are its cut range in the source.
+    #[serde(default)]
+    pub clips: Vec<Clip>,
+    #[serde(default)]
+    pub export: ExportSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Source {
+    pub path: String,
+    pub duration: Timestamp,
+    /// Set by `RemoveSilence` without an output: Export drops the silent parts of this
+    /// source's clips.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub silence: Option<SilenceSettings>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(default)]
+    pub reencode: bool,
+    /// Milliseconds the audio fades over at the joins of a stream-copied export, which
+    /// re-encodes the audio
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_fade_ms: Option<u64>,
+}
+
+/// What `Project::export` did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub output: String,
+    /// The clips rendered, i.e. after silence removal
+    pub clips: Vec<Clip>,
+    /// The silence analysis of each source that has silence removal set
+    pub analyses: Vec<SourceAnalysis>,
+    /// Whether the clips were stream copied rather than re-encoded
+    pub stream_copied: bool,
+    /// The length written
+    pub written: Timestamp,
+}
+
+/// The silence analysis of one source of an export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceAnalysis {
+    pub source: String,
+    pub analysis: Analysis,
+}
+
+impl Default for Project {
+    fn default() -> Self {
+        Project {
+            version: PROJECT_VERSION,
+            sources: Vec::new(),
+            clips: Vec::new(),
+            export: ExportSettings::default(),
+        }
+    }
+}
+
+fn is_toml(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("toml"))
+}
+
+impl Project {
+    /// A missing project file is an empty project.
+    pub fn load(path: &str) -> Result<Project> {
+        if !Path::new(path).exists() {
+            return Ok(Project::default());
+        }
+        let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let project: Project = if is_toml(path) {
+            toml::from_str(&text).map_err(|e| Error::project(path, e.to_string()))?
+        } else {
+            serde_json::from_str(&text).map_err(|e| Error::project(path, e.to_string()))?
+        };
+        project.migrate(path)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let text = if is_toml(path) {
+            toml::to_string_pretty(self).map_err(|e| Error::project(path, e.to_string()))?
+        } else {
+            serde_json::to_string_pretty(self).map_err(|e| Error::project(path, e.to_string()))? + "\n"
+        };
+        fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+
+    fn migrate(mut self, path: &str) -> Result<Project> {
+        if self.version > PROJECT_VERSION {
+            return Err(Error::project(
+                path,
+                format!("version {} is newer than this editor supports ({})", self.version, PROJECT_VERSION),
+            ));
+        }
+        // Version 0 session files only had clips: register their sources, as long as
+        // the furthest any clip reaches into them
+        if self.version == 0 {
+            for clip in &self.clips {
+                match self.sources.iter_mut().find(|source| source.path == clip.source) {
+                    Some(source) => source.duration = source.duration.max(clip.end),
+                    None => self.sources.push(Source {
+                        path: clip.source.clone(),
+                        duration: clip.end,
+                        silence: None,
+                    }),
+                }
+            }
+        }
+        self.version = PROJECT_VERSION;
+        Ok(self)
+    }
+
+    pub fn source(&self, path: &str) -> Option<&Source> {
+        self.sources.iter().find(|source| source.path == path)
+    }
+
+    /// Add `path` to the sources, or refresh its duration if it is already there.
+    pub fn add_source(&mut self, path: &str, duration: Timestamp) {
+        match self.sources.iter_mut().find(|source| source.path == path) {
+            Some(source) => source.duration = duration,
+            None => self.sources.push(Source {
+                path: path.to_string(),
+                duration,
+                silence: None,
+            }),
+        }
+    }
+
+    pub fn set_silence(&mut self, path: &str, silence: SilenceSettings) -> Result<()> {
+        let source = self
+            .sources
+            .iter_mut()
+            .find(|source| source.path == path)
+            .ok_or_else(|| Error::invalid(format!("{} is not a source of this project, Load it first", path)))?;
+        source.silence = Some(silence);
+        Ok(())
+    }
+
+    /// Cut the clips from `path` down to `ranges` (see `trim`) in place of any silence
+    /// removal set on it, since a reviewed cut list replaces the detection it came from.
+    pub fn apply_cut_list(&mut self, path: &str, ranges: &[(Timestamp, Timestamp)]) -> Result<()> {
+        let source = self
+            .sources
+            .iter_mut()
+            .find(|source| source.path == path)
+            .ok_or_else(|| Error::invalid(format!("{} is not a source of this project, Load it first", path)))?;
+        source.silence = None;
+        self.trim(path, ranges);
+        Ok(())
+    }
+
+    pub fn add_clip(&mut self, source: &str, start: Timestamp, end: Timestamp) {
+        self.clips.push(Clip {
+            source: source.to_string(),
+            start,
+            end,
+        });
+    }
+
+    /// Cut every clip taken from `source` down to its parts inside `ranges`, in range
+    /// order. A source that is not on the timeline yet gets one clip per range.
+    pub fn trim(&mut self, source: &str, ranges: &[(Timestamp, Timestamp)]) {
+        if !self.clips.iter().any(|clip| clip.source == source) {
+            for &(start, end) in ranges {
+                self.add_clip(source, start, end);
+            }
+            return;
+        }
+        let mut clips = Vec::new();
+        for clip in self.clips.drain(..) {
+            if clip.source != source {
+                clips.push(clip);
+                continue;
+            }
+            for &(start, end) in ranges {
+                let (start, end) = (clip.start.max(start), clip.end.min(end));
+                if end > start {
+                    clips.push(Clip {
+                        source: clip.source.clone(),
+                        start,
+                        end,
+                    });
+                }
+            }
+        }
+        self.clips = clips;
+    }
+
+    /// The clips with silence removal applied to sources that have it set, i.e. what
+    /// an export renders.
+    pub fn timeline_clips(&self) -> Result<Vec<Clip>> {
+        self.analysed_clips().map(|(clips, _)| clips)
+    }
+
+    // The timeline clips, with the analysis of each source that has silence removal set.
+    fn analysed_clips(&self) -> Result<(Vec<Clip>, Vec<SourceAnalysis>)> {
+        let mut analyses: Vec<SourceAnalysis> = Vec::new();
+        let mut clips = Vec::new();
+        for clip in &self.clips {
+            let Some(silence) = self.source(&clip.source).and_then(|source| source.silence.as_ref()) else {
+                clips.push(clip.clone());
+                continue;
+            };
+            let index = match analyses.iter().position(|analysed| analysed.source == clip.source) {
+                Some(index) => index,
+                None => {
+                    analyses.push(SourceAnalysis {
+                        source: clip.source.clone(),
+                        analysis: silence::analyze(&clip.source, silence)?,
+                    });
+                    analyses.len() - 1
+                }
+            };
+            for &(start, end) in &analyses[index].analysis.keep {
+                let (start, end) = (start.max(clip.start), end.min(clip.end));
+                if end > start {
+                    clips.push(Clip {
+                        source: clip.source.clone(),
+                        start,
+                        end,
+                    });
+                }
+            }
+        }
+        Ok((clips, analyses))
+    }
+
+    /// Render the timeline to `output`, falling back to the saved export settings, and
+    /// remember the settings used for the next export.
+    pub fn export(&mut self, output: Option<String>, reencode: bool, audio_fade_ms: Option<u64>) -> Result<Export> {
+        let output = output
+            .or(self.export.output.clone())
+            .ok_or_else(|| Error::invalid("No output given and none saved in the project"))?;
+        let reencode = reencode || self.export.reencode;
+        let audio_fade_ms = audio_fade_ms.or(self.export.audio_fade_ms);
+        let (clips, analyses) = self.analysed_clips()?;
+        let written = render::render(&clips, &output, reencode, audio_fade_ms.map(Timestamp::from_millis))?;
+        self.export.output = Some(output.clone());
+        self.export.reencode = reencode;
+        self.export.audio_fade_ms = audio_fade_ms;
+        Ok(Export {
+            output,
+            stream_copied: render::stream_copies(&clips, reencode),
+            clips,
+            analyses,
+            written,
+        })
+    }
+
+    pub fn duration(&self) -> Timestamp {
+        self.clips.iter().map(Clip::duration).fold(Timestamp::default(), |a, b| a + b)
+    }
+
+    pub fn print(&self) {
+        println!("Timeline ({} clips, {}):", self.clips.len(), self.duration());
+        for (index, clip) in self.clips.iter().enumerate() {
+            println!("  {}: {} [{} - {}]", index, clip.source, clip.start, clip.end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::silence::Threshold;
+
+    // Ranges in whole seconds
+    fn spans(ranges: &[(i64, i64)]) -> Vec<(Timestamp, Timestamp)> {
+        ranges.iter().map(|&(s, e)| (Timestamp::from_secs(s), Timestamp::from_secs(e))).collect()
+    }
+
+    fn clip(source: &str, start: i64, end: i64) -> Clip {
+        Clip {
+            source: source.to_string(),
+            start: Timestamp::from_secs(start),
+            end: Timestamp::from_secs(end),
+        }
+    }
+
+    fn timeline(clips: Vec<Clip>) -> Project {
+        Project {
+            clips,
+            ..Project::default()
+        }
+    }
+
+    // A path in the temp directory that is removed again when dropped
+    struct Scratch(String);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let path = std::env::temp_dir().join(format!("project-{}-{}", std::process::id(), name));
+            Scratch(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn sample() -> Project {
+        let mut project = Project::default();
+        project.add_source("a.mp4", Timestamp::from_secs(60));
+        project.add_source("b.mkv", Timestamp::from_millis(12_345));
+        let mut silence = SilenceSettings::new(Threshold::Auto { margin_db: 8.0 });
+        silence.pad_before_ms = 100;
+        project.set_silence("b.mkv", silence).unwrap();
+        project.clips = vec![clip("a.mp4", 5, 20), clip("b.mkv", 0, 12), clip("a.mp4", 40, 60)];
+        project.export = ExportSettings {
+            output: Some("out.mp4".to_string()),
+            reencode: true,
+            audio_fade_ms: Some(15),
+        };
+        project
+    }
+
+    #[test]
+    fn a_project_reads_back_as_saved_in_json_and_toml() {
+        for name in ["round-trip.json", "round-trip.toml", "round-trip.TOML"] {
+            let path = Scratch::new(name);
+            sample().save(&path.0).unwrap();
+            assert_eq!(Project::load(&path.0).unwrap(), sample(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn the_format_follows_the_extension() {
+        let (json, toml) = (Scratch::new("format.json"), Scratch::new("format.toml"));
+        sample().save(&json.0).unwrap();
+        sample().save(&toml.0).unwrap();
+        assert!(fs::read_to_string(&json.0).unwrap().starts_with('{'));
+        assert!(fs::read_to_string(&toml.0).unwrap().starts_with("version = 1"));
+    }
+
+    #[test]
+    fn a_missing_project_is_empty() {
+        let path = Scratch::new("missing.json");
+        assert_eq!(Project::load(&path.0).unwrap(), Project::default());
+    }
+
+    #[test]
+    fn an_unreadable_project_names_the_file() {
+        let path = Scratch::new("broken.toml");
+        fs::write(&path.0, "clips = 3").unwrap();
+        let err = Project::load(&path.0).unwrap_err();
+        assert!(matches!(err, Error::Project { .. }), "{:?}", err);
+        assert!(err.to_string().contains(&path.0), "{}", err);
+    }
+
+    // A session file as written before projects were versioned: only the clips
+    const VERSION_0: &str = r#"{
+  "clips": [
+    {
+      "source": "a.mp4",
+      "start": "00:00:05.000",
+      "end": "00:00:20.000"
+    },
+    {
+      "source": "b.mkv",
+      "start": "00:00:00.000",
+      "end": "00:00:12.345"
+    },
+    {
+      "source": "a.mp4",
+      "start": "00:00:40.000",
+      "end": "00:01:00.000"
+    }
+  ]
+}"#;
+
+    #[test]
+    fn a_version_0_session_is_migrated() {
+        let path = Scratch::new("session.json");
+        fs::write(&path.0, VERSION_0).unwrap();
+        let project = Project::load(&path.0).unwrap();
+        assert_eq!(project.version, PROJECT_VERSION);
+        let b = Clip {
+            end: Timestamp::from_millis(12_345),
+            ..clip("b.mkv", 0, 0)
+        };
+        assert_eq!(project.clips, vec![clip("a.mp4", 5, 20), b, clip("a.mp4", 40, 60)]);
+        let sources: Vec<_> = project.sources.iter().map(|source| (source.path.as_str(), source.duration)).collect();
+        assert_eq!(sources, vec![("a.mp4", Timestamp::from_secs(60)), ("b.mkv", Timestamp::from_millis(12_345))]);
+        assert!(project.sources.iter().all(|source| source.silence.is_none()));
+        assert_eq!(project.export, ExportSettings::default());
+    }
+
+    #[test]
+    fn a_newer_project_is_refused() {
+        let path = Scratch::new("newer.json");
+        fs::write(&path.0, format!("{{\"version\": {}}}", PROJECT_VERSION + 1)).unwrap();
+        let err = Project::load(&path.0).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{}", err);
+    }
+
+    #[test]
+    fn trimming_a_new_source_adds_a_clip_per_range() {
+        let mut project = Project::default();
+        project.add_clip("a.mp4", Timestamp::from_secs(0), Timestamp::from_secs(10));
+        project.trim("b.mp4", &spans(&[(1, 2), (5, 8)]));
+        assert_eq!(project.clips, vec![clip("a.mp4", 0, 10), clip("b.mp4", 1, 2), clip("b.mp4", 5, 8)]);
+    }
+
+    #[test]
+    fn trimming_cuts_each_clip_of_the_source_to_the_ranges() {
+        let mut project = timeline(vec![clip("a.mp4", 0, 10), clip("b.mp4", 0, 30), clip("a.mp4", 20, 30)]);
+        project.trim("a.mp4", &spans(&[(25, 40), (5, 22), (12, 18)]));
+        // Each clip keeps its place, split in range order; the range between the
+        // clips (12-18) adds nothing
+        let expected = vec![
+            clip("a.mp4", 5, 10),
+            clip("b.mp4", 0, 30),
+            clip("a.mp4", 25, 30),
+            clip("a.mp4", 20, 22),
+        ];
+        assert_eq!(project.clips, expected);
+    }
+
+    #[test]
+    fn trimming_to_nothing_drops_the_clips() {
+        let mut project = timeline(vec![clip("a.mp4", 0, 10), clip("b.mp4", 0, 30)]);
+        project.trim("a.mp4", &spans(&[(10, 20)]));
+        assert_eq!(project.clips, vec![clip("b.mp4", 0, 30)]);
+    }
+
+    #[test]
+    fn a_cut_list_replaces_silence_removal() {
+        let mut project = sample();
+        project.apply_cut_list("b.mkv", &spans(&[(2, 4), (6, 9)])).unwrap();
+        assert_eq!(project.source("b.mkv").unwrap().silence, None);
+        let expected = vec![clip("a.mp4", 5, 20), clip("b.mkv", 2, 4), clip("b.mkv", 6, 9), clip("a.mp4", 40, 60)];
+        assert_eq!(project.clips, expected);
+    }
+
+    #[test]
+    fn a_cut_list_needs_a_loaded_source() {
+        let mut project = sample();
+        assert!(project.apply_cut_list("c.mp4", &spans(&[(0, 1)])).is_err());
+        assert_eq!(project, sample());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::timestamp::Timestamp;
+use ffmpeg::{Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use std::collections::HashMap;
+
+/// How one segment's source times map onto the output: `begin` in the source
+/// lands on `offset` in the output, for every stream alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mapping {
+    pub begin: Timestamp,
+    pub offset: Timestamp,
+}
+
+impl Mapping {
+    // Amount to add to a timestamp in `time_base`. Rounded once from the common
+    // microsecond offset, so streams never drift apart across joins.
+    fn shift(&self, time_base: Rational) -> i64 {
+        (self.offset - self.begin).to_pts(time_base)
+    }
+}
+
+/// Places joined segments one after another on the output timeline. Each segment
+/// starts where the previous one really ended (the end of its last reference-stream
+/// packet, duration included), and all streams of a segment share one mapping, so
+/// audio and video stay in sync no matter how many joins there are.
+#[derive(Debug, Default)]
+pub(crate) struct Rebaser {
+    // Stream whose packets define where a segment ends; None means every stream
+    reference: Option<usize>,
+    // Where the next segment starts on the output timeline
+    next_offset: Timestamp,
+    // Per stream: last output dts, in that stream's time base
+    last_dts: HashMap<usize, i64>,
+    // Per stream: last known packet duration, for packets that carry none
+    durations: HashMap<usize, i64>,
+}
+
+impl Rebaser {
+    /// A rebaser whose segments end with the last packet of stream `reference`, or of
+    /// any stream when None.
+    pub fn new(reference: Option<usize>) -> Self {
+        Rebaser {
+            reference,
+            ..Rebaser::default()
+        }
+    }
+
+    /// Begin a segment whose first kept source instant is `begin`.
+    pub fn start_segment(&mut self, begin: Timestamp) -> Mapping {
+        Mapping {
+            begin,
+            offset: self.next_offset,
+        }
+    }
+
+    /// End of everything placed so far on the output timeline.
+    pub fn position(&self) -> Timestamp {
+        self.next_offset
+    }
+
+    /// Map a packet's timestamps (in `time_base`) through `mapping`, keeping each
+    /// stream's dts strictly increasing.
+    pub fn rebase_ts(
+        &mut self,
+        mapping: &Mapping,
+        stream: usize,
+        time_base: Rational,
+        pts: Option<i64>,
+        dts: Option<i64>,
+        duration: i64,
+    ) -> (Option<i64>, Option<i64>) {
+        let shift = mapping.shift(time_base);
+        let mut pts = pts.map(|ts| ts + shift);
+        let mut dts = dts.map(|ts| ts + shift);
+        if let (Some(out_dts), Some(&last)) = (dts, self.last_dts.get(&stream))
+            && out_dts <= last
+        {
+            // Only this packet moves; shifting the whole segment would desync it
+            dts = Some(last + 1);
+            pts = pts.map(|ts| ts.max(last + 1));
+        }
+        if let Some(out_dts) = dts {
+            self.last_dts.insert(stream, out_dts);
+        }
+
+        let duration = if duration > 0 {
+            self.durations.insert(stream, duration);
+            duration
+        } else {
+            self.durations.get(&stream).copied().unwrap_or(0)
+        };
+        if self.reference.is_none_or(|reference| reference == stream)
+            && let Some(ts) = pts
+        {
+            let end = Timestamp::from_pts(ts + duration, time_base);
+            self.next_offset = self.next_offset.max(end);
+        }
+        (pts, dts)
+    }
+
+    /// `rebase_ts` applied to `packet` in place.
+    pub fn rebase(&mut self, mapping: &Mapping, stream: usize, time_base: Rational, packet: &mut Packet) {
+        let (pts, dts) = self.rebase_ts(mapping, stream, time_base, packet.pts(), packet.dts(), packet.duration());
+        packet.set_pts(pts);
+        packet.set_dts(dts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIDEO: usize = 0;
+    const AUDIO: usize = 1;
+    // 29.97 fps video and 44.1 kHz audio in 1024-sample packets
+    const FRAME: i64 = 1001;
+    const SAMPLES: i64 = 1024;
+
+    fn video_tb() -> Rational {
+        Rational::new(1, 30000)
+    }
+
+    fn audio_tb() -> Rational {
+        Rational::new(1, 44100)
+    }
+
+    fn frame_duration() -> Timestamp {
+        Timestamp::from_pts(FRAME, video_tb())
+    }
+
+    fn first_at_or_after(t: Timestamp, step: i64, time_base: Rational) -> i64 {
+        let ts = t.to_pts(time_base);
+        (ts + step - 1).div_euclid(step) * step
+    }
+
+    // Ranges with awkward, non-frame-aligned lengths spread over a long recording.
+    fn ranges(count: i64) -> Vec<(Timestamp, Timestamp)> {
+        (0..count)
+            .map(|k| {
+                let start = Timestamp::from_micros(k * 7_300_000 + (k % 7) * 12_345);
+                let end = start + Timestamp::from_micros(2_100_000 + (k % 5) * 130_017);
+                (start, end)
+            })
+            .collect()
+    }
+
+    struct Joined {
+        // Output start of each segment's first video and audio packet
+        video_starts: Vec<Timestamp>,
+        audio_starts: Vec<Timestamp>,
+        // Source start of each segment's first video and audio packet
+        source_video: Vec<Timestamp>,
+        source_audio: Vec<Timestamp>,
+        // Output end of each segment's video
+        video_ends: Vec<Timestamp>,
+        dts: HashMap<usize, Vec<i64>>,
+    }
+
+    // Join `ranges` of a synthetic recording whose video has `delay` ticks of dts lead.
+    fn join(ranges: &[(Timestamp, Timestamp)], delay: i64) -> Joined {
+        let mut rebaser = Rebaser::new(Some(VIDEO));
+        let mut joined = Joined {
+            video_starts: Vec::new(),
+            audio_starts: Vec::new(),
+            source_video: Vec::new(),
+            source_audio: Vec::new(),
+            video_ends: Vec::new(),
+            dts: HashMap::new(),
+        };
+        for &(start, end) in ranges {
+            let first_video = first_at_or_after(start, FRAME, video_tb());
+            let begin = Timestamp::from_pts(first_video, video_tb());
+            let mapping = rebaser.start_segment(begin);
+
+            let end_video = end.to_pts(video_tb());
+            let mut video_end = None;
+            for pts in (first_video..end_video).step_by(FRAME as usize) {
+                let (out_pts, out_dts) =
+                    rebaser.rebase_ts(&mapping, VIDEO, video_tb(), Some(pts), Some(pts - delay), FRAME);
+                let out_pts = out_pts.unwrap();
+                if pts == first_video {
+                    joined.video_starts.push(Timestamp::from_pts(out_pts, video_tb()));
+                    joined.source_video.push(begin);
+                }
+                joined.dts.entry(VIDEO).or_default().push(out_dts.unwrap());
+                video_end = Some(Timestamp::from_pts(out_pts + FRAME, video_tb()));
+            }
+            joined.video_ends.push(video_end.unwrap());
+
+            let first_audio = first_at_or_after(begin, SAMPLES, audio_tb());
+            let end_audio = end.to_pts(audio_tb());
+            for pts in (first_audio..end_audio).step_by(SAMPLES as usize) {
+                let (out_pts, out_dts) =
+                    rebaser.rebase_ts(&mapping, AUDIO, audio_tb(), Some(pts), Some(pts), SAMPLES);
+                if pts == first_audio {
+                    joined.audio_starts.push(Timestamp::from_pts(out_pts.unwrap(), audio_tb()));
+                    joined.source_audio.push(Timestamp::from_pts(first_audio, audio_tb()));
+                }
+                joined.dts.entry(AUDIO).or_default().push(out_dts.unwrap());
+            }
+        }
+        joined
+    }
+
+    fn abs(t: Timestamp) -> i64 {
+        t.as_micros().abs()
+    }
+
+    #[test]
+    fn av_sync_drift_stays_under_one_frame_across_hundreds_of_joins() {
+        let joined = join(&ranges(300), 0);
+        for k in 0..joined.video_starts.len() {
+            let output_gap = joined.audio_starts[k] - joined.video_starts[k];
+            let source_gap = joined.source_audio[k] - joined.source_video[k];
+            let drift = output_gap - source_gap;
+            assert!(abs(drift) < abs(frame_duration()), "segment {}: A/V drift {:?}", k, drift);
+            // Rounding stays at tick level instead of accumulating
+            assert!(abs(drift) <= 100, "segment {}: A/V drift {:?}", k, drift);
+        }
+    }
+
+    #[test]
+    fn segments_are_contiguous_without_tick_gaps() {
+        let joined = join(&ranges(300), 0);
+        for k in 1..joined.video_starts.len() {
+            let gap = joined.video_starts[k] - joined.video_ends[k - 1];
+            assert!(abs(gap) <= 1, "join {}: video gap {:?}", k, gap);
+        }
+    }
+
+    #[test]
+    fn total_duration_matches_the_kept_content() {
+        let ranges = ranges(300);
+        let joined = join(&ranges, 0);
+        let kept = (0..ranges.len())
+            .map(|k| {
+                let frames = (ranges[k].1.to_pts(video_tb()) - 1 - first_at_or_after(ranges[k].0, FRAME, video_tb()))
+                    .div_euclid(FRAME)
+                    + 1;
+                frames * FRAME
+            })
+            .sum::<i64>();
+        let expected = Timestamp::from_pts(kept, video_tb());
+        let drift = *joined.video_ends.last().unwrap() - expected;
+        assert!(abs(drift) < abs(frame_duration()), "total drift {:?}", drift);
+    }
+
+    #[test]
+    fn dts_stays_strictly_increasing_with_reordered_video() {
+        let joined = join(&ranges(50), 2 * FRAME);
+        for (stream, dts) in &joined.dts {
+            assert!(dts.windows(2).all(|pair| pair[0] < pair[1]), "stream {} dts not increasing", stream);
+        }
+    }
+
+    #[test]
+    fn packets_without_duration_reuse_the_last_known_one() {
+        let mut rebaser = Rebaser::new(Some(VIDEO));
+        let mapping = rebaser.start_segment(Timestamp::default());
+        rebaser.rebase_ts(&mapping, VIDEO, video_tb(), Some(0), Some(0), FRAME);
+        rebaser.rebase_ts(&mapping, VIDEO, video_tb(), Some(FRAME), Some(FRAME), 0);
+        assert_eq!(rebaser.position(), Timestamp::from_pts(2 * FRAME, video_tb()));
+    }
+}
@@ -0,0 +1,698 @@
+use crate::error::{self, Error, Result};
+use crate::keyframe;
+use crate::segment;
+use crate::timeline::Clip;
+use crate::timestamp::Timestamp;
+use ffmpeg::{codec, decoder, encoder, filter, format, frame, media, software, ChannelLayout, Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use std::collections::HashSet;
+
+/// Render the timeline to `output`. Clips that all come from one source, in source order,
+/// are stream copied in a single pass (with the audio faded at the joins when
+/// `audio_fade` is given); anything else (or `reencode`) goes through a decode/encode pass.
+/// Returns the length written.
+pub fn render(clips: &[Clip], output: &str, reencode: bool, audio_fade: Option<Timestamp>) -> Result<Timestamp> {
+    if clips.is_empty() {
+        return Err(Error::empty_output(output, "the timeline is empty"));
+    }
+    error::init()?;
+    if stream_copies(clips, reencode) {
+        let ranges: Vec<_> = clips.iter().map(|clip| (clip.start, clip.end)).collect();
+        segment::write_segments(&clips[0].source, &ranges, output, audio_fade)
+    } else {
+        transcode_clips(clips, output)?;
+        Ok(clips.iter().map(Clip::duration).fold(Timestamp::default(), |a, b| a + b))
+    }
+}
+
+/// Whether `render` stream copies `clips` rather than re-encoding them.
+pub fn stream_copies(clips: &[Clip], reencode: bool) -> bool {
+    let single_source = clips.iter().all(|clip| clip.source == clips[0].source);
+    let in_order = clips.windows(2).all(|pair| pair[0].end <= pair[1].start);
+    single_source && in_order && !reencode
+}
+
+// Video side of a re-encoded export. Frames are scaled to the first clip's size
+// and pixel format and encoded in 1/frame_rate units.
+struct VideoOutput {
+    encoder: encoder::Video,
+    out_index: usize,
+    width: u32,
+    height: u32,
+    format: format::Pixel,
+    time_base: Rational,
+    last_pts: Option<i64>,
+    // Only set up once a clip's frames differ from the output size or format
+    scaler: Option<software::scaling::Context>,
+}
+
+// Audio side of a re-encoded export. Each clip's samples are converted to the
+// encoder's format and then re-chunked to its frame size by `framer`, which lives
+// for the whole export so no partial frames appear at the joins.
+struct AudioOutput {
+    encoder: encoder::Audio,
+    framer: filter::Graph,
+    out_index: usize,
+    time_base: Rational,
+    placement: AudioPlacement,
+}
+
+// Keeps the exported audio in step with the video by counting samples: each clip's
+// samples start where the clip starts on the timeline, silence fills in where a clip
+// has too few and samples past a clip's end are dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct AudioPlacement {
+    // Samples written so far
+    written: i64,
+    // Where the current clip ends, in samples
+    clip_end: i64,
+}
+
+impl AudioPlacement {
+    // Start the clip at `start..end` samples; returns how much silence leads into it.
+    fn begin_clip(&mut self, start: i64, end: i64) -> usize {
+        self.clip_end = end;
+        self.pad_to(start)
+    }
+
+    // How many of `samples` more samples fit in the current clip.
+    fn take(&mut self, samples: usize) -> usize {
+        let kept = (self.clip_end - self.written).clamp(0, samples as i64);
+        self.written += kept;
+        kept as usize
+    }
+
+    // Finish the current clip; returns how much silence makes up for missing samples.
+    fn end_clip(&mut self) -> usize {
+        self.pad_to(self.clip_end)
+    }
+
+    fn pad_to(&mut self, position: i64) -> usize {
+        let silence = (position - self.written).max(0);
+        self.written += silence;
+        silence as usize
+    }
+}
+
+fn transcode_clips(clips: &[Clip], output: &str) -> Result<()> {
+    let mut output_file = format::output(&output).map_err(|e| Error::open(output, e))?;
+    let first = format::input(&clips[0].source).map_err(|e| Error::open(&clips[0].source, e))?;
+    let mut video = match first.streams().best(media::Type::Video) {
+        Some(stream) => Some(open_video_output(&clips[0].source, &stream, &mut output_file, output)?),
+        None => None,
+    };
+    let mut audio = match first.streams().best(media::Type::Audio) {
+        Some(stream) => Some(open_audio_output(&clips[0].source, &stream, &mut output_file, output)?),
+        None => None,
+    };
+    drop(first);
+    if video.is_none() && audio.is_none() {
+        return Err(Error::unsupported(&clips[0].source, None, "no audio or video to export"));
+    }
+    output_file.write_header().map_err(|e| Error::mux(output, e))?;
+
+    let mut offset = Timestamp::default();
+    for clip in clips {
+        let end = offset + clip.duration();
+        if let Some(audio) = audio.as_mut() {
+            audio.begin_clip(offset, end, &mut output_file, output)?;
+        }
+        transcode_clip(clip, offset, video.as_mut(), audio.as_mut(), &mut output_file, output)?;
+        if let Some(audio) = audio.as_mut() {
+            audio.end_clip(&mut output_file, output)?;
+        }
+        offset = end;
+    }
+
+    if let Some(video) = video.as_mut() {
+        let out_index = video.out_index;
+        video.encoder.send_eof().map_err(|e| Error::codec(output, Some(out_index), e))?;
+        write_encoded(&mut video.encoder, video.time_base, out_index, &mut output_file, output)?;
+    }
+    if let Some(audio) = audio.as_mut() {
+        let out_index = audio.out_index;
+        audio
+            .framer
+            .get("in")
+            .unwrap()
+            .source()
+            .flush()
+            .map_err(|e| Error::codec(output, Some(out_index), e))?;
+        audio.send_framed(&mut output_file, output)?;
+        audio.encoder.send_eof().map_err(|e| Error::codec(output, Some(out_index), e))?;
+        write_encoded(&mut audio.encoder, audio.time_base, out_index, &mut output_file, output)?;
+    }
+    output_file.write_trailer().map_err(|e| Error::mux(output, e))?;
+    Ok(())
+}
+
+fn open_video_output(
+    source: &str,
+    stream: &format::stream::Stream,
+    output_file: &mut format::context::Output,
+    output: &str,
+) -> Result<VideoOutput> {
+    let decoder = codec::context::Context::from_parameters(stream.parameters())
+        .and_then(|ctx| ctx.decoder().video())
+        .map_err(|e| Error::codec(source, Some(stream.index()), e))?;
+    let codec = encoder::find(output_file.format().codec(output, media::Type::Video))
+        .ok_or_else(|| Error::unsupported(output, None, "no video encoder available for the output format"))?;
+    let global_header = output_file.format().flags().contains(format::Flags::GLOBAL_HEADER);
+    let mut out_stream = output_file.add_stream(codec).map_err(|e| Error::mux(output, e))?;
+    let mut encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()
+        .map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+
+    let frame_rate = match stream.avg_frame_rate() {
+        rate if rate.numerator() > 0 => rate,
+        _ => stream.rate(),
+    };
+    let time_base = if frame_rate.numerator() > 0 { frame_rate.invert() } else { stream.time_base() };
+    // Keep the source's pixel format when the encoder takes it
+    let format = codec
+        .video()
+        .ok()
+        .and_then(|video| video.formats())
+        .and_then(|mut formats| {
+            let supported: Vec<format::Pixel> = formats.by_ref().collect();
+            supported
+                .iter()
+                .copied()
+                .find(|&f| f == decoder.format())
+                .or(supported.first().copied())
+        })
+        .unwrap_or(decoder.format());
+
+    encoder.set_width(decoder.width());
+    encoder.set_height(decoder.height());
+    encoder.set_format(format);
+    encoder.set_aspect_ratio(decoder.aspect_ratio());
+    encoder.set_time_base(time_base);
+    if frame_rate.numerator() > 0 {
+        encoder.set_frame_rate(Some(frame_rate));
+    }
+    if decoder.bit_rate() > 0 {
+        encoder.set_bit_rate(decoder.bit_rate());
+    }
+    if global_header {
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let encoder = encoder.open_as(codec).map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+    out_stream.set_parameters(&encoder);
+    out_stream.set_time_base(time_base);
+    Ok(VideoOutput {
+        out_index: out_stream.index(),
+        width: decoder.width(),
+        height: decoder.height(),
+        format,
+        time_base,
+        last_pts: None,
+        scaler: None,
+        encoder,
+    })
+}
+
+fn open_audio_output(
+    source: &str,
+    stream: &format::stream::Stream,
+    output_file: &mut format::context::Output,
+    output: &str,
+) -> Result<AudioOutput> {
+    let decoder = codec::context::Context::from_parameters(stream.parameters())
+        .and_then(|ctx| ctx.decoder().audio())
+        .map_err(|e| Error::codec(source, Some(stream.index()), e))?;
+    let codec = encoder::find(output_file.format().codec(output, media::Type::Audio))
+        .ok_or_else(|| Error::unsupported(output, None, "no audio encoder available for the output format"))?;
+    let audio_codec = codec.audio().map_err(|e| Error::codec(output, None, e))?;
+    let global_header = output_file.format().flags().contains(format::Flags::GLOBAL_HEADER);
+    let mut out_stream = output_file.add_stream(codec).map_err(|e| Error::mux(output, e))?;
+    let mut encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .audio()
+        .map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+
+    let channel_layout = audio_codec
+        .channel_layouts()
+        .map(|layouts| layouts.best(decoder.channels() as i32))
+        .unwrap_or(ChannelLayout::STEREO);
+    let sample_format = audio_codec
+        .formats()
+        .and_then(|mut formats| formats.next())
+        .ok_or_else(|| Error::unsupported(output, None, "the audio encoder reports no sample formats"))?;
+    let time_base = Rational::new(1, decoder.rate() as i32);
+
+    encoder.set_rate(decoder.rate() as i32);
+    encoder.set_channel_layout(channel_layout);
+    encoder.set_format(sample_format);
+    encoder.set_time_base(time_base);
+    if decoder.bit_rate() > 0 {
+        encoder.set_bit_rate(decoder.bit_rate());
+    }
+    if global_header {
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let encoder = encoder.open_as(codec).map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+    out_stream.set_parameters(&encoder);
+    out_stream.set_time_base(time_base);
+
+    let frame_size = if audio_codec
+        .capabilities()
+        .contains(codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
+    {
+        None
+    } else {
+        Some(encoder.frame_size())
+    };
+    let framer = audio_graph(
+        (time_base, encoder.rate(), encoder.format(), encoder.channel_layout()),
+        (encoder.format(), encoder.channel_layout(), encoder.rate()),
+        "anull",
+        frame_size,
+    )
+    .map_err(|e| Error::codec(output, Some(out_stream.index()), e))?;
+    Ok(AudioOutput {
+        out_index: out_stream.index(),
+        time_base,
+        placement: AudioPlacement::default(),
+        framer,
+        encoder,
+    })
+}
+
+// The channel layout of `decoder`'s audio. Streams with an unspecified channel order
+// carry no mask, so they get the default layout for their channel count.
+pub(crate) fn audio_layout(decoder: &decoder::Audio) -> ChannelLayout {
+    match decoder.channel_layout() {
+        layout if layout.bits() == 0 => ChannelLayout::default(decoder.channels() as i32),
+        layout => layout,
+    }
+}
+
+// Build `abuffer -> filters -> abuffersink`, letting the graph insert whatever conversion
+// is needed between the input and the requested output format. `filters` is a filter
+// chain, "anull" for none.
+pub(crate) fn audio_graph(
+    (time_base, rate, format, layout): (Rational, u32, format::Sample, ChannelLayout),
+    (out_format, out_layout, out_rate): (format::Sample, ChannelLayout, u32),
+    filters: &str,
+    frame_size: Option<u32>,
+) -> Result<filter::Graph, ffmpeg::Error> {
+    let mut graph = filter::Graph::new();
+    let args = format!(
+        "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+        time_base,
+        rate,
+        format.name(),
+        layout.bits()
+    );
+    graph.add(&filter::find("abuffer").unwrap(), "in", &args)?;
+    graph.add(&filter::find("abuffersink").unwrap(), "out", "")?;
+    {
+        let mut out = graph.get("out").unwrap();
+        out.set_sample_format(out_format);
+        out.set_channel_layout(out_layout);
+        out.set_sample_rate(out_rate);
+    }
+    graph.output("in", 0)?.input("out", 0)?.parse(filters)?;
+    graph.validate()?;
+    if let Some(frame_size) = frame_size {
+        graph.get("out").unwrap().sink().set_frame_size(frame_size);
+    }
+    Ok(graph)
+}
+
+impl VideoOutput {
+    fn send(
+        &mut self,
+        frame: &mut frame::Video,
+        pts: i64,
+        output_file: &mut format::context::Output,
+        output: &str,
+    ) -> Result<()> {
+        // Rounding into the output frame rate can map two source frames onto one slot
+        if self.last_pts.is_some_and(|last| pts <= last) {
+            return Ok(());
+        }
+        self.last_pts = Some(pts);
+        let mut scaled = frame::Video::empty();
+        let frame = if frame.width() != self.width || frame.height() != self.height || frame.format() != self.format {
+            let scaler = match self.scaler.as_mut() {
+                Some(scaler)
+                    if scaler.input().width == frame.width()
+                        && scaler.input().height == frame.height()
+                        && scaler.input().format == frame.format() =>
+                {
+                    scaler
+                }
+                _ => self.scaler.insert(
+                    software::scaling::Context::get(
+                        frame.format(),
+                        frame.width(),
+                        frame.height(),
+                        self.format,
+                        self.width,
+                        self.height,
+                        software::scaling::Flags::BILINEAR,
+                    )
+                    .map_err(|e| Error::codec(output, Some(self.out_index), e))?,
+                ),
+            };
+            scaler
+                .run(frame, &mut scaled)
+                .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+            &mut scaled
+        } else {
+            frame
+        };
+        frame.set_pts(Some(pts));
+        frame.set_kind(ffmpeg::picture::Type::None);
+        self.encoder
+            .send_frame(frame)
+            .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+        write_encoded(&mut self.encoder, self.time_base, self.out_index, output_file, output)
+    }
+}
+
+impl AudioOutput {
+    fn begin_clip(
+        &mut self,
+        start: Timestamp,
+        end: Timestamp,
+        output_file: &mut format::context::Output,
+        output: &str,
+    ) -> Result<()> {
+        let pts = self.placement.written;
+        let silence = self.placement.begin_clip(start.to_pts(self.time_base), end.to_pts(self.time_base));
+        self.send_silence(pts, silence, output_file, output)
+    }
+
+    fn end_clip(&mut self, output_file: &mut format::context::Output, output: &str) -> Result<()> {
+        let pts = self.placement.written;
+        let silence = self.placement.end_clip();
+        self.send_silence(pts, silence, output_file, output)
+    }
+
+    // Feed converted samples into the framer at the current clip's place on the timeline.
+    fn send(
+        &mut self,
+        frame: &mut frame::Audio,
+        output_file: &mut format::context::Output,
+        output: &str,
+    ) -> Result<()> {
+        let pts = self.placement.written;
+        let kept = self.placement.take(frame.samples());
+        if kept == 0 {
+            return Ok(());
+        }
+        frame.set_samples(kept);
+        frame.set_pts(Some(pts));
+        self.add(frame, output_file, output)
+    }
+
+    fn send_silence(
+        &mut self,
+        mut pts: i64,
+        mut samples: usize,
+        output_file: &mut format::context::Output,
+        output: &str,
+    ) -> Result<()> {
+        let channels = self.encoder.channel_layout().channels();
+        while samples > 0 {
+            // Chunked, so a long stretch without audio does not take one huge frame
+            let chunk = samples.min(self.encoder.rate() as usize);
+            let mut frame = frame::Audio::new(self.encoder.format(), chunk, self.encoder.channel_layout());
+            frame.set_rate(self.encoder.rate());
+            // SAFETY: the frame was just allocated for `chunk` samples of `channels`
+            // channels in the encoder's sample format
+            unsafe {
+                ffmpeg::ffi::av_samples_set_silence(
+                    (*frame.as_mut_ptr()).extended_data,
+                    0,
+                    chunk as i32,
+                    channels,
+                    self.encoder.format().into(),
+                );
+            }
+            frame.set_pts(Some(pts));
+            self.add(&frame, output_file, output)?;
+            pts += chunk as i64;
+            samples -= chunk;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, frame: &frame::Audio, output_file: &mut format::context::Output, output: &str) -> Result<()> {
+        self.framer
+            .get("in")
+            .unwrap()
+            .source()
+            .add(frame)
+            .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+        self.send_framed(output_file, output)
+    }
+
+    fn send_framed(&mut self, output_file: &mut format::context::Output, output: &str) -> Result<()> {
+        let mut framed = frame::Audio::empty();
+        while self.framer.get("out").unwrap().sink().frame(&mut framed).is_ok() {
+            self.encoder
+                .send_frame(&framed)
+                .map_err(|e| Error::codec(output, Some(self.out_index), e))?;
+            write_encoded(&mut self.encoder, self.time_base, self.out_index, output_file, output)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_encoded(
+    encoder: &mut encoder::Encoder,
+    time_base: Rational,
+    out_index: usize,
+    output_file: &mut format::context::Output,
+    output: &str,
+) -> Result<()> {
+    let out_time_base = output_file.stream(out_index).expect("Output stream not found").time_base();
+    let mut packet = Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(out_index);
+        packet.rescale_ts(time_base, out_time_base);
+        packet.write_interleaved(output_file).map_err(|e| Error::mux(output, e))?;
+    }
+    Ok(())
+}
+
+// Decode `clip` and append its frames to the encoders at timeline position `offset`.
+fn transcode_clip(
+    clip: &Clip,
+    offset: Timestamp,
+    mut video: Option<&mut VideoOutput>,
+    mut audio: Option<&mut AudioOutput>,
+    output_file: &mut format::context::Output,
+    output: &str,
+) -> Result<()> {
+    let source = clip.source.as_str();
+    let mut input_file = format::input(&source).map_err(|e| Error::open(source, e))?;
+
+    let video_stream = input_file.streams().best(media::Type::Video).filter(|_| video.is_some());
+    let video_index = video_stream.as_ref().map(|stream| stream.index());
+    let video_time_base = video_stream.as_ref().map(|stream| stream.time_base());
+    let mut video_decoder = match video_stream {
+        Some(stream) => Some(
+            codec::context::Context::from_parameters(stream.parameters())
+                .and_then(|ctx| ctx.decoder().video())
+                .map_err(|e| Error::codec(source, video_index, e))?,
+        ),
+        None => None,
+    };
+
+    let audio_stream = input_file.streams().best(media::Type::Audio).filter(|_| audio.is_some());
+    let audio_index = audio_stream.as_ref().map(|stream| stream.index());
+    let audio_time_base = audio_stream.as_ref().map(|stream| stream.time_base());
+    let mut audio_decoder: Option<decoder::Audio> = match audio_stream {
+        Some(stream) => Some(
+            codec::context::Context::from_parameters(stream.parameters())
+                .and_then(|ctx| ctx.decoder().audio())
+                .map_err(|e| Error::codec(source, audio_index, e))?,
+        ),
+        None => None,
+    };
+    // Trims this clip's samples to the clip, to the sample, and converts them to the
+    // export's audio format
+    let mut converter = match (audio_decoder.as_ref(), audio.as_ref(), audio_time_base) {
+        (Some(decoder), Some(audio), Some(time_base)) => Some(
+            audio_graph(
+                (time_base, decoder.rate(), decoder.format(), audio_layout(decoder)),
+                (audio.encoder.format(), audio.encoder.channel_layout(), audio.encoder.rate()),
+                &format!(
+                    "atrim=start_pts={}:end_pts={}",
+                    clip.start.to_pts(time_base),
+                    clip.end.to_pts(time_base)
+                ),
+                None,
+            )
+            .map_err(|e| Error::codec(source, audio_index, e))?,
+        ),
+        _ => None,
+    };
+
+    keyframe::seek(&mut input_file, source, clip.start)?;
+
+    let mut decoded_video = frame::Video::empty();
+    let mut decoded_audio = frame::Audio::empty();
+    let mut finished = HashSet::new();
+    let wanted = video_index.iter().chain(audio_index.iter()).count();
+    let mut packets = input_file.packets();
+    loop {
+        let next = packets.next();
+        let eof = next.is_none();
+        if let Some((stream, packet)) = &next
+            && (Some(stream.index()) == video_index || Some(stream.index()) == audio_index)
+            && packet.dts().is_some_and(|dts| dts > clip.end.to_pts(stream.time_base()))
+        {
+            finished.insert(stream.index());
+        }
+        let done = eof || finished.len() == wanted;
+
+        if let (Some(decoder), Some(time_base)) = (video_decoder.as_mut(), video_time_base) {
+            match &next {
+                Some((stream, packet)) if !done && Some(stream.index()) == video_index => {
+                    decoder.send_packet(packet).map_err(|e| Error::codec(source, video_index, e))?
+                }
+                _ if done => decoder.send_eof().map_err(|e| Error::codec(source, video_index, e))?,
+                _ => {}
+            }
+            let (start_ts, end_ts) = (clip.start.to_pts(time_base), clip.end.to_pts(time_base));
+            while decoder.receive_frame(&mut decoded_video).is_ok() {
+                let Some(ts) = decoded_video.timestamp() else { continue };
+                if ts < start_ts || ts >= end_ts {
+                    continue;
+                }
+                if let Some(video) = video.as_deref_mut() {
+                    let position = offset + (Timestamp::from_pts(ts, time_base) - clip.start);
+                    video.send(&mut decoded_video, position.to_pts(video.time_base), output_file, output)?;
+                }
+            }
+        }
+
+        if let (Some(decoder), Some(graph), Some(time_base)) =
+            (audio_decoder.as_mut(), converter.as_mut(), audio_time_base)
+        {
+            match &next {
+                Some((stream, packet)) if !done && Some(stream.index()) == audio_index => {
+                    decoder.send_packet(packet).map_err(|e| Error::codec(source, audio_index, e))?
+                }
+                _ if done => decoder.send_eof().map_err(|e| Error::codec(source, audio_index, e))?,
+                _ => {}
+            }
+            let end_ts = clip.end.to_pts(time_base);
+            while decoder.receive_frame(&mut decoded_audio).is_ok() {
+                // Frames straddling the clip's start are cut to the sample by the converter
+                let Some(ts) = decoded_audio.timestamp() else { continue };
+                if ts >= end_ts {
+                    continue;
+                }
+                decoded_audio.set_pts(Some(ts));
+                graph
+                    .get("in")
+                    .unwrap()
+                    .source()
+                    .add(&decoded_audio)
+                    .map_err(|e| Error::codec(source, audio_index, e))?;
+            }
+            if done {
+                graph
+                    .get("in")
+                    .unwrap()
+                    .source()
+                    .flush()
+                    .map_err(|e| Error::codec(source, audio_index, e))?;
+            }
+            let mut converted = frame::Audio::empty();
+            while graph.get("out").unwrap().sink().frame(&mut converted).is_ok() {
+                if let Some(audio) = audio.as_deref_mut() {
+                    audio.send(&mut converted, output_file, output)?;
+                }
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs clips through the placement the way an export does: each clip's converted
+    // samples arrive in 1024-sample frames, `extra` more or fewer than the clip holds.
+    // Returns where each clip's first sample landed and the total written.
+    fn place(clips: &[(Timestamp, i64)], rate: i32) -> (Vec<i64>, i64) {
+        let time_base = Rational::new(1, rate);
+        let mut placement = AudioPlacement::default();
+        let mut offset = Timestamp::default();
+        let mut starts = Vec::new();
+        for &(duration, extra) in clips {
+            let end = offset + duration;
+            placement.begin_clip(offset.to_pts(time_base), end.to_pts(time_base));
+            starts.push(placement.written);
+            let mut left = (duration.to_pts(time_base) + extra).max(0) as usize;
+            while left > 0 {
+                let frame = left.min(1024);
+                placement.take(frame);
+                left -= frame;
+            }
+            placement.end_clip();
+            offset = end;
+        }
+        (starts, placement.written)
+    }
+
+    #[test]
+    fn exported_audio_is_as_long_as_the_clips_together() {
+        let clips = [
+            (Timestamp::from_millis(1_500), 0),
+            (Timestamp::from_micros(733_333), 1),
+            (Timestamp::from_micros(1_001_001), -1),
+            (Timestamp::from_millis(2_000), 0),
+        ];
+        let total = clips.iter().fold(Timestamp::default(), |sum, &(duration, _)| sum + duration);
+        for rate in [44_100, 48_000] {
+            let (_, written) = place(&clips, rate);
+            assert_eq!(written, total.to_pts(Rational::new(1, rate)), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn each_clip_starts_at_its_place_on_the_timeline() {
+        let clips = [
+            (Timestamp::from_millis(500), 300),
+            (Timestamp::from_millis(250), -4_000),
+            (Timestamp::from_millis(1_000), 0),
+        ];
+        assert_eq!(place(&clips, 48_000), (vec![0, 24_000, 36_000], 84_000));
+    }
+
+    #[test]
+    fn clips_without_audio_are_filled_with_silence() {
+        let mut placement = AudioPlacement::default();
+        assert_eq!(placement.begin_clip(0, 4_800), 0);
+        assert_eq!(placement.end_clip(), 4_800);
+        assert_eq!(placement.begin_clip(4_800, 9_600), 0);
+        assert_eq!(placement.take(1_024), 1_024);
+        assert_eq!(placement.end_clip(), 3_776);
+        assert_eq!(placement.written, 9_600);
+    }
+
+    #[test]
+    fn samples_past_the_end_of_a_clip_are_dropped() {
+        let mut placement = AudioPlacement::default();
+        placement.begin_clip(0, 1_000);
+        assert_eq!(placement.take(1_024), 1_000);
+        assert_eq!(placement.take(1_024), 0);
+        assert_eq!(placement.end_clip(), 0);
+        assert_eq!(placement.written, 1_000);
+    }
+}
@@ -0,0 +1,382 @@
+//! Silence analyses written out as data files, for reviewing and hand-editing a cut
+//! list before rendering it.
+
+use crate::error::{Error, Result};
+use crate::loudness;
+use crate::probe;
+use crate::silence::{Analysis, SilenceSettings, FLOOR_DB};
+use crate::timestamp::Timestamp;
+use ffmpeg_next::media;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The kinds of file an analysis can be written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Settings, intervals and per-window levels as one JSON document
+    Json,
+    /// One row per kept part, sound and window
+    Csv,
+    /// An Audacity label track marking the kept parts
+    Audacity,
+    /// An ffconcat script that plays the kept parts of the input in order. It names the
+    /// input by its absolute path, so ffmpeg only reads it with `-f concat -safe 0`.
+    Concat,
+    /// A `-filter_complex_script` that selects the kept parts of the input
+    Filter,
+}
+
+impl ReportFormat {
+    /// The format a file name's extension implies, if any.
+    pub fn from_path(path: &str) -> Option<ReportFormat> {
+        let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" => Some(ReportFormat::Json),
+            "csv" => Some(ReportFormat::Csv),
+            "txt" => Some(ReportFormat::Audacity),
+            "ffconcat" => Some(ReportFormat::Concat),
+            "filter" => Some(ReportFormat::Filter),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ReportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReportFormat::Json => write!(f, "json"),
+            ReportFormat::Csv => write!(f, "csv"),
+            ReportFormat::Audacity => write!(f, "audacity"),
+            ReportFormat::Concat => write!(f, "concat"),
+            ReportFormat::Filter => write!(f, "filter"),
+        }
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            "audacity" => Ok(ReportFormat::Audacity),
+            "concat" => Ok(ReportFormat::Concat),
+            "filter" => Ok(ReportFormat::Filter),
+            _ => Err(format!(
+                "invalid format '{}': expected json, csv, audacity, concat or filter",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Interval {
+    start: Timestamp,
+    end: Timestamp,
+}
+
+#[derive(Serialize)]
+struct Window {
+    start: Timestamp,
+    end: Timestamp,
+    db: f64,
+    speech: bool,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    input: &'a str,
+    settings: &'a SilenceSettings,
+    threshold_db: f64,
+    keep: Vec<Interval>,
+    noisy: Vec<Interval>,
+    levels: Vec<Window>,
+}
+
+/// Write the analysis of `input` to `path` as `format`.
+pub fn write(
+    path: &str,
+    format: ReportFormat,
+    input: &str,
+    settings: &SilenceSettings,
+    analysis: &Analysis,
+) -> Result<()> {
+    let text = match format {
+        ReportFormat::Json => json(input, settings, analysis)?,
+        ReportFormat::Csv => csv(analysis),
+        ReportFormat::Audacity => audacity(analysis),
+        ReportFormat::Concat => concat(&absolute(input)?, analysis),
+        ReportFormat::Filter => {
+            let streams = probe::streams(input)?;
+            let has = |medium| streams.iter().any(|stream| stream.medium == medium);
+            filter(analysis, has(media::Type::Video), has(media::Type::Audio))
+        }
+    };
+    fs::write(path, text).map_err(|e| Error::io(path, e))
+}
+
+fn intervals(intervals: &[(Timestamp, Timestamp)]) -> Vec<Interval> {
+    intervals.iter().map(|&(start, end)| Interval { start, end }).collect()
+}
+
+fn json(input: &str, settings: &SilenceSettings, analysis: &Analysis) -> Result<String> {
+    let document = Document {
+        input,
+        settings,
+        threshold_db: analysis.threshold_db,
+        keep: intervals(&analysis.keep),
+        noisy: intervals(&analysis.noisy),
+        levels: analysis
+            .levels
+            .iter()
+            .map(|level| Window {
+                start: level.start,
+                end: level.end,
+                db: level_db(level.power),
+                speech: level.speech,
+            })
+            .collect(),
+    };
+    serde_json::to_string_pretty(&document).map_err(|e| Error::invalid(format!("Failed to serialize analysis: {}", e)))
+}
+
+// Digital silence has no finite level (nor a JSON one); report it at the floor.
+fn level_db(power: f64) -> f64 {
+    loudness::mean_square_db(power).max(FLOOR_DB)
+}
+
+fn seconds(time: Timestamp) -> String {
+    format!("{:.6}", time.as_secs_f64())
+}
+
+// Times in seconds, so spreadsheets can do arithmetic on them.
+fn csv(analysis: &Analysis) -> String {
+    let mut text = String::from("kind,start,end,db,speech\n");
+    for (kind, list) in [("keep", &analysis.keep), ("noisy", &analysis.noisy)] {
+        for &(start, end) in list {
+            text += &format!("{},{},{},,\n", kind, seconds(start), seconds(end));
+        }
+    }
+    for level in &analysis.levels {
+        text += &format!(
+            "window,{},{},{:.2},{}\n",
+            seconds(level.start),
+            seconds(level.end),
+            level_db(level.power),
+            level.speech
+        );
+    }
+    text
+}
+
+// Tab separated START END LABEL, as Audacity's Import Labels expects.
+fn audacity(analysis: &Analysis) -> String {
+    analysis
+        .keep
+        .iter()
+        .enumerate()
+        .map(|(i, &(start, end))| format!("{}\t{}\tkeep {}\n", seconds(start), seconds(end), i + 1))
+        .collect()
+}
+
+// Single quotes end a quoted string in ffmpeg scripts, so they are closed, escaped
+// and reopened.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+// ffmpeg resolves relative paths in an ffconcat script against the script's own
+// directory, so the input is written as an absolute path.
+fn absolute(input: &str) -> Result<String> {
+    let path = fs::canonicalize(input).map_err(|e| Error::io(input, e))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// `input` is the absolute path of the input.
+fn concat(input: &str, analysis: &Analysis) -> String {
+    let mut text = String::from("ffconcat version 1.0\n# Absolute paths: read with ffmpeg -f concat -safe 0 -i\n");
+    for &(start, end) in &analysis.keep {
+        text += &format!(
+            "file {}\ninpoint {}\noutpoint {}\n",
+            quote(input),
+            seconds(start),
+            seconds(end)
+        );
+    }
+    text
+}
+
+// `trim`/`atrim` each kept part of the first video and audio streams, start each
+// part's timestamps at zero and `concat` the parts, producing the `[v]` and `[a]`
+// outputs for the streams the input has. Going by timestamps rather than frame counts
+// keeps variable frame rate sources in sync.
+fn filter(analysis: &Analysis, video: bool, audio: bool) -> String {
+    let mut chains = Vec::new();
+    let mut parts = String::new();
+    for (index, &(start, end)) in analysis.keep.iter().enumerate() {
+        let range = format!("start={}:end={}", seconds(start), seconds(end));
+        if video {
+            chains.push(format!("[0:v]trim={},setpts=PTS-STARTPTS[v{}]", range, index));
+            parts += &format!("[v{}]", index);
+        }
+        if audio {
+            chains.push(format!("[0:a]atrim={},asetpts=PTS-STARTPTS[a{}]", range, index));
+            parts += &format!("[a{}]", index);
+        }
+    }
+    if analysis.keep.is_empty() {
+        // concat needs at least one part
+        if video {
+            chains.push("[0:v]select='0'[v]".to_string());
+        }
+        if audio {
+            chains.push("[0:a]aselect='0'[a]".to_string());
+        }
+    } else {
+        let outputs = [(video, "[v]"), (audio, "[a]")]
+            .into_iter()
+            .filter_map(|(has, label)| has.then_some(label))
+            .collect::<String>();
+        let (n, v, a) = (analysis.keep.len(), video as u8, audio as u8);
+        chains.push(format!("{}concat=n={}:v={}:a={}{}", parts, n, v, a, outputs));
+    }
+    chains.join(";\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loudness::Level;
+
+    fn analysis(keep: &[(i64, i64)]) -> Analysis {
+        let level = |start, power, speech| Level {
+            start: Timestamp::ms(start),
+            end: Timestamp::ms(start + 10),
+            power,
+            speech,
+        };
+        Analysis {
+            threshold_db: -40.0,
+            auto: None,
+            levels: vec![level(0, 0.01, true), level(10, 0.0, false), level(20, 0.000_5, true)],
+            noisy: vec![(Timestamp::ms(0), Timestamp::ms(10)), (Timestamp::ms(20), Timestamp::ms(30))],
+            keep: keep.iter().map(|&(start, end)| (Timestamp::ms(start), Timestamp::ms(end))).collect(),
+        }
+    }
+
+    #[test]
+    fn csv_lists_kept_parts_sounds_and_windows() {
+        let expected = "\
+kind,start,end,db,speech
+keep,0.000000,1.500000,,
+keep,2.250000,3.000001,,
+noisy,0.000000,0.010000,,
+noisy,0.020000,0.030000,,
+window,0.000000,0.010000,-20.00,true
+window,0.010000,0.020000,-120.00,false
+window,0.020000,0.030000,-33.01,true
+";
+        let mut analysis = analysis(&[(0, 1500), (2250, 3000)]);
+        analysis.keep[1].1 = Timestamp::from_micros(3_000_001);
+        assert_eq!(csv(&analysis), expected);
+    }
+
+    #[test]
+    fn audacity_labels_each_kept_part() {
+        let expected = "0.000000\t1.500000\tkeep 1\n2.250000\t3.000000\tkeep 2\n";
+        assert_eq!(audacity(&analysis(&[(0, 1500), (2250, 3000)])), expected);
+        assert_eq!(audacity(&analysis(&[])), "");
+    }
+
+    #[test]
+    fn concat_plays_each_kept_part_of_the_input() {
+        let expected = "\
+ffconcat version 1.0
+# Absolute paths: read with ffmpeg -f concat -safe 0 -i
+file '/media/talk.mp4'
+inpoint 0.000000
+outpoint 1.500000
+file '/media/talk.mp4'
+inpoint 2.250000
+outpoint 3.000000
+";
+        assert_eq!(concat("/media/talk.mp4", &analysis(&[(0, 1500), (2250, 3000)])), expected);
+        let empty = "ffconcat version 1.0\n# Absolute paths: read with ffmpeg -f concat -safe 0 -i\n";
+        assert_eq!(concat("/media/talk.mp4", &analysis(&[])), empty);
+    }
+
+    #[test]
+    fn concat_quotes_the_path() {
+        let text = concat("/media/it's here.mp4", &analysis(&[(0, 1000)]));
+        assert_eq!(text.lines().nth(2), Some(r"file '/media/it'\''s here.mp4'"));
+    }
+
+    #[test]
+    fn concat_paths_are_absolute() {
+        // Tests run in the package directory
+        let path = absolute("Cargo.toml").unwrap();
+        assert!(Path::new(&path).is_absolute(), "{}", path);
+        let manifest = fs::canonicalize(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")).unwrap();
+        assert_eq!(Path::new(&path), manifest);
+        assert_eq!(absolute("./src/../Cargo.toml").unwrap(), path);
+        assert!(absolute("no/such/input.mp4").is_err());
+    }
+
+    #[test]
+    fn filter_trims_and_joins_the_kept_parts_of_each_stream() {
+        let analysis = analysis(&[(0, 1500), (2250, 3000)]);
+        let expected = "\
+[0:v]trim=start=0.000000:end=1.500000,setpts=PTS-STARTPTS[v0];
+[0:a]atrim=start=0.000000:end=1.500000,asetpts=PTS-STARTPTS[a0];
+[0:v]trim=start=2.250000:end=3.000000,setpts=PTS-STARTPTS[v1];
+[0:a]atrim=start=2.250000:end=3.000000,asetpts=PTS-STARTPTS[a1];
+[v0][a0][v1][a1]concat=n=2:v=1:a=1[v][a]
+";
+        assert_eq!(filter(&analysis, true, true), expected);
+        let video = "\
+[0:v]trim=start=0.000000:end=1.500000,setpts=PTS-STARTPTS[v0];
+[0:v]trim=start=2.250000:end=3.000000,setpts=PTS-STARTPTS[v1];
+[v0][v1]concat=n=2:v=1:a=0[v]
+";
+        assert_eq!(filter(&analysis, true, false), video);
+        let audio = "\
+[0:a]atrim=start=0.000000:end=1.500000,asetpts=PTS-STARTPTS[a0];
+[0:a]atrim=start=2.250000:end=3.000000,asetpts=PTS-STARTPTS[a1];
+[a0][a1]concat=n=2:v=0:a=1[a]
+";
+        assert_eq!(filter(&analysis, false, true), audio);
+    }
+
+    #[test]
+    fn filter_without_kept_parts_selects_nothing() {
+        let expected = "[0:v]select='0'[v];\n[0:a]aselect='0'[a]\n";
+        assert_eq!(filter(&analysis(&[]), true, true), expected);
+        assert_eq!(filter(&analysis(&[]), false, true), "[0:a]aselect='0'[a]\n");
+    }
+
+    #[test]
+    fn formats_follow_the_extension() {
+        let cases = [
+            ("a.json", Some(ReportFormat::Json)),
+            ("a.CSV", Some(ReportFormat::Csv)),
+            ("labels.txt", Some(ReportFormat::Audacity)),
+            ("a.ffconcat", Some(ReportFormat::Concat)),
+            ("a.filter", Some(ReportFormat::Filter)),
+            ("a.mp4", None),
+            ("noextension", None),
+        ];
+        for (path, format) in cases {
+            assert_eq!(ReportFormat::from_path(path), format, "{}", path);
+        }
+        for format in [ReportFormat::Json, ReportFormat::Csv, ReportFormat::Audacity, ReportFormat::Concat] {
+            assert_eq!(format.to_string().parse(), Ok(format));
+        }
+        assert_eq!(" Filter ".parse(), Ok(ReportFormat::Filter));
+        assert!("xml".parse::<ReportFormat>().is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use crate::error::{self, Error, Result};
+use crate::fade::FadedAudio;
+use crate::interleave::{Interleaver, DEFAULT_LOOKAHEAD};
+use crate::keyframe;
+use crate::rebase::{Mapping, Rebaser};
+use crate::timestamp::Timestamp;
+use ffmpeg::{format, media, Packet};
+use ffmpeg_next as ffmpeg;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Sort `ranges`, drop empty ones and merge any that overlap or touch.
+pub fn normalize_ranges(ranges: &[(Timestamp, Timestamp)]) -> Vec<(Timestamp, Timestamp)> {
+    let mut sorted: Vec<_> = ranges.iter().copied().filter(|(s, e)| e > s).collect();
+    sorted.sort();
+    let mut merged: Vec<(Timestamp, Timestamp)> = Vec::new();
+    for (s, e) in sorted {
+        if let Some((_, last_e)) = merged.last_mut()
+            && s <= *last_e
+        {
+            *last_e = (*last_e).max(e);
+            continue;
+        }
+        merged.push((s, e));
+    }
+    merged
+}
+
+/// The parts of `0..duration` not covered by `ranges`, i.e. what is kept when
+/// `ranges` are the parts to delete.
+pub fn complement_ranges(ranges: &[(Timestamp, Timestamp)], duration: Timestamp) -> Vec<(Timestamp, Timestamp)> {
+    let mut kept = Vec::new();
+    let mut position = Timestamp::default();
+    for (start, end) in normalize_ranges(ranges) {
+        if start > position {
+            kept.push((position, start.min(duration)));
+        }
+        position = position.max(end);
+    }
+    if position < duration {
+        kept.push((position, duration));
+    }
+    kept.retain(|(s, e)| e > s);
+    kept
+}
+
+// One kept range while it is being written.
+struct Segment {
+    start: Timestamp,
+    // Set once it is known where the range really begins: its first video keyframe,
+    // since copied video cannot start anywhere else.
+    mapping: Option<Mapping>,
+    // Latest non-video packets read before `begin` was known
+    pending: VecDeque<Packet>,
+}
+
+// Which range each video packet is copied into, decided in decode order: a range's
+// video begins at its first keyframe and stops at the first frame decoded that shows
+// at or past its end.
+#[derive(Debug)]
+struct VideoCuts {
+    ranges: Vec<(Timestamp, Timestamp)>,
+    // Per range: where its video begins, once its first keyframe has been seen
+    begins: Vec<Option<Timestamp>>,
+    // Per range: whether a frame showing at or past its end has been decoded
+    ended: Vec<bool>,
+    // The range video is being copied into
+    current: Option<usize>,
+}
+
+// What becomes of one video packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoCut {
+    Skip,
+    // The keyframe a range's video begins at
+    Begin(usize),
+    Copy(usize),
+}
+
+impl VideoCuts {
+    fn new(ranges: &[(Timestamp, Timestamp)]) -> Self {
+        VideoCuts {
+            ranges: ranges.to_vec(),
+            begins: vec![None; ranges.len()],
+            ended: vec![false; ranges.len()],
+            current: None,
+        }
+    }
+
+    // Decide the packet showing at `t`, which comes next in decode order.
+    fn select(&mut self, t: Timestamp, is_key: bool) -> VideoCut {
+        if let Some(current) = self.current
+            && t >= self.ranges[current].1
+        {
+            // The first frame decoded that shows past the end cannot be a B-frame: the
+            // later frame a B-frame refers to is decoded before it. Frames decoded after
+            // it can be B-frames that refer to it, so they are dropped with it.
+            self.ended[current] = true;
+            self.current = None;
+        }
+        let k = self.ranges.partition_point(|&(_, end)| end <= t);
+        if k == self.ranges.len() || t < self.ranges[k].0 || self.ended[k] {
+            return VideoCut::Skip;
+        }
+        match self.begins[k] {
+            None if !is_key => VideoCut::Skip,
+            None => {
+                self.begins[k] = Some(t);
+                self.current = Some(k);
+                VideoCut::Begin(k)
+            }
+            // Leading pictures of an open GOP can show before the keyframe itself
+            Some(begin) if t < begin => VideoCut::Skip,
+            Some(_) => VideoCut::Copy(k),
+        }
+    }
+}
+
+/// Stream copy the given ranges of `input` into `output` with continuous timestamps,
+/// demuxing the input once. Ranges are normalized first, so they come out in source order.
+///
+/// Audio copied at arbitrary cut points tends to click at the joins. With `audio_fade`
+/// the audio streams are re-encoded instead, fading out and in over that long at the
+/// edges of every range; the video is still copied.
+///
+/// Returns the length written, which is short of the ranges' by up to a GOP each.
+pub fn write_segments(
+    input: &str,
+    ranges: &[(Timestamp, Timestamp)],
+    output: &str,
+    audio_fade: Option<Timestamp>,
+) -> Result<Timestamp> {
+    let ranges = normalize_ranges(ranges);
+    let Some(&(first_start, _)) = ranges.first() else {
+        return Err(Error::invalid("No ranges to cut"));
+    };
+    let last_end = ranges[ranges.len() - 1].1;
+
+    error::init()?;
+    let mut input_file = format::input(&input).map_err(|e| Error::open(input, e))?;
+    let mut output_file = format::output(&output).map_err(|e| Error::open(output, e))?;
+    let mut stream_mapping = HashMap::new();
+    let mut time_bases = HashMap::new();
+    let mut faded = HashMap::new();
+    for (idx, stream) in input_file.streams().enumerate() {
+        time_bases.insert(idx, stream.time_base());
+        if let Some(fade) = audio_fade
+            && stream.parameters().medium() == media::Type::Audio
+        {
+            let audio = FadedAudio::open(input, &stream, fade, &mut output_file, output)?;
+            stream_mapping.insert(idx, audio.out_index());
+            faded.insert(idx, audio);
+            continue;
+        }
+        let codec_params = stream.parameters();
+        let mut out_stream = output_file.add_stream(codec_params.id()).map_err(|e| Error::mux(output, e))?;
+        out_stream.set_parameters(codec_params);
+        stream_mapping.insert(idx, out_stream.index());
+    }
+    let video_index = input_file.streams().best(media::Type::Video).map(|stream| stream.index());
+    output_file.write_header().map_err(|e| Error::mux(output, e))?;
+
+    keyframe::seek(&mut input_file, input, first_start)?;
+
+    let mut segments: Vec<Segment> = ranges
+        .iter()
+        .map(|&(start, _)| Segment {
+            start,
+            mapping: None,
+            pending: VecDeque::new(),
+        })
+        .collect();
+    let mut writer = SegmentWriter {
+        output_file: &mut output_file,
+        output,
+        stream_mapping: &stream_mapping,
+        time_bases: &time_bases,
+        faded,
+        rebaser: Rebaser::new(video_index),
+        interleaver: Interleaver::new(DEFAULT_LOOKAHEAD),
+    };
+    let mut video_cuts = VideoCuts::new(&ranges);
+    let mut video_packets_written = 0;
+    let mut finished = HashSet::new();
+
+    for (stream, packet) in input_file.packets() {
+        let stream_index = stream.index();
+        if !stream_mapping.contains_key(&stream_index) {
+            continue;
+        }
+        let Some(pts) = packet.pts() else { continue };
+        let t = Timestamp::from_pts(pts, stream.time_base());
+        if packet.dts().is_some_and(|dts| Timestamp::from_pts(dts, stream.time_base()) >= last_end) {
+            if finished.insert(stream_index) {
+                // What this stream still sends only ends its last range: the others
+                // need not wait for it any more
+                writer
+                    .interleaver
+                    .finish_stream(stream_mapping[&stream_index], writer.output_file)
+                    .map_err(|e| Error::mux(output, e))?;
+            }
+            if finished.len() == stream_mapping.len() {
+                break;
+            }
+        }
+        if Some(stream_index) == video_index {
+            let k = match video_cuts.select(t, packet.is_key()) {
+                VideoCut::Skip => continue,
+                VideoCut::Begin(k) => {
+                    // Ranges before this one that never saw a keyframe are left out
+                    for segment in &mut segments[..k] {
+                        segment.pending.clear();
+                    }
+                    let mapping = writer.rebaser.start_segment(t);
+                    segments[k].mapping = Some(mapping);
+                    for held in std::mem::take(&mut segments[k].pending) {
+                        let held_stream = held.stream();
+                        let held_t = Timestamp::from_pts(held.pts().unwrap_or(0), time_bases[&held_stream]);
+                        if held_t >= t {
+                            writer.write(&mapping, held_stream, held)?;
+                        }
+                    }
+                    k
+                }
+                VideoCut::Copy(k) => k,
+            };
+            let mapping = segments[k].mapping.expect("Segment mapping not set");
+            writer.write(&mapping, stream_index, packet)?;
+            video_packets_written += 1;
+            continue;
+        }
+
+        // The range this packet falls in, if any
+        let k = ranges.partition_point(|&(_, end)| end <= t);
+        if k == ranges.len() || t < ranges[k].0 {
+            continue;
+        }
+
+        let segment = &mut segments[k];
+        match segment.mapping {
+            None if video_index.is_some() => {
+                // Hold until the video keyframe fixes where this range starts. The oldest
+                // packets are the ones most likely to fall before it, so drop those first.
+                if segment.pending.len() >= DEFAULT_LOOKAHEAD {
+                    segment.pending.pop_front();
+                }
+                segment.pending.push_back(packet);
+            }
+            None => {
+                // Without video every range starts exactly where it was asked to
+                let mapping = writer.rebaser.start_segment(segment.start);
+                segment.mapping = Some(mapping);
+                writer.write(&mapping, stream_index, packet)?;
+            }
+            Some(mapping) if t >= mapping.begin => writer.write(&mapping, stream_index, packet)?,
+            Some(_) => {}
+        }
+    }
+
+    writer.finish()?;
+    let written = writer.rebaser.position();
+
+    if video_index.is_some() && video_packets_written == 0 {
+        std::fs::remove_file(output).ok();
+        return Err(Error::NoKeyframe {
+            path: input.to_string(),
+            start: first_start,
+            end: last_end,
+        });
+    }
+    output_file.write_trailer().map_err(|e| Error::mux(output, e))?;
+    Ok(written)
+}
+
+struct SegmentWriter<'a> {
+    output_file: &'a mut format::context::Output,
+    output: &'a str,
+    stream_mapping: &'a HashMap<usize, usize>,
+    time_bases: &'a HashMap<usize, ffmpeg::Rational>,
+    // Audio streams re-encoded with fades, by input stream
+    faded: HashMap<usize, FadedAudio>,
+    rebaser: Rebaser,
+    interleaver: Interleaver,
+}
+
+impl SegmentWriter<'_> {
+    fn write(&mut self, mapping: &Mapping, stream_index: usize, mut packet: Packet) -> Result<()> {
+        let time_base = self.time_bases[&stream_index];
+        if let Some(audio) = self.faded.get_mut(&stream_index) {
+            // The rebaser still needs to see the packet to know where the range ends
+            let (pts, dts, duration) = (packet.pts(), packet.dts(), packet.duration());
+            self.rebaser.rebase_ts(mapping, stream_index, time_base, pts, dts, duration);
+            return audio.write(mapping, &packet, self.output_file, &mut self.interleaver, self.output);
+        }
+        self.rebaser.rebase(mapping, stream_index, time_base, &mut packet);
+        let out_index = self.stream_mapping[&stream_index];
+        let out_time_base = self.output_file.stream(out_index).expect("Output stream not found").time_base();
+        packet.set_stream(out_index);
+        packet.set_position(-1);
+        packet.rescale_ts(time_base, out_time_base);
+        self.interleaver
+            .push(packet, out_time_base, self.output_file)
+            .map_err(|e| Error::mux(self.output, e))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for audio in self.faded.values_mut() {
+            audio.finish(self.output_file, &mut self.interleaver, self.output)?;
+        }
+        self.interleaver.flush(self.output_file).map_err(|e| Error::mux(self.output, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ranges in whole seconds
+    type Spans = &'static [(i64, i64)];
+
+    fn secs(ranges: &[(i64, i64)]) -> Vec<(Timestamp, Timestamp)> {
+        ranges.iter().map(|&(s, e)| (Timestamp::from_secs(s), Timestamp::from_secs(e))).collect()
+    }
+
+    // Run video packets, given in decode order as (pts in seconds, keyframe), through
+    // the cuts for `ranges` and return the pts copied into each range.
+    fn cut(ranges: &[(i64, i64)], packets: &[(i64, bool)]) -> Vec<Vec<i64>> {
+        let mut cuts = VideoCuts::new(&secs(ranges));
+        let mut copied = vec![Vec::new(); ranges.len()];
+        let mut begun = vec![false; ranges.len()];
+        for &(pts, is_key) in packets {
+            match cuts.select(Timestamp::from_secs(pts), is_key) {
+                VideoCut::Skip => {}
+                VideoCut::Begin(k) => {
+                    assert!(!begun[k] && is_key, "range {} begun twice or not at a keyframe", k);
+                    begun[k] = true;
+                    copied[k].push(pts);
+                }
+                VideoCut::Copy(k) => {
+                    assert!(begun[k], "range {} copied before it began", k);
+                    copied[k].push(pts);
+                }
+            }
+        }
+        copied
+    }
+
+    // I P P P I P P P I P P P, a keyframe every 4 s
+    fn without_b_frames() -> Vec<(i64, bool)> {
+        (0..12).map(|pts| (pts, pts % 4 == 0)).collect()
+    }
+
+    // I0 P3 B1 B2 P6 B4 B5 I9 B7 B8 P12 B10 B11 in decode order
+    fn with_b_frames() -> Vec<(i64, bool)> {
+        [0, 3, 1, 2, 6, 4, 5, 9, 7, 8, 12, 10, 11].map(|pts| (pts, pts % 9 == 0)).to_vec()
+    }
+
+    #[test]
+    fn normalizing_sorts_merges_and_drops_empty_ranges() {
+        let cases: [(Spans, Spans); 6] = [
+            (&[], &[]),
+            (&[(5, 8), (1, 3)], &[(1, 3), (5, 8)]),
+            (&[(1, 5), (3, 8)], &[(1, 8)]),
+            (&[(1, 3), (3, 5)], &[(1, 5)]),
+            (&[(1, 10), (2, 4), (12, 14)], &[(1, 10), (12, 14)]),
+            (&[(4, 4), (6, 2), (7, 9)], &[(7, 9)]),
+        ];
+        for (ranges, expected) in cases {
+            assert_eq!(normalize_ranges(&secs(ranges)), secs(expected), "{:?}", ranges);
+        }
+    }
+
+    #[test]
+    fn the_complement_is_what_the_deleted_ranges_leave_of_the_file() {
+        let cases: [(Spans, Spans); 9] = [
+            (&[(2, 4)], &[(0, 2), (4, 10)]),
+            // Overlapping
+            (&[(2, 5), (4, 7)], &[(0, 2), (7, 10)]),
+            // Unsorted
+            (&[(6, 8), (2, 4)], &[(0, 2), (4, 6), (8, 10)]),
+            // Touching
+            (&[(2, 4), (4, 6)], &[(0, 2), (6, 10)]),
+            // At zero
+            (&[(0, 3)], &[(3, 10)]),
+            // Running past the end, or starting after it
+            (&[(8, 12)], &[(0, 8)]),
+            (&[(12, 15)], &[(0, 10)]),
+            // The whole file
+            (&[(0, 10)], &[]),
+            (&[(0, 4), (3, 11)], &[]),
+        ];
+        for (deleted, kept) in cases {
+            assert_eq!(complement_ranges(&secs(deleted), Timestamp::from_secs(10)), secs(kept), "{:?}", deleted);
+        }
+    }
+
+    #[test]
+    fn deleting_nothing_keeps_the_whole_file() {
+        assert_eq!(complement_ranges(&[], Timestamp::from_secs(10)), secs(&[(0, 10)]));
+        assert_eq!(complement_ranges(&secs(&[(4, 4)]), Timestamp::from_secs(10)), secs(&[(0, 10)]));
+    }
+
+    #[test]
+    fn a_range_begins_at_its_first_keyframe() {
+        assert_eq!(cut(&[(2, 7)], &without_b_frames()), [vec![4, 5, 6]]);
+        assert_eq!(cut(&[(4, 7)], &without_b_frames()), [vec![4, 5, 6]]);
+    }
+
+    #[test]
+    fn a_range_without_a_keyframe_is_left_out() {
+        assert_eq!(cut(&[(1, 3), (5, 10)], &without_b_frames()), [vec![], vec![8, 9]]);
+    }
+
+    #[test]
+    fn every_range_begins_afresh() {
+        assert_eq!(cut(&[(0, 2), (4, 6), (8, 12)], &without_b_frames()), [vec![0, 1], vec![4, 5], vec![8, 9, 10, 11]]);
+    }
+
+    #[test]
+    fn b_frames_that_lean_on_a_frame_past_the_end_are_dropped() {
+        // P6 shows past the end, so B4 and B5, decoded after it, go too
+        assert_eq!(cut(&[(0, 5)], &with_b_frames()), [vec![0, 3, 1, 2]]);
+        // P3 is at the end: B1 and B2 refer to it
+        assert_eq!(cut(&[(0, 3)], &with_b_frames()), [vec![0]]);
+    }
+
+    #[test]
+    fn a_range_ending_past_a_reference_frame_keeps_its_b_frames() {
+        assert_eq!(cut(&[(0, 7)], &with_b_frames()), [vec![0, 3, 1, 2, 6, 4, 5]]);
+    }
+
+    #[test]
+    fn leading_b_frames_of_the_next_range_show_before_its_keyframe() {
+        // B7 and B8 are decoded after I9 but show before it
+        assert_eq!(cut(&[(0, 5), (7, 11)], &with_b_frames()), [vec![0, 3, 1, 2], vec![9]]);
+    }
+}
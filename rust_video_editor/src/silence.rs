@@ -0,0 +1,711 @@
+//! Silence detection and removal.
+
+use crate::error::{Error, Result};
+use crate::gate::Gate;
+use crate::loudness::{self, Level, StreamLevels, Windowing};
+use crate::probe;
+use crate::segment;
+use crate::timestamp::Timestamp;
+use ffmpeg_next as ffmpeg;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// How silence is detected and which parts around it are kept. Lengths are in
+/// milliseconds, as given on the command line and stored in the project.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SilenceSettings {
+    /// RMS level that audio must exceed to count as sound
+    pub threshold: Threshold,
+    /// Level in dBFS the audio must fall below to count as silence again; the
+    /// threshold when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub close_threshold: Option<f64>,
+    /// How quickly the measured level follows a rise
+    #[serde(default)]
+    pub attack_ms: u64,
+    /// How long sound is kept after the level falls below the close threshold
+    #[serde(default)]
+    pub hold_ms: u64,
+    /// How quickly the measured level follows a fall
+    #[serde(default)]
+    pub release_ms: u64,
+    /// Silences shorter than this are left in
+    #[serde(default)]
+    pub min_silence_ms: u64,
+    /// Sounds shorter than this are cut with the silence around them
+    #[serde(default = "default_min_keep_ms")]
+    pub min_keep_ms: u64,
+    /// Silence kept before each sound so its start is not clipped
+    #[serde(default)]
+    pub pad_before_ms: u64,
+    /// Silence kept after each sound so its tail is not clipped
+    #[serde(default)]
+    pub pad_after_ms: u64,
+    /// Kept parts closer together than this (after padding) are joined into one
+    #[serde(default = "default_merge_gap_ms")]
+    pub merge_gap_ms: u64,
+    /// Which audio streams are listened to
+    #[serde(default)]
+    pub streams: StreamPolicy,
+    /// Whether loud enough audio counts as sound, or only loud enough speech
+    #[serde(default)]
+    pub detection: Detection,
+    /// Window and hop the loudness is measured over
+    #[serde(flatten)]
+    pub windowing: Windowing,
+}
+
+pub const DEFAULT_MIN_KEEP_MS: u64 = 1000;
+pub const DEFAULT_MERGE_GAP_MS: u64 = 1000;
+
+fn default_min_keep_ms() -> u64 {
+    DEFAULT_MIN_KEEP_MS
+}
+
+fn default_merge_gap_ms() -> u64 {
+    DEFAULT_MERGE_GAP_MS
+}
+
+impl SilenceSettings {
+    /// The defaults for everything but the threshold.
+    pub fn new(threshold: Threshold) -> Self {
+        SilenceSettings {
+            threshold,
+            close_threshold: None,
+            attack_ms: 0,
+            hold_ms: 0,
+            release_ms: 0,
+            min_silence_ms: 0,
+            min_keep_ms: DEFAULT_MIN_KEEP_MS,
+            pad_before_ms: 0,
+            pad_after_ms: 0,
+            merge_gap_ms: DEFAULT_MERGE_GAP_MS,
+            streams: StreamPolicy::Any,
+            detection: Detection::Energy,
+            windowing: Windowing::default(),
+        }
+    }
+
+    /// The noise gate these settings describe, opening at `threshold_db` (the
+    /// threshold itself, or the one derived for an automatic threshold).
+    pub fn gate(&self, threshold_db: f64) -> Result<Gate> {
+        let close_db = self.close_threshold.unwrap_or(threshold_db);
+        if close_db > threshold_db {
+            return Err(Error::invalid(format!(
+                "The close threshold ({} dB) must not be above the threshold ({} dB)",
+                close_db, threshold_db
+            )));
+        }
+        Ok(Gate {
+            open_db: threshold_db,
+            close_db,
+            attack: Timestamp::from_millis(self.attack_ms),
+            hold: Timestamp::from_millis(self.hold_ms),
+            release: Timestamp::from_millis(self.release_ms),
+        })
+    }
+}
+
+/// The parts of `input` that silence removal keeps.
+pub fn keep_intervals(input: &str, settings: &SilenceSettings) -> Result<Vec<(Timestamp, Timestamp)>> {
+    analyze(input, settings).map(|analysis| analysis.keep)
+}
+
+/// A threshold derived from a recording's loudness distribution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoThreshold {
+    pub threshold_db: f64,
+    /// Level that 10% of the recording is quieter than
+    pub noise_floor_db: f64,
+    /// Level that 5% of the recording is louder than
+    pub speech_db: f64,
+}
+
+/// Levels below this are treated as this, so digital silence does not drag the
+/// noise floor to negative infinity.
+pub const FLOOR_DB: f64 = -120.0;
+
+/// Derive a threshold `margin_db` above the noise floor of `levels`, but never above
+/// the midpoint between the noise floor and the speech level. None without audio.
+pub fn auto_threshold(levels: &[Level], margin_db: f64) -> Option<AutoThreshold> {
+    let mut weighted: Vec<(f64, i64)> = levels
+        .iter()
+        .map(|level| {
+            let db = loudness::mean_square_db(level.power).max(FLOOR_DB);
+            (db, (level.end - level.start).as_micros())
+        })
+        .filter(|&(_, duration)| duration > 0)
+        .collect();
+    if weighted.is_empty() {
+        return None;
+    }
+    weighted.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total: i64 = weighted.iter().map(|&(_, duration)| duration).sum();
+    // The level below which `fraction` of the time is spent
+    let percentile = |fraction: f64| {
+        let target = (total as f64 * fraction) as i64;
+        let mut elapsed = 0;
+        for &(db, duration) in &weighted {
+            elapsed += duration;
+            if elapsed > target {
+                return db;
+            }
+        }
+        weighted[weighted.len() - 1].0
+    };
+    let noise_floor_db = percentile(0.10);
+    let speech_db = percentile(0.95);
+    let threshold_db = (noise_floor_db + margin_db).min((noise_floor_db + speech_db) / 2.0);
+    Some(AutoThreshold {
+        threshold_db,
+        noise_floor_db,
+        speech_db,
+    })
+}
+
+/// Turn raw noisy intervals into the parts to keep: bridge silences shorter than
+/// `min_silence_ms`, drop sounds shorter than `min_keep_ms`, pad what is left (within
+/// the source's `duration`) and join parts within `merge_gap_ms` of each other.
+pub fn shape_intervals(
+    mut intervals: Vec<(Timestamp, Timestamp)>,
+    settings: &SilenceSettings,
+    duration: Timestamp,
+) -> Vec<(Timestamp, Timestamp)> {
+    intervals.retain(|(s, e)| e > s);
+    intervals.sort();
+    let bridged = merge_within(intervals, Timestamp::from_millis(settings.min_silence_ms));
+
+    let min_keep = Timestamp::from_millis(settings.min_keep_ms);
+    let pad_before = Timestamp::from_millis(settings.pad_before_ms);
+    let pad_after = Timestamp::from_millis(settings.pad_after_ms);
+    let padded = bridged
+        .into_iter()
+        .filter(|&(s, e)| e - s >= min_keep)
+        .map(|(s, e)| ((s - pad_before).max(Timestamp::default()), (e + pad_after).min(duration.max(e))))
+        .collect();
+    merge_within(padded, Timestamp::from_millis(settings.merge_gap_ms))
+}
+
+// Merge sorted intervals that overlap or are at most `gap` apart.
+fn merge_within(intervals: Vec<(Timestamp, Timestamp)>, gap: Timestamp) -> Vec<(Timestamp, Timestamp)> {
+    let mut merged: Vec<(Timestamp, Timestamp)> = Vec::new();
+    for (s, e) in intervals {
+        if let Some((_, last_e)) = merged.last_mut()
+            && s <= *last_e + gap
+        {
+            *last_e = (*last_e).max(e);
+            continue;
+        }
+        merged.push((s, e));
+    }
+    merged
+}
+
+/// Keep the noisy parts of `input`, streamed straight from the source into `output`
+/// (see `segment::write_segments` for `audio_fade`). Returns the analysis the cut
+/// followed and the length written.
+pub fn cut_noisy_segments(
+    input: &str,
+    settings: &SilenceSettings,
+    output: &str,
+    audio_fade: Option<Timestamp>,
+) -> Result<(Analysis, Timestamp)> {
+    let analysis = analyze(input, settings)?;
+    if analysis.keep.is_empty() {
+        return Err(Error::empty_output(output, "no non-silent segments found"));
+    }
+    let written = segment::write_segments(input, &analysis.keep, output, audio_fade)?;
+    Ok((analysis, written))
+}
+
+/// The level audio must exceed to count as sound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    /// A fixed RMS level in dBFS
+    Db(f64),
+    /// Derived from each recording: `margin_db` above its noise floor
+    Auto { margin_db: f64 },
+}
+
+pub const DEFAULT_AUTO_MARGIN_DB: f64 = 10.0;
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Threshold::Db(db) => write!(f, "{}", db),
+            Threshold::Auto { margin_db } => write!(f, "auto+{}", margin_db),
+        }
+    }
+}
+
+// Accepts a dBFS level like `-40`, `auto` or `auto+N` for N dB above the noise floor.
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("invalid threshold '{}': expected dBFS, auto or auto+DB", s);
+        match s.strip_prefix("auto") {
+            Some("") => Ok(Threshold::Auto {
+                margin_db: DEFAULT_AUTO_MARGIN_DB,
+            }),
+            Some(margin) => {
+                let margin_db = margin.strip_prefix('+').ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+                Ok(Threshold::Auto { margin_db })
+            }
+            None => s.parse().map(Threshold::Db).map_err(|_| invalid()),
+        }
+    }
+}
+
+// A fixed threshold is stored as a plain number, as project files always did.
+impl Serialize for Threshold {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Threshold::Db(db) => serializer.serialize_f64(*db),
+            Threshold::Auto { .. } => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Threshold {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Db(f64),
+            Text(String),
+        }
+        match Stored::deserialize(deserializer)? {
+            Stored::Db(db) => Ok(Threshold::Db(db)),
+            Stored::Text(text) => text.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// Which audio streams decide whether a moment is silent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamPolicy {
+    /// Sound on any stream keeps the moment
+    #[default]
+    Any,
+    /// Only sound on every stream keeps it
+    All,
+    /// Only this stream (by container index) is listened to
+    Stream(usize),
+    /// The streams are mixed into one, sample by sample, and the mix is listened to:
+    /// the same sound on several streams adds up, and sound that cancels out in the
+    /// mix is silence
+    Sum,
+}
+
+impl StreamPolicy {
+    // Streams to decode, or None for every audio stream.
+    fn streams(self) -> Option<Vec<usize>> {
+        match self {
+            StreamPolicy::Stream(index) => Some(vec![index]),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StreamPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamPolicy::Any => write!(f, "any"),
+            StreamPolicy::All => write!(f, "all"),
+            StreamPolicy::Stream(index) => write!(f, "{}", index),
+            StreamPolicy::Sum => write!(f, "sum"),
+        }
+    }
+}
+
+// Accepts `any`, `all`, `sum` or a stream index.
+impl FromStr for StreamPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "any" => Ok(StreamPolicy::Any),
+            "all" => Ok(StreamPolicy::All),
+            "sum" => Ok(StreamPolicy::Sum),
+            index => index
+                .parse()
+                .map(StreamPolicy::Stream)
+                .map_err(|_| format!("invalid stream policy '{}': expected any, all, sum or a stream index", s)),
+        }
+    }
+}
+
+/// What counts as sound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Detection {
+    /// Any audio above the threshold
+    #[default]
+    Energy,
+    /// Audio above the threshold that voice activity detection also hears as speech:
+    /// mostly in the speech band, neither hiss nor clicks, and rising and falling from
+    /// syllable to syllable. Steady tones, hum and stationary noise count as silence;
+    /// music may still count as speech
+    Vad,
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Detection::Energy => write!(f, "energy"),
+            Detection::Vad => write!(f, "vad"),
+        }
+    }
+}
+
+impl FromStr for Detection {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "energy" => Ok(Detection::Energy),
+            "vad" => Ok(Detection::Vad),
+            _ => Err(format!("invalid detector '{}': expected energy or vad", s)),
+        }
+    }
+}
+
+impl Serialize for StreamPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for StreamPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Everything silence detection found in one input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// The level the gate opened at: the threshold itself, or the derived one
+    pub threshold_db: f64,
+    /// How the threshold was derived, for an automatic one
+    pub auto: Option<AutoThreshold>,
+    /// The combined loudness timeline the gate ran over
+    pub levels: Vec<Level>,
+    /// Where the gate was open
+    pub noisy: Vec<(Timestamp, Timestamp)>,
+    /// The parts silence removal keeps, after bridging, padding and merging
+    pub keep: Vec<(Timestamp, Timestamp)>,
+}
+
+/// Measure `input` over the settings' windows, with the audio streams chosen and
+/// combined by their policy, and run the settings' gate over it. All streams are
+/// analysed in one pass.
+pub fn analyze(input: &str, settings: &SilenceSettings) -> Result<Analysis> {
+    let measured = loudness::measure(
+        input,
+        settings.streams.streams().as_deref(),
+        &settings.windowing,
+        settings.detection == Detection::Vad,
+        settings.streams == StreamPolicy::Sum,
+    )?;
+    let levels = combine(&measured, settings.streams);
+    let (threshold_db, auto) = match settings.threshold {
+        Threshold::Db(db) => (db, None),
+        Threshold::Auto { margin_db } => {
+            let auto = auto_threshold(&levels, margin_db)
+                .ok_or_else(|| Error::unsupported(input, None, "no audio to derive a threshold from"))?;
+            (auto.threshold_db, Some(auto))
+        }
+    };
+    let noisy = settings.gate(threshold_db)?.open_intervals(&levels);
+    // Padding stops at the end of the source, or of its audio when the container
+    // does not know its duration
+    let audio_end = levels.last().map_or(Timestamp::default(), |level| level.end);
+    let duration = probe::probe_duration(input).unwrap_or(audio_end);
+    let keep = shape_intervals(noisy.clone(), settings, duration);
+    Ok(Analysis {
+        threshold_db,
+        auto,
+        levels,
+        noisy,
+        keep,
+    })
+}
+
+/// Intervals of `input` where the settings' gate is open.
+pub fn find_noisy_intervals(input: &str, settings: &SilenceSettings) -> Result<Vec<(Timestamp, Timestamp)>> {
+    analyze(input, settings).map(|analysis| analysis.noisy)
+}
+
+/// Combine the streams of `measured` into one loudness timeline under `policy`:
+/// the loudest stream for `any`, the quietest for `all`. For `sum`, `measure` has
+/// mixed the streams into one already, which is taken as it is. A stream with no level
+/// at some moment (it has ended, or has a gap) is silent there.
+pub fn combine(measured: &[StreamLevels], policy: StreamPolicy) -> Vec<Level> {
+    let measured: Vec<&StreamLevels> = match policy {
+        StreamPolicy::Stream(index) => measured.iter().filter(|levels| levels.stream == index).collect(),
+        _ => measured.iter().collect(),
+    };
+    // Every point where some stream's level changes
+    let mut bounds: Vec<Timestamp> = measured
+        .iter()
+        .flat_map(|levels| levels.levels.iter().flat_map(|level| [level.start, level.end]))
+        .collect();
+    bounds.sort();
+    bounds.dedup();
+
+    // Per stream: index of the first level that has not ended yet
+    let mut cursors = vec![0; measured.len()];
+    let mut combined = Vec::new();
+    for pair in bounds.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let mut covered = false;
+        let (powers, speech): (Vec<f64>, Vec<bool>) = measured
+            .iter()
+            .zip(cursors.iter_mut())
+            .map(|(levels, cursor)| {
+                while levels.levels.get(*cursor).is_some_and(|level| level.end <= start) {
+                    *cursor += 1;
+                }
+                let level = levels.levels.get(*cursor).filter(|level| level.start <= start);
+                covered |= level.is_some();
+                level.map_or((0.0, false), |level| (level.power, level.speech))
+            })
+            .unzip();
+        if !covered {
+            continue;
+        }
+        let power = match policy {
+            StreamPolicy::Any | StreamPolicy::Stream(_) | StreamPolicy::Sum => {
+                powers.iter().copied().fold(0.0, f64::max)
+            }
+            StreamPolicy::All => powers.iter().copied().fold(f64::INFINITY, f64::min),
+        };
+        let speech = match policy {
+            StreamPolicy::All => speech.iter().all(|&speech| speech),
+            _ => speech.iter().any(|&speech| speech),
+        };
+        combined.push(Level {
+            start,
+            end,
+            power,
+            speech,
+        });
+    }
+    combined
+}
+
+/// Whether the RMS level of `audio_frame` is above `threshold` dBFS, for any sample format.
+pub fn is_noisy(audio_frame: &ffmpeg::frame::Audio, threshold: f64) -> bool {
+    loudness::rms_db(audio_frame).is_some_and(|db| db > threshold)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(spans: &[(i64, i64)]) -> Vec<(Timestamp, Timestamp)> {
+        spans.iter().map(|&(start, end)| (Timestamp::ms(start), Timestamp::ms(end))).collect()
+    }
+
+    // Consecutive levels, each `(milliseconds, dBFS)`.
+    fn levels(parts: &[(i64, f64)]) -> Vec<Level> {
+        let mut start = 0;
+        parts
+            .iter()
+            .map(|&(duration, db)| {
+                start += duration;
+                Level {
+                    start: Timestamp::ms(start - duration),
+                    end: Timestamp::ms(start),
+                    power: 10f64.powf(db / 10.0),
+                    speech: true,
+                }
+            })
+            .collect()
+    }
+
+    fn assert_db(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} dB, expected {} dB", actual, expected);
+    }
+
+    #[test]
+    fn auto_threshold_sits_above_the_noise_floor() {
+        let mut parts = vec![(10, -60.0); 90];
+        parts.extend(vec![(10, -20.0); 10]);
+        let auto = auto_threshold(&levels(&parts), 6.0).unwrap();
+        assert_db(auto.noise_floor_db, -60.0);
+        assert_db(auto.speech_db, -20.0);
+        assert_db(auto.threshold_db, -54.0);
+    }
+
+    #[test]
+    fn auto_threshold_stays_below_the_midpoint() {
+        let mut parts = vec![(10, -60.0); 90];
+        parts.extend(vec![(10, -20.0); 10]);
+        assert_db(auto_threshold(&levels(&parts), 30.0).unwrap().threshold_db, -40.0);
+    }
+
+    #[test]
+    fn auto_threshold_weighs_levels_by_duration() {
+        let mut parts = vec![(900, -50.0)];
+        parts.extend(vec![(10, -10.0); 10]);
+        let auto = auto_threshold(&levels(&parts), 10.0).unwrap();
+        assert_db(auto.noise_floor_db, -50.0);
+        assert_db(auto.speech_db, -10.0);
+        assert_db(auto.threshold_db, -40.0);
+    }
+
+    #[test]
+    fn auto_threshold_floors_digital_silence() {
+        let auto = auto_threshold(&levels(&[(100, f64::NEG_INFINITY)]), 6.0).unwrap();
+        assert_db(auto.noise_floor_db, FLOOR_DB);
+        assert_db(auto.threshold_db, FLOOR_DB);
+    }
+
+    #[test]
+    fn auto_threshold_needs_audio() {
+        assert_eq!(auto_threshold(&[], 6.0), None);
+        assert_eq!(auto_threshold(&levels(&[(0, -20.0), (0, -60.0)]), 6.0), None);
+    }
+
+    #[test]
+    fn merge_within_joins_close_intervals() {
+        type Spans = &'static [(i64, i64)];
+        let cases: [(Spans, i64, Spans); 6] = [
+            (&[], 100, &[]),
+            (&[(0, 100), (50, 150)], 0, &[(0, 150)]),
+            (&[(0, 100), (100, 200)], 0, &[(0, 200)]),
+            (&[(0, 100), (200, 300)], 100, &[(0, 300)]),
+            (&[(0, 100), (201, 300)], 100, &[(0, 100), (201, 300)]),
+            (&[(0, 500), (100, 200), (600, 700)], 100, &[(0, 700)]),
+        ];
+        for (intervals, gap, expected) in cases {
+            assert_eq!(
+                merge_within(spans(intervals), Timestamp::ms(gap)),
+                spans(expected),
+                "{:?} within {}",
+                intervals,
+                gap
+            );
+        }
+    }
+
+    // Settings that only shape what is given.
+    fn shaping() -> SilenceSettings {
+        SilenceSettings {
+            min_keep_ms: 0,
+            merge_gap_ms: 0,
+            ..SilenceSettings::new(Threshold::Db(-40.0))
+        }
+    }
+
+    // Shape `intervals` of a 10 s source
+    fn shape(intervals: &[(i64, i64)], settings: &SilenceSettings) -> Vec<(Timestamp, Timestamp)> {
+        shape_intervals(spans(intervals), settings, Timestamp::ms(10_000))
+    }
+
+    #[test]
+    fn shape_intervals_sorts_and_drops_empty_intervals() {
+        let shaped = shape(&[(500, 600), (300, 300), (0, 100), (800, 700)], &shaping());
+        assert_eq!(shaped, spans(&[(0, 100), (500, 600)]));
+    }
+
+    #[test]
+    fn shape_intervals_bridges_short_silences_before_dropping_short_sounds() {
+        let settings = SilenceSettings {
+            min_silence_ms: 300,
+            min_keep_ms: 1000,
+            ..shaping()
+        };
+        // 600 ms sounds 200 ms apart make one 1400 ms sound; the lone 600 ms one goes
+        let shaped = shape(&[(0, 600), (800, 1400), (3000, 3600)], &settings);
+        assert_eq!(shaped, spans(&[(0, 1400)]));
+    }
+
+    #[test]
+    fn shape_intervals_pads_then_merges() {
+        let settings = SilenceSettings {
+            pad_before_ms: 200,
+            pad_after_ms: 300,
+            merge_gap_ms: 100,
+            ..shaping()
+        };
+        let shaped = shape(&[(100, 1000), (1500, 2000), (3000, 4000)], &settings);
+        // The first pad stops at zero; 1300 and 1300 touch, 2300 and 2800 do not
+        assert_eq!(shaped, spans(&[(0, 2300), (2800, 4300)]));
+    }
+
+    #[test]
+    fn shape_intervals_pads_no_further_than_the_source() {
+        let settings = SilenceSettings {
+            pad_after_ms: 500,
+            ..shaping()
+        };
+        let shaped = shape(&[(1000, 2000), (9800, 9900)], &settings);
+        assert_eq!(shaped, spans(&[(1000, 2500), (9800, 10_000)]));
+        // Sound measured past the container's duration is kept, just not padded
+        let shaped = shape(&[(9800, 10_200)], &settings);
+        assert_eq!(shaped, spans(&[(9800, 10_200)]));
+    }
+
+    #[test]
+    fn shape_intervals_with_the_defaults() {
+        let settings = SilenceSettings::new(Threshold::Db(-40.0));
+        let shaped = shape(&[(0, 500), (1000, 2500), (3000, 4500), (7000, 9000)], &settings);
+        assert_eq!(shaped, spans(&[(1000, 4500), (7000, 9000)]));
+    }
+
+    fn stream(stream: usize, parts: &[(i64, i64, f64)]) -> StreamLevels {
+        StreamLevels {
+            stream,
+            levels: parts
+                .iter()
+                .map(|&(start, end, power)| Level {
+                    start: Timestamp::ms(start),
+                    end: Timestamp::ms(end),
+                    power,
+                    speech: power > 0.0,
+                })
+                .collect(),
+        }
+    }
+
+    fn combined(policy: StreamPolicy) -> Vec<(i64, i64, f64)> {
+        let measured = [
+            stream(1, &[(0, 10, 0.5), (10, 20, 0.25)]),
+            stream(2, &[(5, 15, 0.125)]),
+        ];
+        combine(&measured, policy)
+            .iter()
+            .map(|level| (level.start.as_micros() / 1000, level.end.as_micros() / 1000, level.power))
+            .collect()
+    }
+
+    #[test]
+    fn combine_applies_the_stream_policy() {
+        let cases = [
+            (StreamPolicy::Any, [0.5, 0.5, 0.25, 0.25]),
+            (StreamPolicy::All, [0.0, 0.125, 0.125, 0.0]),
+        ];
+        for (policy, powers) in cases {
+            let expected: Vec<(i64, i64, f64)> =
+                [(0, 5), (5, 10), (10, 15), (15, 20)].iter().zip(powers).map(|(&(s, e), p)| (s, e, p)).collect();
+            assert_eq!(combined(policy), expected, "{}", policy);
+        }
+        assert_eq!(combined(StreamPolicy::Stream(2)), vec![(5, 15, 0.125)]);
+        assert_eq!(combined(StreamPolicy::Stream(3)), vec![]);
+    }
+
+    #[test]
+    fn stream_policies_read_back() {
+        for policy in [StreamPolicy::Any, StreamPolicy::All, StreamPolicy::Sum, StreamPolicy::Stream(3)] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("mix".parse::<StreamPolicy>().is_err());
+    }
+}
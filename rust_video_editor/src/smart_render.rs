@@ -0,0 +1,540 @@
+use crate::error::{self, Error, Result};
+use crate::keyframe::{self, StreamKeyframes};
+use crate::timestamp::Timestamp;
+use ffmpeg::{codec, decoder, encoder, format, frame, media, picture, Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use std::collections::{HashMap, HashSet};
+
+/// How one GOP (a keyframe up to the next keyframe) of the video stream is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GopMode {
+    // Entirely inside the range: stream copy the packets untouched
+    Copy,
+    // Straddles a cut point: decode it and re-encode only the frames inside the range
+    Reencode,
+    // Entirely outside the range
+    Skip,
+}
+
+// How the GOP starting at `keyframe` is written when cutting `start_ts..end_ts`.
+fn mode_for_gop(layout: &StreamKeyframes, keyframe: i64, start_ts: i64, end_ts: i64, splice: Splice) -> GopMode {
+    let gop_end = layout.gop_end(keyframe);
+    if gop_end <= start_ts || keyframe >= end_ts {
+        GopMode::Skip
+    } else if keyframe >= start_ts && gop_end <= end_ts && splice != Splice::ReencodeAll {
+        GopMode::Copy
+    } else {
+        GopMode::Reencode
+    }
+}
+
+/// How re-encoded GOPs join the copied ones in the output stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Splice {
+    // The encoder's packets already fit the source stream
+    AsIs,
+    // The encoder's Annex-B packets must be rewritten with NAL units prefixed by
+    // their length in this many bytes, as in the source's avcC (MP4) stream
+    LengthPrefixed(usize),
+    // The encoder's parameter sets differ from the source's, so its packets cannot
+    // share a stream with copied ones: the whole range is re-encoded and the output
+    // stream takes the encoder's extradata
+    ReencodeAll,
+}
+
+// How the re-encoded GOPs can join the copied ones, given the extradata of the
+// source stream and of the encoder.
+fn splice_for(codec: codec::Id, source: &[u8], encoder: &[u8]) -> Splice {
+    if codec != codec::Id::H264 {
+        return if source == encoder { Splice::AsIs } else { Splice::ReencodeAll };
+    }
+    match (h264_configuration(source), h264_configuration(encoder)) {
+        (Some((splice, mut source_sets)), Some((_, mut encoder_sets))) => {
+            source_sets.sort_unstable();
+            encoder_sets.sort_unstable();
+            // Streams without out-of-band parameter sets (MPEG-TS) carry them in-band,
+            // where the encoder's would clash with the source's ids
+            if !source_sets.is_empty() && source_sets == encoder_sets { splice } else { Splice::ReencodeAll }
+        }
+        _ => Splice::ReencodeAll,
+    }
+}
+
+// How an H.264 stream with `extradata` frames its packets, and its parameter sets.
+// None when the extradata is malformed.
+fn h264_configuration(extradata: &[u8]) -> Option<(Splice, Vec<&[u8]>)> {
+    if extradata.first() == Some(&1) {
+        let (size, sets) = avcc(extradata)?;
+        return Some((Splice::LengthPrefixed(size), sets));
+    }
+    let sets = annex_b_units(extradata).into_iter().filter(|unit| is_parameter_set(unit)).collect();
+    Some((Splice::AsIs, sets))
+}
+
+// The NAL length size and the parameter sets (SPS then PPS) of an avcC record.
+fn avcc(record: &[u8]) -> Option<(usize, Vec<&[u8]>)> {
+    if record.len() < 6 || record[0] != 1 {
+        return None;
+    }
+    let size = (record[4] & 3) as usize + 1;
+    let mut sets = Vec::new();
+    let mut rest = &record[5..];
+    // The SPS count sits in the low five bits, the PPS count takes a whole byte
+    for mask in [0x1f, 0xff] {
+        let (&count, tail) = rest.split_first()?;
+        rest = tail;
+        for _ in 0..count & mask {
+            let length = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+            sets.push(rest.get(2..2 + length)?);
+            rest = &rest[2 + length..];
+        }
+    }
+    Some((size, sets))
+}
+
+// The NAL units of start-code delimited (Annex-B) `data`.
+fn annex_b_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut codes = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            codes.push(i);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    codes
+        .iter()
+        .enumerate()
+        .map(|(n, &code)| {
+            let mut unit = &data[code + 3..codes.get(n + 1).copied().unwrap_or(data.len())];
+            // Trailing zeros belong to the next four-byte start code
+            while let [rest @ .., 0] = unit {
+                unit = rest;
+            }
+            unit
+        })
+        .filter(|unit| !unit.is_empty())
+        .collect()
+}
+
+fn is_parameter_set(unit: &[u8]) -> bool {
+    matches!(unit.first().map(|header| header & 0x1f), Some(7 | 8))
+}
+
+// `data` with its Annex-B NAL units prefixed by their length in `size` bytes instead.
+// Parameter sets are dropped, since the stream's extradata already has them. Data
+// that is not Annex-B is returned unchanged.
+fn to_length_prefixed(data: &[u8], size: usize) -> Vec<u8> {
+    if !data.starts_with(&[0, 0, 1]) && !data.starts_with(&[0, 0, 0, 1]) {
+        return data.to_vec();
+    }
+    let mut converted = Vec::with_capacity(data.len());
+    for unit in annex_b_units(data).into_iter().filter(|unit| !is_parameter_set(unit)) {
+        converted.extend_from_slice(&(unit.len() as u64).to_be_bytes()[8 - size..]);
+        converted.extend_from_slice(unit);
+    }
+    converted
+}
+
+fn extradata(parameters: &codec::Parameters) -> &[u8] {
+    // SAFETY: `extradata` is null or points at `extradata_size` bytes owned by the parameters
+    unsafe {
+        let raw = &*parameters.as_ptr();
+        if raw.extradata.is_null() || raw.extradata_size <= 0 {
+            return &[];
+        }
+        std::slice::from_raw_parts(raw.extradata, raw.extradata_size as usize)
+    }
+}
+
+// Decodes partial GOPs and re-encodes the frames inside the cut range with an
+// encoder configured like the source stream.
+struct GopReencoder<'a> {
+    input: &'a str,
+    output: &'a str,
+    decoder: decoder::Video,
+    encoder: Option<encoder::Video>,
+    time_base: Rational,
+    start_ts: i64,
+    end_ts: i64,
+    delay: i64,
+    video_index: usize,
+    out_index: usize,
+    splice: Splice,
+}
+
+impl GopReencoder<'_> {
+    fn open_encoder(&self) -> Result<encoder::Video> {
+        open_matching_encoder(&self.decoder, self.time_base)
+            .map_err(|e| Error::codec(self.output, Some(self.out_index), e))?
+            .ok_or_else(|| {
+                let reason = format!("no encoder available for {:?}", self.decoder.id());
+                Error::unsupported(self.input, Some(self.video_index), reason)
+            })
+    }
+
+    fn begin_gop(&mut self) -> Result<()> {
+        self.encoder = Some(self.open_encoder()?);
+        Ok(())
+    }
+
+    fn send_packet(&mut self, packet: &Packet, output: &mut format::context::Output) -> Result<usize> {
+        self.decoder.send_packet(packet).map_err(|e| self.decode_error(e))?;
+        self.receive_frames(output)
+    }
+
+    // Flush the current GOP through the decoder and encoder and get ready for the next one.
+    fn end_gop(&mut self, output: &mut format::context::Output) -> Result<usize> {
+        if self.encoder.is_none() {
+            return Ok(0);
+        }
+        self.decoder.send_eof().map_err(|e| self.decode_error(e))?;
+        let mut written = self.receive_frames(output)?;
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.send_eof().map_err(|e| Error::codec(self.output, Some(self.out_index), e))?;
+        }
+        written += self.receive_packets(output)?;
+        self.encoder = None;
+        self.decoder.flush();
+        Ok(written)
+    }
+
+    fn receive_frames(&mut self, output: &mut format::context::Output) -> Result<usize> {
+        let mut written = 0;
+        let mut frame = frame::Video::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            let Some(pts) = frame.timestamp() else { continue };
+            if pts < self.start_ts || pts >= self.end_ts {
+                continue;
+            }
+            frame.set_pts(Some(pts));
+            frame.set_kind(picture::Type::None);
+            if let Some(encoder) = self.encoder.as_mut() {
+                encoder
+                    .send_frame(&frame)
+                    .map_err(|e| Error::codec(self.output, Some(self.out_index), e))?;
+            }
+            written += self.receive_packets(output)?;
+        }
+        Ok(written)
+    }
+
+    fn receive_packets(&mut self, output: &mut format::context::Output) -> Result<usize> {
+        let Some(encoder) = self.encoder.as_mut() else { return Ok(0) };
+        let mut written = 0;
+        loop {
+            let mut packet = Packet::empty();
+            if encoder.receive_packet(&mut packet).is_err() {
+                break;
+            }
+            // Give re-encoded packets the source's decode delay so their dts
+            // stays below the dts of the copied GOP that follows them.
+            if let Some(pts) = packet.pts() {
+                packet.set_dts(Some(pts - self.delay));
+            }
+            if let Splice::LengthPrefixed(size) = self.splice
+                && let Some(data) = packet.data()
+            {
+                let mut converted = Packet::copy(&to_length_prefixed(data, size));
+                converted.set_pts(packet.pts());
+                converted.set_dts(packet.dts());
+                converted.set_duration(packet.duration());
+                converted.set_flags(packet.flags());
+                packet = converted;
+            }
+            write_rebased(packet, self.out_index, self.start_ts, self.time_base, output, self.output)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    fn decode_error(&self, source: ffmpeg::Error) -> Error {
+        Error::codec(self.input, Some(self.video_index), source)
+    }
+}
+
+// An encoder for `decoder`'s codec set up like the source stream, or None when
+// FFmpeg has no encoder for it.
+fn open_matching_encoder(
+    decoder: &decoder::Video,
+    time_base: Rational,
+) -> Result<Option<encoder::Video>, ffmpeg::Error> {
+    let Some(codec) = encoder::find(decoder.id()) else { return Ok(None) };
+    let mut encoder = codec::context::Context::new_with_codec(codec).encoder().video()?;
+    encoder.set_width(decoder.width());
+    encoder.set_height(decoder.height());
+    encoder.set_format(decoder.format());
+    encoder.set_aspect_ratio(decoder.aspect_ratio());
+    encoder.set_frame_rate(decoder.frame_rate());
+    encoder.set_time_base(time_base);
+    encoder.set_colorspace(decoder.color_space());
+    encoder.set_color_range(decoder.color_range());
+    if decoder.bit_rate() > 0 {
+        encoder.set_bit_rate(decoder.bit_rate());
+    }
+    // B-frames would reorder the re-encoded packets past the copied ones
+    encoder.set_max_b_frames(0);
+    // Keep the parameter sets out of band, where they can be checked against the
+    // source's before any re-encoded packet lands next to a copied one
+    encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    encoder.open_as(codec).map(Some)
+}
+
+fn write_rebased(
+    mut packet: Packet,
+    out_index: usize,
+    offset: i64,
+    time_base: Rational,
+    output: &mut format::context::Output,
+    path: &str,
+) -> Result<()> {
+    packet.set_pts(packet.pts().map(|ts| ts - offset));
+    packet.set_dts(packet.dts().map(|ts| ts - offset));
+    let out_time_base = output.stream(out_index).expect("Output stream not found").time_base();
+    packet.set_stream(out_index);
+    packet.set_position(-1);
+    packet.rescale_ts(time_base, out_time_base);
+    packet.write_interleaved(output).map_err(|e| Error::mux(path, e))
+}
+
+/// Cut `start..end` frame-accurately: GOPs fully inside the range are stream copied and
+/// only the partial GOPs at either end are decoded and re-encoded. When the encoder
+/// cannot reproduce the source's parameter sets, the whole range is re-encoded.
+pub fn smart_cut(input: &str, start: Timestamp, end: Timestamp, output: &str) -> Result<()> {
+    error::init()?;
+    let mut input_file = format::input(&input).map_err(|e| Error::open(input, e))?;
+    let video_index = input_file
+        .streams()
+        .best(media::Type::Video)
+        .map(|stream| stream.index())
+        .ok_or_else(|| Error::unsupported(input, None, "no video stream to smart-render"))?;
+    let index = keyframe::index(input)?;
+    let layout = index.stream(video_index).expect("Video stream not indexed");
+
+    let mut output_file = format::output(&output).map_err(|e| Error::open(output, e))?;
+    let mut stream_mapping = HashMap::new();
+    let mut ts_bounds: HashMap<usize, (i64, i64)> = HashMap::new();
+    for (idx, stream) in input_file.streams().enumerate() {
+        let time_base = stream.time_base();
+        ts_bounds.insert(idx, (start.to_pts(time_base), end.to_pts(time_base)));
+        let codec_params = stream.parameters();
+        let mut out_stream = output_file.add_stream(codec_params.id()).map_err(|e| Error::mux(output, e))?;
+        out_stream.set_parameters(codec_params);
+        stream_mapping.insert(idx, out_stream.index());
+    }
+
+    let video_stream = input_file.stream(video_index).expect("Video stream not found");
+    let (video_start, video_end) = ts_bounds[&video_index];
+    let mut reencoder = GopReencoder {
+        input,
+        output,
+        decoder: codec::context::Context::from_parameters(video_stream.parameters())
+            .and_then(|ctx| ctx.decoder().video())
+            .map_err(|e| Error::codec(input, Some(video_index), e))?,
+        encoder: None,
+        time_base: video_stream.time_base(),
+        start_ts: video_start,
+        end_ts: video_end,
+        delay: layout.delay,
+        video_index,
+        out_index: stream_mapping[&video_index],
+        splice: Splice::ReencodeAll,
+    };
+    let encoder_parameters = codec::Parameters::from(&reencoder.open_encoder()?);
+    let source_parameters = video_stream.parameters();
+    reencoder.splice = splice_for(
+        source_parameters.id(),
+        extradata(&source_parameters),
+        extradata(&encoder_parameters),
+    );
+    if reencoder.splice == Splice::ReencodeAll {
+        let mut out_stream = output_file.stream_mut(reencoder.out_index).expect("Output stream not found");
+        out_stream.set_parameters(encoder_parameters);
+    }
+
+    output_file.write_header().map_err(|e| Error::mux(output, e))?;
+    keyframe::seek(&mut input_file, input, start)?;
+
+    let mut gop_mode = None;
+    let mut video_packets_written = 0;
+    let mut finished = HashSet::new();
+    for (stream, packet) in input_file.packets() {
+        let stream_index = stream.index();
+        let Some(&out_index) = stream_mapping.get(&stream_index) else { continue };
+        let (start_ts, end_ts) = ts_bounds[&stream_index];
+        // dts only grows, so once it passes the end nothing later in this stream is needed
+        if packet.dts().is_some_and(|dts| dts > end_ts) {
+            finished.insert(stream_index);
+            if finished.len() == stream_mapping.len() {
+                break;
+            }
+        }
+
+        if stream_index != video_index {
+            if let Some(pts) = packet.pts()
+                && (pts < start_ts || pts >= end_ts)
+            {
+                continue;
+            }
+            write_rebased(packet, out_index, start_ts, stream.time_base(), &mut output_file, output)?;
+            continue;
+        }
+
+        if packet.is_key()
+            && let Some(pts) = packet.pts()
+        {
+            video_packets_written += reencoder.end_gop(&mut output_file)?;
+            let mode = mode_for_gop(layout, pts, start_ts, end_ts, reencoder.splice);
+            if mode == GopMode::Reencode {
+                reencoder.begin_gop()?;
+            }
+            gop_mode = Some(mode);
+        }
+        match gop_mode {
+            Some(GopMode::Copy) => {
+                write_rebased(packet, out_index, start_ts, stream.time_base(), &mut output_file, output)?;
+                video_packets_written += 1;
+            }
+            Some(GopMode::Reencode) => {
+                video_packets_written += reencoder.send_packet(&packet, &mut output_file)?;
+            }
+            Some(GopMode::Skip) | None => {}
+        }
+    }
+    video_packets_written += reencoder.end_gop(&mut output_file)?;
+
+    if video_packets_written == 0 {
+        std::fs::remove_file(output).ok();
+        return Err(Error::empty_output(output, "no video frames fall inside the requested range"));
+    }
+    output_file.write_trailer().map_err(|e| Error::mux(output, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyframe::Keyframe;
+
+    // Keyframes every 100 ticks from 0 to 300, the last packet at 390
+    fn layout() -> StreamKeyframes {
+        StreamKeyframes {
+            stream: 0,
+            time_base: (1, 100),
+            keyframes: [0, 100, 200, 300].map(|pts| Keyframe { pts, position: None }).to_vec(),
+            last_pts: Some(390),
+            delay: 0,
+        }
+    }
+
+    fn modes(start_ts: i64, end_ts: i64, splice: Splice) -> Vec<GopMode> {
+        let layout = layout();
+        [0, 100, 200, 300].map(|keyframe| mode_for_gop(&layout, keyframe, start_ts, end_ts, splice)).to_vec()
+    }
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1f, 0xac];
+    const PPS: &[u8] = &[0x68, 0xee, 0x3c, 0x80];
+    const SEI: &[u8] = &[0x06, 0x05, 0x01, 0xff];
+
+    fn avcc_record(length_size: u8, sps: &[u8], pps: &[u8]) -> Vec<u8> {
+        let mut record = vec![1, 0x64, 0x00, 0x1f, 0xfc | (length_size - 1), 0xe1];
+        record.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        record.extend_from_slice(sps);
+        record.push(1);
+        record.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        record.extend_from_slice(pps);
+        record
+    }
+
+    fn annex_b(units: &[&[u8]]) -> Vec<u8> {
+        units.iter().flat_map(|unit| [&[0, 0, 0, 1][..], unit].concat()).collect()
+    }
+
+    #[test]
+    fn gops_inside_the_range_are_copied_and_those_outside_skipped() {
+        use GopMode::*;
+        assert_eq!(modes(100, 300, Splice::AsIs), [Skip, Copy, Copy, Skip]);
+    }
+
+    #[test]
+    fn a_range_starting_inside_a_gop_reencodes_only_that_gop() {
+        use GopMode::*;
+        assert_eq!(modes(150, 300, Splice::AsIs), [Skip, Reencode, Copy, Skip]);
+    }
+
+    #[test]
+    fn a_range_ending_inside_a_gop_reencodes_only_that_gop() {
+        use GopMode::*;
+        assert_eq!(modes(100, 250, Splice::AsIs), [Skip, Copy, Reencode, Skip]);
+    }
+
+    #[test]
+    fn a_range_inside_one_gop_reencodes_it_alone() {
+        use GopMode::*;
+        assert_eq!(modes(120, 180, Splice::AsIs), [Skip, Reencode, Skip, Skip]);
+    }
+
+    #[test]
+    fn the_last_gop_runs_to_just_past_the_last_packet() {
+        use GopMode::*;
+        assert_eq!(modes(300, 391, Splice::AsIs), [Skip, Skip, Skip, Copy]);
+        assert_eq!(modes(300, 390, Splice::AsIs), [Skip, Skip, Skip, Reencode]);
+    }
+
+    #[test]
+    fn mismatched_parameter_sets_reencode_every_gop_in_the_range() {
+        use GopMode::*;
+        assert_eq!(modes(100, 300, Splice::ReencodeAll), [Skip, Reencode, Reencode, Skip]);
+    }
+
+    #[test]
+    fn matching_parameter_sets_splice_into_an_avcc_stream() {
+        let source = avcc_record(4, SPS, PPS);
+        let encoder = annex_b(&[SPS, PPS, SEI]);
+        assert_eq!(splice_for(codec::Id::H264, &source, &encoder), Splice::LengthPrefixed(4));
+        let source = avcc_record(2, SPS, PPS);
+        assert_eq!(splice_for(codec::Id::H264, &source, &encoder), Splice::LengthPrefixed(2));
+    }
+
+    #[test]
+    fn matching_parameter_sets_splice_into_an_annex_b_stream() {
+        let source = annex_b(&[SPS, PPS]);
+        let encoder = annex_b(&[PPS, SPS]);
+        assert_eq!(splice_for(codec::Id::H264, &source, &encoder), Splice::AsIs);
+    }
+
+    #[test]
+    fn differing_or_missing_parameter_sets_reencode_the_whole_range() {
+        let encoder = annex_b(&[SPS, PPS]);
+        let other_pps: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb];
+        let cases: [&[u8]; 4] = [&avcc_record(4, SPS, other_pps), &[], &[1, 0x64], &annex_b(&[SPS])];
+        for source in cases {
+            assert_eq!(splice_for(codec::Id::H264, source, &encoder), Splice::ReencodeAll, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn other_codecs_splice_only_with_identical_extradata() {
+        assert_eq!(splice_for(codec::Id::MPEG2VIDEO, &[], &[]), Splice::AsIs);
+        assert_eq!(splice_for(codec::Id::MPEG2VIDEO, &[0, 0, 1, 0xb3], &[0, 0, 1, 0xb3]), Splice::AsIs);
+        assert_eq!(splice_for(codec::Id::HEVC, &[1, 2], &[1, 3]), Splice::ReencodeAll);
+    }
+
+    #[test]
+    fn annex_b_packets_become_length_prefixed_without_parameter_sets() {
+        let slice: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x21];
+        let packet = [&[0, 0, 0, 1][..], SPS, &[0, 0, 1], PPS, &[0, 0, 1], SEI, &[0, 0, 0, 1], slice].concat();
+        let expected = [&[0, 0, 0, 4][..], SEI, &[0, 0, 0, 5], slice].concat();
+        assert_eq!(to_length_prefixed(&packet, 4), expected);
+        let expected = [&[0, 4][..], SEI, &[0, 5], slice].concat();
+        assert_eq!(to_length_prefixed(&packet, 2), expected);
+    }
+
+    #[test]
+    fn packets_already_length_prefixed_are_left_alone() {
+        let packet = [&[0, 0, 0, 5][..], &[0x65, 0x88, 0x84, 0x00, 0x21]].concat();
+        assert_eq!(to_length_prefixed(&packet, 4), packet);
+    }
+}
@@ -0,0 +1,16 @@
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+
+/// One piece of the timeline: `start..end` of a source file, in source time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clip {
+    pub source: String,
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+impl Clip {
+    pub fn duration(&self) -> Timestamp {
+        self.end - self.start
+    }
+}
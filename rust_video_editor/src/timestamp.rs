@@ -0,0 +1,376 @@
+use ffmpeg_next::Rational;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::{Add, Sub};
+use std::str::FromStr;
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_MILLI: i64 = 1_000;
+
+/// A point in time (or a duration) in microseconds. This is the same unit as
+/// AV_TIME_BASE, so values can be handed straight to container-level seeks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub fn from_micros(micros: i64) -> Self {
+        Timestamp(micros)
+    }
+
+    /// `ms` milliseconds, as the CLI and settings files give lengths.
+    pub fn from_millis(ms: u64) -> Self {
+        Timestamp(ms.saturating_mul(MICROS_PER_MILLI as u64).min(i64::MAX as u64) as i64)
+    }
+
+    pub fn from_secs(secs: i64) -> Self {
+        Timestamp(secs.saturating_mul(MICROS_PER_SECOND))
+    }
+
+    /// Duration of `samples` audio samples at `rate` Hz.
+    pub fn from_samples(samples: usize, rate: u32) -> Self {
+        Timestamp(rescale(samples as i64, MICROS_PER_SECOND, rate as i64))
+    }
+
+    /// Convert a stream timestamp (pts/dts) expressed in `time_base` units.
+    pub fn from_pts(pts: i64, time_base: Rational) -> Self {
+        Timestamp(rescale(
+            pts,
+            time_base.numerator() as i64 * MICROS_PER_SECOND,
+            time_base.denominator() as i64,
+        ))
+    }
+
+    /// Convert to a stream timestamp in `time_base` units, rounding to the nearest tick.
+    pub fn to_pts(self, time_base: Rational) -> i64 {
+        rescale(
+            self.0,
+            time_base.denominator() as i64,
+            time_base.numerator() as i64 * MICROS_PER_SECOND,
+        )
+    }
+
+    pub fn as_micros(self) -> i64 {
+        self.0
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / MICROS_PER_SECOND as f64
+    }
+}
+
+impl Add for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, other: Timestamp) -> Timestamp {
+        Timestamp(self.0.saturating_add(other.0))
+    }
+}
+
+impl Sub for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, other: Timestamp) -> Timestamp {
+        Timestamp(self.0.saturating_sub(other.0))
+    }
+}
+
+// Formats as HH:MM:SS.mmm, widening to microseconds only when needed.
+#[cfg(test)]
+impl Timestamp {
+    /// `ms` milliseconds, which unlike `from_millis` may be negative.
+    pub(crate) fn ms(ms: i64) -> Self {
+        Timestamp(ms * MICROS_PER_MILLI)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let micros = self.0.unsigned_abs();
+        let secs = micros / MICROS_PER_SECOND as u64;
+        let frac = micros % MICROS_PER_SECOND as u64;
+        write!(f, "{}{:02}:{:02}:{:02}", sign, secs / 3600, secs / 60 % 60, secs % 60)?;
+        if frac.is_multiple_of(MICROS_PER_MILLI as u64) {
+            write!(f, ".{:03}", frac / MICROS_PER_MILLI as u64)
+        } else {
+            write!(f, ".{:06}", frac)
+        }
+    }
+}
+
+// Accepts `HH:MM:SS.fff`, `MM:SS.fff`, `62.345s`, `1500ms`, `250us` and bare seconds
+// (`62.345`), each optionally negative, so everything `Display` writes reads back.
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (negative, body) = match s.strip_prefix('-') {
+            Some(body) => (true, body),
+            None => (false, s),
+        };
+        if body.is_empty() {
+            return Err("empty timestamp".to_string());
+        }
+        let micros = if body.contains(':') {
+            let parts: Vec<&str> = body.split(':').collect();
+            if parts.len() > 3 {
+                return Err(format!("invalid timestamp '{}': too many ':' fields", s));
+            }
+            let (seconds, units) = parts.split_last().unwrap();
+            let mut micros = parse_decimal(seconds, MICROS_PER_SECOND)
+                .map_err(|e| format!("invalid timestamp '{}': {}", s, e))?;
+            for (unit, scale) in units.iter().rev().zip([60, 3600]) {
+                let value: u32 = unit
+                    .parse()
+                    .map_err(|_| format!("invalid timestamp '{}': bad field '{}'", s, unit))?;
+                micros += value as i64 * scale * MICROS_PER_SECOND;
+            }
+            micros
+        } else {
+            let (number, unit) = if let Some(n) = body.strip_suffix("ms") {
+                (n, MICROS_PER_MILLI)
+            } else if let Some(n) = body.strip_suffix("us") {
+                (n, 1)
+            } else if let Some(n) = body.strip_suffix('s') {
+                (n, MICROS_PER_SECOND)
+            } else {
+                (body, MICROS_PER_SECOND)
+            };
+            parse_decimal(number, unit).map_err(|e| format!("invalid timestamp '{}': {}", s, e))?
+        };
+        Ok(Timestamp(if negative { -micros } else { micros }))
+    }
+}
+
+// Stored as the same `HH:MM:SS.mmm` text the CLI accepts so session files stay readable.
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A time given on the command line, which may be a frame number that can only
+/// be converted once the source's frame rate is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSpec {
+    Time(Timestamp),
+    Frame(i64),
+}
+
+impl TimeSpec {
+    pub fn resolve(self, frame_rate: Option<Rational>) -> Result<Timestamp, String> {
+        match self {
+            TimeSpec::Time(ts) => Ok(ts),
+            TimeSpec::Frame(frame) => match frame_rate {
+                Some(rate) if rate.numerator() > 0 && rate.denominator() > 0 => {
+                    Ok(Timestamp(rescale(
+                        frame,
+                        rate.denominator() as i64 * MICROS_PER_SECOND,
+                        rate.numerator() as i64,
+                    )))
+                }
+                _ => Err(format!(
+                    "cannot resolve frame:{} without a known video frame rate",
+                    frame
+                )),
+            },
+        }
+    }
+}
+
+// Accepts everything `Timestamp` does plus `frame:N`.
+impl FromStr for TimeSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_prefix("frame:") {
+            Some(frame) => {
+                let frame: i64 = frame
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid frame number in '{}'", s))?;
+                if frame < 0 {
+                    return Err(format!("frame number must not be negative: '{}'", s));
+                }
+                Ok(TimeSpec::Frame(frame))
+            }
+            None => s.parse().map(TimeSpec::Time),
+        }
+    }
+}
+
+/// A `START-END` range given on the command line or in a ranges file, e.g.
+/// `00:12-00:19` or `frame:100-frame:250`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: TimeSpec,
+    pub end: TimeSpec,
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Only a negative time has a '-', and only as its first character, so the
+        // separator is the first '-' after that
+        let trimmed = s.trim();
+        let separator = trimmed
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| c == '-')
+            .map(|(index, _)| index)
+            .ok_or_else(|| format!("invalid range '{}': expected START-END", s))?;
+        let (start, end) = (&trimmed[..separator], &trimmed[separator + 1..]);
+        Ok(TimeRange {
+            start: start.parse()?,
+            end: end.parse()?,
+        })
+    }
+}
+
+// Parse a non-negative decimal like `62.345` into integer multiples of
+// `unit / 10^digits`, rounding anything finer than a microsecond.
+fn parse_decimal(s: &str, unit: i64) -> Result<i64, String> {
+    let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+    if whole.is_empty() && frac.is_empty() {
+        return Err("missing number".to_string());
+    }
+    if !whole.chars().all(|c| c.is_ascii_digit()) || !frac.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("'{}' is not a non-negative number", s));
+    }
+    let whole: i64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().map_err(|_| format!("'{}' is out of range", s))?
+    };
+    let mut micros = whole
+        .checked_mul(unit)
+        .ok_or_else(|| format!("'{}' is out of range", s))?;
+    if !frac.is_empty() {
+        let digits = frac.len().min(18) as u32;
+        let frac: i64 = frac[..digits as usize].parse().unwrap_or(0);
+        micros += rescale(frac, unit, 10i64.pow(digits));
+    }
+    Ok(micros)
+}
+
+// Compute `value * mul / div` rounded to nearest without intermediate overflow.
+fn rescale(value: i64, mul: i64, div: i64) -> i64 {
+    if div == 0 {
+        return 0;
+    }
+    let (mul, div) = if div < 0 { (-mul, -div) } else { (mul, div) };
+    let scaled = (value as i128 * mul as i128 * 2 + div as i128).div_euclid(div as i128 * 2);
+    scaled.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_reads_back() {
+        let values = [0, 1, 999, 1_000, 62_345_000, 3_723_456_789, 360_000_000_000, -1, -1_000_000, -3_723_456_000];
+        for micros in values {
+            let ts = Timestamp::from_micros(micros);
+            assert_eq!(ts.to_string().parse::<Timestamp>(), Ok(ts), "{}", ts);
+        }
+    }
+
+    #[test]
+    fn display_widens_to_microseconds_only_when_needed() {
+        assert_eq!(Timestamp::ms(3_723_456).to_string(), "01:02:03.456");
+        assert_eq!(Timestamp::from_micros(1_500_001).to_string(), "00:00:01.500001");
+        assert_eq!(Timestamp::ms(-1_500).to_string(), "-00:00:01.500");
+    }
+
+    #[test]
+    fn parses_every_form() {
+        let cases = [
+            ("01:02:03.456", Timestamp::ms(3_723_456)),
+            ("02:03.5", Timestamp::ms(123_500)),
+            ("00:00:07", Timestamp::ms(7_000)),
+            ("62.345s", Timestamp::ms(62_345)),
+            ("1500ms", Timestamp::ms(1_500)),
+            ("250us", Timestamp::from_micros(250)),
+            ("62.345", Timestamp::ms(62_345)),
+            (".5", Timestamp::ms(500)),
+            ("1.", Timestamp::ms(1_000)),
+            (" 3s ", Timestamp::ms(3_000)),
+            ("0.0000015", Timestamp::from_micros(2)),
+            ("-1.5s", Timestamp::ms(-1_500)),
+            ("-00:01:00.000", Timestamp::ms(-60_000)),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(text.parse::<Timestamp>(), Ok(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_times() {
+        let malformed = [
+            "", " ", "-", "--1", "1:2:3:4", "abc", "1.2.3", "12x", "1:-2", "00:-01", "1h", "s", "ms", "+5", "- 5",
+        ];
+        for text in malformed {
+            assert!(text.parse::<Timestamp>().is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn frame_numbers_resolve_with_a_frame_rate() {
+        assert_eq!("frame:50".parse::<TimeSpec>(), Ok(TimeSpec::Frame(50)));
+        assert_eq!("62.345s".parse::<TimeSpec>(), Ok(TimeSpec::Time(Timestamp::ms(62_345))));
+        assert_eq!(TimeSpec::Frame(50).resolve(Some(Rational::new(25, 1))), Ok(Timestamp::ms(2_000)));
+        assert_eq!(TimeSpec::Frame(300).resolve(Some(Rational::new(30_000, 1001))), Ok(Timestamp::ms(10_010)));
+        assert!(TimeSpec::Frame(50).resolve(None).is_err());
+        assert!(TimeSpec::Frame(50).resolve(Some(Rational::new(0, 1))).is_err());
+        assert_eq!(TimeSpec::Time(Timestamp::ms(5)).resolve(None), Ok(Timestamp::ms(5)));
+    }
+
+    #[test]
+    fn rejects_malformed_frame_numbers() {
+        for text in ["frame:", "frame:-1", "frame:1.5", "frame:x", "-frame:5"] {
+            assert!(text.parse::<TimeSpec>().is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let range = |start, end| TimeRange { start, end };
+        let time = |ms| TimeSpec::Time(Timestamp::ms(ms));
+        let cases = [
+            ("00:12-00:19", range(time(12_000), time(19_000))),
+            ("frame:100-frame:250", range(TimeSpec::Frame(100), TimeSpec::Frame(250))),
+            ("1500ms-2s", range(time(1_500), time(2_000))),
+            (" 1s - 2s ", range(time(1_000), time(2_000))),
+            ("-00:00:01.000-00:00:02.000", range(time(-1_000), time(2_000))),
+            ("1s--1s", range(time(1_000), time(-1_000))),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(text.parse::<TimeRange>(), Ok(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        for text in ["", "00:12", "-00:12", "00:12-", "1s-", "a-b", "1s-2s-3s"] {
+            assert!(text.parse::<TimeRange>().is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn serializes_as_display_text() {
+        let ts = Timestamp::ms(-3_723_456);
+        let json = serde_json::to_string(&ts).unwrap();
+        assert_eq!(json, "\"-01:02:03.456\"");
+        assert_eq!(serde_json::from_str::<Timestamp>(&json).unwrap(), ts);
+    }
+}
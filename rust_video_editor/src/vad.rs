@@ -0,0 +1,260 @@
+//! Model-free voice activity detection from a few spectral and temporal features.
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::sync::Arc;
+
+/// Band most of the energy of speech falls in, in Hz.
+pub const SPEECH_BAND: (f64, f64) = (300.0, 3400.0);
+
+// A window can be speech when enough of its energy is in the speech band and it is
+// either tonal or slowly varying
+const MAX_FLATNESS: f64 = 0.3;
+const MAX_ZERO_CROSSING_RATE: f64 = 0.25;
+const MIN_SPEECH_BAND_RATIO: f64 = 0.25;
+
+/// How far either side of a window `modulated` looks, in ms: a syllable or two.
+pub const MODULATION_SPAN_MS: u64 = 250;
+// Speech rises and falls by this much from syllable to syllable, while steady tones,
+// hum and stationary noise stay within a few dB
+const MIN_MODULATION_DB: f64 = 6.0;
+
+/// What the detector measures over one window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    /// Geometric over arithmetic mean of the power spectrum: near 1 for noise, near 0
+    /// for tonal sounds like voiced speech
+    pub flatness: f64,
+    /// Sign changes per sample: high for hiss and clicks
+    pub zero_crossing_rate: f64,
+    /// Share of the energy inside `SPEECH_BAND`
+    pub speech_band_ratio: f64,
+}
+
+impl Features {
+    /// Whether the window on its own could be speech rather than hiss, hum or clicks.
+    /// A steady tone in the speech band passes too; `modulated` tells it apart.
+    pub fn is_speech(&self) -> bool {
+        self.speech_band_ratio >= MIN_SPEECH_BAND_RATIO
+            && (self.flatness <= MAX_FLATNESS || self.zero_crossing_rate <= MAX_ZERO_CROSSING_RATE)
+    }
+}
+
+/// For each of a run of consecutive windows with mean square `powers`, whether the
+/// level around it rises and falls the way speech does from syllable to syllable: the
+/// loudest and quietest windows within `span` windows either side are at least
+/// `MIN_MODULATION_DB` apart.
+pub fn modulated(powers: &[f64], span: usize) -> Vec<bool> {
+    // Floored, so digital silence does not make every neighbour look modulated by -inf dB
+    let levels: Vec<f64> = powers.iter().map(|power| 10.0 * power.max(1e-12).log10()).collect();
+    (0..levels.len())
+        .map(|i| {
+            let around = &levels[i.saturating_sub(span)..(i + span + 1).min(levels.len())];
+            let (low, high) = around
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), &level| (low.min(level), high.max(level)));
+            high - low >= MIN_MODULATION_DB
+        })
+        .collect()
+}
+
+/// Computes `Features` for windows of one length at one sample rate.
+pub struct Detector {
+    rate: u32,
+    fft: Arc<dyn Fft<f64>>,
+    // Hann window, so the window edges do not smear energy across the spectrum
+    taper: Vec<f64>,
+}
+
+impl Detector {
+    pub fn new(rate: u32, window: usize) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(window);
+        let taper = (0..window)
+            .map(|n| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * n as f64 / window as f64).cos())
+            .collect();
+        Detector { rate, fft, taper }
+    }
+
+    /// Features of `samples` (a mono signal, at most one window long).
+    pub fn features(&self, samples: &[f64]) -> Features {
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0))
+            .count();
+        let zero_crossing_rate = crossings as f64 / samples.len().max(1) as f64;
+
+        let mut spectrum: Vec<Complex<f64>> = self
+            .taper
+            .iter()
+            .enumerate()
+            .map(|(n, taper)| Complex::new(samples.get(n).copied().unwrap_or(0.0) * taper, 0.0))
+            .collect();
+        self.fft.process(&mut spectrum);
+        // Real input: the upper half mirrors the lower, and the DC bin says nothing
+        let powers: Vec<f64> = spectrum[1..spectrum.len() / 2 + 1]
+            .iter()
+            .map(|bin| bin.norm_sqr())
+            .collect();
+        let total: f64 = powers.iter().sum();
+        if total <= f64::MIN_POSITIVE {
+            return Features {
+                flatness: 1.0,
+                zero_crossing_rate,
+                speech_band_ratio: 0.0,
+            };
+        }
+
+        let mean = total / powers.len() as f64;
+        let log_mean = powers
+            .iter()
+            .map(|power| (power + f64::MIN_POSITIVE).ln())
+            .sum::<f64>()
+            / powers.len() as f64;
+        let hz_per_bin = self.rate as f64 / spectrum.len() as f64;
+        let in_band: f64 = powers
+            .iter()
+            .enumerate()
+            .filter(|(bin, _)| {
+                let hz = (bin + 1) as f64 * hz_per_bin;
+                hz >= SPEECH_BAND.0 && hz <= SPEECH_BAND.1
+            })
+            .map(|(_, power)| power)
+            .sum();
+        Features {
+            flatness: (log_mean.exp() / mean).clamp(0.0, 1.0),
+            zero_crossing_rate,
+            speech_band_ratio: in_band / total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const RATE: u32 = 16_000;
+    // 20 ms
+    const WINDOW: usize = 320;
+
+    fn sine(hz: f64) -> Vec<f64> {
+        (0..WINDOW).map(|n| 0.5 * (2.0 * PI * hz * n as f64 / RATE as f64).sin()).collect()
+    }
+
+    // Uniform white noise from a fixed xorshift generator, so the test is repeatable.
+    fn white_noise() -> Vec<f64> {
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        (0..WINDOW)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+            })
+            .collect()
+    }
+
+    fn features(samples: &[f64]) -> Features {
+        Detector::new(RATE, WINDOW).features(samples)
+    }
+
+    // Whether each 20 ms window, 10 ms apart, of `signal` is heard as speech, the way
+    // the loudness measurement decides it.
+    fn speech(signal: &[f64]) -> Vec<bool> {
+        let detector = Detector::new(RATE, WINDOW);
+        let windows: Vec<&[f64]> = signal.windows(WINDOW).step_by(WINDOW / 2).collect();
+        let powers: Vec<f64> = windows
+            .iter()
+            .map(|window| window.iter().map(|sample| sample * sample).sum::<f64>() / WINDOW as f64)
+            .collect();
+        let span = (MODULATION_SPAN_MS / 10) as usize;
+        windows
+            .iter()
+            .zip(modulated(&powers, span))
+            .map(|(window, modulated)| detector.features(window).is_speech() && modulated)
+            .collect()
+    }
+
+    // One second of a 440 Hz tone, its amplitude scaled by `envelope` at each instant.
+    fn shaped_tone(envelope: impl Fn(f64) -> f64) -> Vec<f64> {
+        (0..RATE as usize)
+            .map(|n| {
+                let t = n as f64 / RATE as f64;
+                envelope(t) * 0.5 * (2.0 * PI * 440.0 * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_tone_in_the_speech_band_passes_the_single_window_test() {
+        let tone = features(&sine(440.0));
+        assert!(tone.flatness < 0.1, "{:?}", tone);
+        assert!((tone.zero_crossing_rate - 2.0 * 440.0 / RATE as f64).abs() < 0.01, "{:?}", tone);
+        assert!(tone.speech_band_ratio > 0.9, "{:?}", tone);
+        assert!(tone.is_speech());
+    }
+
+    #[test]
+    fn a_steady_tone_is_not_speech() {
+        let heard = speech(&shaped_tone(|_| 1.0));
+        assert!(!heard.is_empty());
+        assert!(heard.iter().all(|&speech| !speech), "{:?}", heard);
+    }
+
+    #[test]
+    fn a_tone_rising_and_falling_at_syllable_rate_is_speech() {
+        // Four "syllables" a second, each fading down to a tenth of its peak
+        let heard = speech(&shaped_tone(|t| 0.1 + 0.9 * (PI * 4.0 * t).sin().abs()));
+        assert!(heard.iter().all(|&speech| speech), "{:?}", heard);
+    }
+
+    #[test]
+    fn modulation_looks_only_within_the_span() {
+        let mut powers = vec![1.0; 20];
+        powers[10] = 0.01;
+        let expected: Vec<bool> = (0..20).map(|i: usize| i.abs_diff(10) <= 3).collect();
+        assert_eq!(modulated(&powers, 3), expected);
+        assert_eq!(modulated(&[0.0, 0.0, 0.0], 1), [false; 3]);
+        assert!(modulated(&[], 5).is_empty());
+    }
+
+    #[test]
+    fn white_noise_does_not() {
+        let noise = features(&white_noise());
+        assert!(noise.flatness > 0.4, "{:?}", noise);
+        assert!(noise.zero_crossing_rate > 0.4, "{:?}", noise);
+        // The band covers about 3100 of the 8000 Hz
+        assert!((noise.speech_band_ratio - 0.39).abs() < 0.15, "{:?}", noise);
+        assert!(!noise.is_speech());
+    }
+
+    #[test]
+    fn tones_outside_the_speech_band_do_not() {
+        for hz in [50.0, 6000.0] {
+            let tone = features(&sine(hz));
+            assert!(tone.speech_band_ratio < MIN_SPEECH_BAND_RATIO, "{} Hz: {:?}", hz, tone);
+            assert!(!tone.is_speech(), "{} Hz", hz);
+        }
+    }
+
+    #[test]
+    fn silence_is_flat_and_has_no_band_energy() {
+        let silence = features(&[0.0; WINDOW]);
+        assert_eq!(
+            silence,
+            Features {
+                flatness: 1.0,
+                zero_crossing_rate: 0.0,
+                speech_band_ratio: 0.0,
+            }
+        );
+        assert!(!silence.is_speech());
+    }
+
+    #[test]
+    fn short_windows_are_padded() {
+        let tone = features(&sine(440.0)[..WINDOW / 2]);
+        assert!(tone.speech_band_ratio > 0.9, "{:?}", tone);
+        assert!(features(&[]).zero_crossing_rate == 0.0);
+    }
+}
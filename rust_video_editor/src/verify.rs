@@ -0,0 +1,597 @@
+//! Checking that a written file decodes cleanly and holds what it was meant to.
+
+use crate::error::{self, Error, Result};
+use crate::keyframe;
+use crate::segment;
+use crate::timestamp::Timestamp;
+use ffmpeg::{codec, format, frame, media, Frame};
+use ffmpeg_next as ffmpeg;
+use serde::Serialize;
+
+/// How far the duration and the audio/video start may be off before it counts.
+pub const DEFAULT_TOLERANCE_MS: u64 = 500;
+
+/// What a file cut from ranges of a source should hold.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Expected {
+    /// In the order they were written
+    pub ranges: Vec<ExpectedRange>,
+}
+
+/// One range of the source as it should come out.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExpectedRange {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// How much of the start a stream copy leaves out, since it begins at the first
+    /// video keyframe in the range (all of it when there is none)
+    pub slack: Timestamp,
+    /// Source times of the video keyframes a stream copy of the range holds; empty
+    /// when it is re-encoded
+    pub keyframes: Vec<Timestamp>,
+}
+
+impl ExpectedRange {
+    pub fn length(&self) -> Timestamp {
+        self.end - self.start - self.slack
+    }
+}
+
+impl Expected {
+    /// `ranges` re-encoded one after another, each exactly as long as it is.
+    pub fn reencoded(ranges: &[(Timestamp, Timestamp)]) -> Expected {
+        Expected::with_keyframes(ranges, None)
+    }
+
+    /// `ranges` of `source` stream copied by `segment::write_segments`: normalized, and
+    /// each starting at its first video keyframe, which the keyframe index tells.
+    pub fn stream_copied(source: &str, ranges: &[(Timestamp, Timestamp)]) -> Result<Expected> {
+        let index = keyframe::index(source)?;
+        let keyframes: Option<Vec<Timestamp>> = index.video().map(|video| {
+            let time_base = video.time_base();
+            video
+                .keyframes
+                .iter()
+                .map(|keyframe| Timestamp::from_pts(keyframe.pts, time_base))
+                .collect()
+        });
+        Ok(Expected::with_keyframes(&segment::normalize_ranges(ranges), keyframes.as_deref()))
+    }
+
+    // The ranges, stream copied when the source's video `keyframes` (in time order)
+    // are given and re-encoded otherwise.
+    fn with_keyframes(ranges: &[(Timestamp, Timestamp)], keyframes: Option<&[Timestamp]>) -> Expected {
+        let ranges = ranges
+            .iter()
+            .map(|&(start, end)| {
+                let inside = keyframes.map(|keyframes| {
+                    let first = keyframes.partition_point(|&keyframe| keyframe < start);
+                    let last = keyframes.partition_point(|&keyframe| keyframe < end);
+                    &keyframes[first..last.max(first)]
+                });
+                let slack = match inside {
+                    Some([first, ..]) => *first - start,
+                    Some([]) => end - start,
+                    None => Timestamp::default(),
+                };
+                ExpectedRange {
+                    start,
+                    end,
+                    slack,
+                    keyframes: inside.unwrap_or_default().to_vec(),
+                }
+            })
+            .collect();
+        Expected { ranges }
+    }
+
+    pub fn duration(&self) -> Timestamp {
+        self.ranges.iter().fold(Timestamp::default(), |total, range| total + range.length())
+    }
+
+    // Where each stream-copied keyframe should land in the output: its time, the index
+    // of its range and whether it starts that range.
+    fn output_keyframes(&self) -> Vec<(Timestamp, usize, bool)> {
+        let mut position = Timestamp::default();
+        let mut placed = Vec::new();
+        for (k, range) in self.ranges.iter().enumerate() {
+            let begin = range.start + range.slack;
+            for (i, &keyframe) in range.keyframes.iter().enumerate() {
+                placed.push((position + (keyframe - begin), k, i == 0));
+            }
+            position = position + range.length();
+        }
+        placed
+    }
+
+    // Which range is off when the file's video keyframes (`keyframes`, relative to its
+    // start) and its `duration` first stray from what the ranges say, and by how much.
+    // None when everything is within `tolerance`.
+    fn stray_range(
+        &self,
+        keyframes: &[Timestamp],
+        duration: Timestamp,
+        tolerance: Timestamp,
+    ) -> Option<(usize, Timestamp)> {
+        let within = |off: Timestamp| off.as_micros().abs() <= tolerance.as_micros();
+        // The range that should end where range `k` starts: the last one before it with
+        // anything in it
+        let before = |k: usize| (0..k).rev().find(|&j| self.ranges[j].length() > Timestamp::default());
+        let off = duration - self.duration();
+        for (i, (expected, k, first)) in self.output_keyframes().into_iter().enumerate() {
+            let Some(&found) = keyframes.get(i) else {
+                // The file ended before this range's content did
+                return Some((k, off));
+            };
+            if !within(found - expected) {
+                let culprit = if first { before(k).unwrap_or(k) } else { k };
+                return Some((culprit, found - expected));
+            }
+        }
+        let last = before(self.ranges.len())?;
+        (!within(off)).then_some((last, off))
+    }
+}
+
+/// What was found in one stream.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StreamCheck {
+    pub index: usize,
+    /// `video`, `audio`, `subtitle`, `data` or `attachment`
+    pub medium: String,
+    pub packets: usize,
+    /// Frames decoded; zero for streams that are not decoded (neither audio nor video)
+    pub frames: usize,
+    /// Packets the decoder rejected plus frames it marked corrupt
+    pub decode_errors: usize,
+    /// Packets whose dts is not after that of the packet before
+    pub dts_errors: usize,
+    /// Earliest presentation time of any packet
+    pub start: Option<Timestamp>,
+    /// Latest presentation time plus duration of any packet
+    pub end: Option<Timestamp>,
+}
+
+/// The outcome of `verify`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VerifyReport {
+    pub path: String,
+    pub streams: Vec<StreamCheck>,
+    /// From the earliest start to the latest end of any stream
+    pub duration: Option<Timestamp>,
+    /// What the ranges the file was cut from add up to
+    pub expected_duration: Option<Timestamp>,
+    /// How much later the first audio stream starts than the first video stream
+    /// (negative when it starts earlier)
+    pub av_offset: Option<Timestamp>,
+    /// Everything that is wrong, one line each; empty when the file passed
+    pub problems: Vec<String>,
+}
+
+// Per-stream state while reading the packets.
+struct Checker {
+    check: StreamCheck,
+    decoder: Option<codec::decoder::Opened>,
+    time_base: ffmpeg::Rational,
+    last_dts: Option<i64>,
+    first_error: Option<String>,
+    first_dts_error: Option<String>,
+}
+
+impl Checker {
+    // Drain the frames the decoder has ready, counting corrupt ones and failures.
+    fn receive(&mut self, frame: &mut Frame) {
+        while let Some(decoder) = self.decoder.as_mut() {
+            match decoder.receive_frame(frame) {
+                Ok(()) => {
+                    self.check.frames += 1;
+                    if frame.is_corrupt() {
+                        self.error(format!("corrupt frame at {}", time(frame.pts(), self.time_base)));
+                    }
+                }
+                Err(ffmpeg::Error::Eof) => break,
+                Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => break,
+                Err(e) => {
+                    self.error(e.to_string());
+                    break;
+                }
+            }
+        }
+    }
+
+    fn error(&mut self, message: String) {
+        self.check.decode_errors += 1;
+        self.first_error.get_or_insert(message);
+    }
+}
+
+fn time(pts: Option<i64>, time_base: ffmpeg::Rational) -> String {
+    pts.map_or("an unknown time".to_string(), |pts| Timestamp::from_pts(pts, time_base).to_string())
+}
+
+/// Decode every packet of `path` and check its timestamps. The file passes when no
+/// packet fails to decode, every stream's dts increases, audio and video start
+/// within `tolerance` of each other and, when `expected` is given (the cut list it
+/// was made from), its duration and each range's place in it are within `tolerance`
+/// of that. Only failing to open or read the file is an error; see
+/// `VerifyReport::ensure_passed`.
+pub fn verify(path: &str, expected: Option<&Expected>, tolerance: Timestamp) -> Result<VerifyReport> {
+    error::init()?;
+    let mut input_file = format::input(&path).map_err(|e| Error::open(path, e))?;
+    let video = input_file.streams().best(media::Type::Video).map(|stream| stream.index());
+    let mut keyframes = Vec::new();
+    let mut problems = Vec::new();
+    let mut checkers: Vec<Checker> = input_file
+        .streams()
+        .map(|stream| {
+            let medium = stream.parameters().medium();
+            let decoder = if matches!(medium, media::Type::Video | media::Type::Audio) {
+                let opened = codec::context::Context::from_parameters(stream.parameters())
+                    .and_then(|context| context.decoder().open());
+                match opened {
+                    Ok(decoder) => Some(decoder),
+                    Err(e) => {
+                        problems.push(format!("stream {}: cannot be decoded: {}", stream.index(), e));
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            Checker {
+                check: StreamCheck {
+                    index: stream.index(),
+                    medium: format!("{:?}", medium).to_ascii_lowercase(),
+                    packets: 0,
+                    frames: 0,
+                    decode_errors: 0,
+                    dts_errors: 0,
+                    start: None,
+                    end: None,
+                },
+                decoder,
+                time_base: stream.time_base(),
+                last_dts: None,
+                first_error: None,
+                first_dts_error: None,
+            }
+        })
+        .collect();
+
+    // Any frame type will do as the buffer: the decoders fill in whatever they decode
+    let mut frame = frame::Video::empty();
+    for (stream, packet) in input_file.packets() {
+        let checker = &mut checkers[stream.index()];
+        checker.check.packets += 1;
+        if let Some(dts) = packet.dts() {
+            if let Some(last) = checker.last_dts
+                && dts <= last
+            {
+                checker.check.dts_errors += 1;
+                checker.first_dts_error.get_or_insert(format!(
+                    "dts {} after {}",
+                    time(Some(dts), checker.time_base),
+                    time(Some(last), checker.time_base)
+                ));
+            }
+            checker.last_dts = Some(dts);
+        }
+        if let Some(pts) = packet.pts() {
+            let start = Timestamp::from_pts(pts, checker.time_base);
+            if Some(stream.index()) == video && packet.is_key() {
+                keyframes.push(start);
+            }
+            let end = Timestamp::from_pts(pts + packet.duration().max(0), checker.time_base);
+            checker.check.start = Some(checker.check.start.map_or(start, |first| first.min(start)));
+            checker.check.end = checker.check.end.max(Some(end));
+        }
+        if let Some(decoder) = checker.decoder.as_mut() {
+            if let Err(e) = decoder.send_packet(&packet) {
+                let at = time(packet.pts(), checker.time_base);
+                checker.error(format!("packet at {} rejected: {}", at, e));
+            }
+            checker.receive(&mut frame);
+        }
+    }
+    for checker in &mut checkers {
+        if let Some(decoder) = checker.decoder.as_mut() {
+            decoder.send_eof().ok();
+            checker.receive(&mut frame);
+        }
+    }
+
+    for checker in &checkers {
+        let check = &checker.check;
+        if let Some(first) = &checker.first_error {
+            problems.push(format!(
+                "stream {}: {} decode errors, the first: {}",
+                check.index, check.decode_errors, first
+            ));
+        }
+        if let Some(first) = &checker.first_dts_error {
+            problems.push(format!(
+                "stream {}: {} non-monotonic dts, the first: {}",
+                check.index, check.dts_errors, first
+            ));
+        }
+    }
+    let streams: Vec<StreamCheck> = checkers.into_iter().map(|checker| checker.check).collect();
+    keyframes.sort_unstable();
+    Ok(report(path, streams, &keyframes, expected, tolerance, problems))
+}
+
+// Compare what was read of `path` (its streams, and the times of its video keyframes)
+// with what was expected, adding to the `problems` found while reading.
+fn report(
+    path: &str,
+    streams: Vec<StreamCheck>,
+    keyframes: &[Timestamp],
+    expected: Option<&Expected>,
+    tolerance: Timestamp,
+    mut problems: Vec<String>,
+) -> VerifyReport {
+    if streams.iter().all(|stream| stream.packets == 0) {
+        problems.push("no packets".to_string());
+    }
+
+    let start = streams.iter().filter_map(|stream| stream.start).min();
+    let end = streams.iter().filter_map(|stream| stream.end).max();
+    let duration = start.zip(end).map(|(start, end)| end - start);
+    let expected_duration = expected.map(Expected::duration);
+    if let (Some(duration), Some(expected_duration)) = (duration, expected_duration)
+        && (duration - expected_duration).as_micros().abs() > tolerance.as_micros()
+    {
+        problems.push(format!("duration {} where {} was expected", duration, expected_duration));
+    }
+    if let (Some(start), Some(duration), Some(expected)) = (start, duration, expected) {
+        // Keyframe times from the file's start, which need not be zero (MPEG-TS)
+        let keyframes: Vec<Timestamp> = keyframes.iter().map(|&keyframe| keyframe - start).collect();
+        if let Some((k, off)) = expected.stray_range(&keyframes, duration, tolerance) {
+            let range = &expected.ranges[k];
+            let (by, how) = if off < Timestamp::default() {
+                (Timestamp::default() - off, "short")
+            } else {
+                (off, "long")
+            };
+            problems.push(format!(
+                "range {} ({} - {}) comes out {} {}",
+                k + 1,
+                range.start,
+                range.end,
+                by,
+                how
+            ));
+        }
+    }
+
+    let first_start = |medium: &str| {
+        streams
+            .iter()
+            .find(|stream| stream.medium == medium)
+            .and_then(|stream| stream.start)
+    };
+    let av_offset = first_start("audio").zip(first_start("video")).map(|(audio, video)| audio - video);
+    if let Some(offset) = av_offset
+        && offset.as_micros().abs() > tolerance.as_micros()
+    {
+        problems.push(format!("audio starts {} after video", offset));
+    }
+
+    VerifyReport {
+        path: path.to_string(),
+        streams,
+        duration,
+        expected_duration,
+        av_offset,
+        problems,
+    }
+}
+
+impl VerifyReport {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Ok when the file passed, otherwise a `Verify` error.
+    pub fn ensure_passed(&self) -> Result<()> {
+        if self.passed() {
+            Ok(())
+        } else {
+            Err(Error::verify(&self.path, self.problems.len()))
+        }
+    }
+
+    pub fn print(&self) {
+        let duration = self.duration.map_or("unknown".to_string(), |duration| duration.to_string());
+        match self.expected_duration {
+            Some(expected) => println!("{}: duration {} (expected {})", self.path, duration, expected),
+            None => println!("{}: duration {}", self.path, duration),
+        }
+        for stream in &self.streams {
+            println!(
+                "  Stream {}: {}, {} packets, {} frames decoded, {} decode errors, {} dts errors",
+                stream.index, stream.medium, stream.packets, stream.frames, stream.decode_errors, stream.dts_errors
+            );
+        }
+        if let Some(offset) = self.av_offset {
+            println!("  Audio starts {} after video", offset);
+        }
+        if self.passed() {
+            println!("  OK");
+        }
+        for problem in &self.problems {
+            println!("  Problem: {}", problem);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tolerance() -> Timestamp {
+        Timestamp::from_millis(500)
+    }
+
+    fn secs(seconds: &[i64]) -> Vec<Timestamp> {
+        seconds.iter().map(|&s| Timestamp::from_secs(s)).collect()
+    }
+
+    fn stream(index: usize, medium: &str, start_ms: u64, end_ms: u64) -> StreamCheck {
+        StreamCheck {
+            index,
+            medium: medium.to_string(),
+            packets: 10,
+            frames: 10,
+            decode_errors: 0,
+            dts_errors: 0,
+            start: Some(Timestamp::from_millis(start_ms)),
+            end: Some(Timestamp::from_millis(end_ms)),
+        }
+    }
+
+    // A file with video and audio over `start_ms..end_ms`, its video keyframes at
+    // `keyframes` seconds from its start.
+    fn check(start_ms: u64, end_ms: u64, keyframes: &[i64], expected: &Expected) -> VerifyReport {
+        let streams = vec![stream(0, "video", start_ms, end_ms), stream(1, "audio", start_ms, end_ms)];
+        let keyframes: Vec<Timestamp> = secs(keyframes)
+            .into_iter()
+            .map(|keyframe| keyframe + Timestamp::from_millis(start_ms))
+            .collect();
+        report("out.mp4", streams, &keyframes, Some(expected), tolerance(), Vec::new())
+    }
+
+    // 1-5 s and 9-13 s of a source with a keyframe every 2 s, stream copied: each comes
+    // out from 2 s and 10 s, 3 s long, with keyframes at 0 and 2 s and at 3 and 5 s.
+    fn copied() -> Expected {
+        let keyframes = secs(&[0, 2, 4, 6, 8, 10, 12, 14]);
+        let ranges = [(1, 5), (9, 13)].map(|(s, e)| (Timestamp::from_secs(s), Timestamp::from_secs(e)));
+        Expected::with_keyframes(&ranges, Some(&keyframes))
+    }
+
+    #[test]
+    fn stream_copied_ranges_start_at_their_first_keyframe() {
+        let expected = copied();
+        assert_eq!(expected.ranges[0].slack, Timestamp::from_secs(1));
+        assert_eq!(expected.ranges[0].keyframes, secs(&[2, 4]));
+        assert_eq!(expected.ranges[1].keyframes, secs(&[10, 12]));
+        assert_eq!(expected.duration(), Timestamp::from_secs(6));
+        let at = secs(&[0, 2, 3, 5]);
+        assert_eq!(
+            expected.output_keyframes(),
+            [(at[0], 0, true), (at[1], 0, false), (at[2], 1, true), (at[3], 1, false)]
+        );
+    }
+
+    #[test]
+    fn a_range_without_a_keyframe_comes_out_empty() {
+        let keyframes = secs(&[0, 10]);
+        let ranges = [(Timestamp::from_secs(2), Timestamp::from_secs(8))];
+        let expected = Expected::with_keyframes(&ranges, Some(&keyframes));
+        assert_eq!(expected.ranges[0].length(), Timestamp::default());
+        assert!(expected.ranges[0].keyframes.is_empty());
+    }
+
+    #[test]
+    fn reencoded_ranges_come_out_at_full_length() {
+        let ranges = [(Timestamp::from_secs(1), Timestamp::from_secs(5))];
+        let expected = Expected::reencoded(&ranges);
+        assert_eq!(expected.duration(), Timestamp::from_secs(4));
+        assert!(expected.output_keyframes().is_empty());
+    }
+
+    #[test]
+    fn a_file_matching_its_ranges_passes() {
+        let report = check(0, 6_000, &[0, 2, 3, 5], &copied());
+        assert_eq!(report.problems, Vec::<String>::new());
+        assert_eq!(report.duration, Some(Timestamp::from_secs(6)));
+        assert_eq!(report.expected_duration, Some(Timestamp::from_secs(6)));
+        assert_eq!(report.av_offset, Some(Timestamp::default()));
+    }
+
+    #[test]
+    fn the_duration_may_be_off_by_the_tolerance() {
+        assert!(check(0, 6_400, &[0, 2, 3, 5], &copied()).passed());
+        assert!(check(0, 5_600, &[0, 2, 3, 5], &copied()).passed());
+    }
+
+    #[test]
+    fn a_short_range_in_the_middle_is_named() {
+        // The first range lost its last second, so the second starts at 2 s
+        let report = check(0, 5_000, &[0, 2, 2, 4], &copied());
+        assert_eq!(
+            report.problems,
+            [
+                "duration 00:00:05.000 where 00:00:06.000 was expected",
+                "range 1 (00:00:01.000 - 00:00:05.000) comes out 00:00:01.000 short",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_short_last_range_is_named() {
+        let report = check(0, 4_000, &[0, 2, 3], &copied());
+        assert_eq!(report.problems[1], "range 2 (00:00:09.000 - 00:00:13.000) comes out 00:00:02.000 short");
+    }
+
+    #[test]
+    fn a_missing_gop_is_put_on_its_own_range() {
+        let report = check(0, 5_000, &[0, 2, 3], &copied());
+        assert_eq!(report.problems[1], "range 2 (00:00:09.000 - 00:00:13.000) comes out 00:00:01.000 short");
+    }
+
+    #[test]
+    fn a_long_range_is_named() {
+        let report = check(0, 7_000, &[0, 2, 4, 6], &copied());
+        assert_eq!(report.problems[1], "range 1 (00:00:01.000 - 00:00:05.000) comes out 00:00:01.000 long");
+    }
+
+    #[test]
+    fn keyframes_are_measured_from_the_start_of_the_file() {
+        // MPEG-TS output starts at 1.4 s
+        assert!(check(1_400, 7_400, &[0, 2, 3, 5], &copied()).passed());
+    }
+
+    #[test]
+    fn reencoded_output_is_checked_on_its_duration_alone() {
+        let expected = Expected::reencoded(&[(Timestamp::from_secs(1), Timestamp::from_secs(5))]);
+        assert!(check(0, 4_000, &[0], &expected).passed());
+        let report = check(0, 3_000, &[0], &expected);
+        assert_eq!(
+            report.problems,
+            [
+                "duration 00:00:03.000 where 00:00:04.000 was expected",
+                "range 1 (00:00:01.000 - 00:00:05.000) comes out 00:00:01.000 short",
+            ]
+        );
+    }
+
+    #[test]
+    fn audio_may_start_up_to_the_tolerance_away_from_video() {
+        for (audio_start_ms, offset_ms, passes) in [(0, 0, true), (400, 400, true), (600, 600, false)] {
+            let streams = vec![stream(0, "video", 0, 6_000), stream(1, "audio", audio_start_ms, 6_000)];
+            let report = report("out.mp4", streams, &[], None, tolerance(), Vec::new());
+            assert_eq!(report.av_offset, Some(Timestamp::from_millis(offset_ms)));
+            assert_eq!(report.passed(), passes, "{}", audio_start_ms);
+        }
+        let streams = vec![stream(0, "video", 600, 6_000), stream(1, "audio", 0, 6_000)];
+        let report = report("out.mp4", streams, &[], None, tolerance(), Vec::new());
+        assert_eq!(report.problems, ["audio starts -00:00:00.600 after video"]);
+    }
+
+    #[test]
+    fn a_file_without_audio_has_no_offset_and_no_duration_check_without_ranges() {
+        let report = report("out.mp4", vec![stream(0, "video", 0, 1_000)], &[], None, tolerance(), Vec::new());
+        assert_eq!(report.av_offset, None);
+        assert_eq!(report.expected_duration, None);
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn a_file_without_packets_fails() {
+        let mut empty = stream(0, "video", 0, 0);
+        empty.packets = 0;
+        let report = report("out.mp4", vec![empty], &[], None, tolerance(), Vec::new());
+        assert_eq!(report.problems, ["no packets"]);
+    }
+}